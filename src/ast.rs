@@ -1,15 +1,169 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Type {
+    F64,
+    I64,
+    Bool,
+    Str,
+    // Of f64.
+    Array,
+    Struct(&'static str),
+    // Of a closure.
+    Fn(&'static FnType),
+}
+
+/// Parameter types and return type.
+#[derive(Debug, PartialEq)]
+pub(crate) struct FnType(pub(crate) Vec<Type>, pub(crate) Type);
+
+/// Returns the one copy of the function type, like `intern` does for names.
+pub(crate) fn intern_fn(params: Vec<Type>, ret: Type) -> &'static FnType {
+    static TYPES: OnceLock<Mutex<Vec<&'static FnType>>> = OnceLock::new();
+    let mut types = TYPES.get_or_init(Default::default).lock().unwrap();
+    let t = FnType(params, ret);
+    match types.iter().find(|u| ***u == t) {
+        Some(u) => u,
+        None => {
+            let t = Box::leak(Box::new(t));
+            types.push(t);
+            t
+        }
+    }
+}
+
+/// Returns the one copy of `name`, so that struct types can be named by a `Copy` type.
+pub(crate) fn intern(name: &str) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut names = NAMES.get_or_init(Default::default).lock().unwrap();
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name = Box::leak(name.to_owned().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+impl Type {
+    pub(crate) fn is_number(self) -> bool {
+        self == Type::F64 || self == Type::I64
+    }
+
+    /// Whether operators and conditions work on values of this type.
+    pub(crate) fn is_scalar(self) -> bool {
+        self.is_number() || self == Type::Bool
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Type::F64 => "f64",
+            Type::I64 => "i64",
+            Type::Bool => "bool",
+            Type::Str => "str",
+            Type::Array => "[f64]",
+            Type::Struct(name) => name,
+            Type::Fn(FnType(params, ret)) => {
+                let params = params.iter().map(Type::to_string).collect::<Vec<_>>();
+                return write!(f, "fn({}) -> {}", params.join(", "), ret);
+            }
+        })
+    }
+}
+
+/// A line and column in the source, both starting at 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Pos {
+    pub(crate) line: u32,
+    pub(crate) col: u32,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Expr {
     Number(f64),
+    Str(String),
     Variable(String),
     Binary(char, Box<Expr>, Box<Expr>),
     Call(String, Vec<Expr>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     For(String, Box<Expr>, Box<Expr>, Box<Option<Expr>>, Box<Expr>),
+    // `let x = value in body`; `x` is bound for the body only.
+    Let(String, Box<Expr>, Box<Expr>),
+    // Condition, body.
+    While(Box<Expr>, Box<Expr>),
+    // Out of and to the next iteration of the innermost loop.
+    Break,
+    Continue,
+    // From the innermost function or lambda.
+    Return(Box<Expr>),
+    Array(Vec<Expr>),
+    // Array, index.
+    Index(Box<Expr>, Box<Expr>),
+    // Array, index, value.
+    SetIndex(Box<Expr>, Box<Expr>, Box<Expr>),
+    // Struct name, field values and the struct the rest are taken from, as in
+    // `Point { x: 1, ..p }`.
+    Struct(String, Vec<(String, Expr)>, Option<Box<Expr>>),
+    Field(Box<Expr>, String),
+    // `fn (x) x * 2`; the prototype has no name.
+    Lambda(Box<Prototype>, Box<Expr>),
+    // A call of a function value. The parser only produces these for callees that are not a
+    // name; the type checker turns calls of local variables into them too.
+    Apply(Box<Expr>, Vec<Expr>),
+    // Introduced by the type checker, except that the parser uses `Cast` for annotated loop
+    // and let variables. Either way it is an implicit conversion.
+    Int(i64),
+    Cast(Type, Box<Expr>),
+    // Where the parser found the wrapped expression, for error messages. The type checker
+    // strips these.
+    At(Pos, Box<Expr>),
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) struct Prototype(pub(crate) String, pub(crate) Vec<String>);
+/// Name, parameter names, parameter types and return type. Types that were not annotated are
+/// `None` until the type checker infers them.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Prototype(
+    pub(crate) String,
+    pub(crate) Vec<String>,
+    pub(crate) Vec<Option<Type>>,
+    pub(crate) Option<Type>,
+);
 
-#[derive(Debug)]
+impl Prototype {
+    /// A prototype without annotations.
+    pub(crate) fn new(name: String, args: Vec<String>) -> Self {
+        let types = vec![None; args.len()];
+        Prototype(name, args, types, None)
+    }
+}
+
+impl Expr {
+    /// Whether evaluating the expression always jumps elsewhere, so that it has no value of its
+    /// own and fits where any type is expected.
+    pub(crate) fn jumps(&self) -> bool {
+        match self {
+            Expr::Break | Expr::Continue | Expr::Return(_) => true,
+            Expr::If(_, then, els) => then.jumps() && els.jumps(),
+            Expr::At(_, e) => e.jumps(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Function(pub(crate) Box<Prototype>, pub(crate) Box<Expr>);
+
+/// A `struct` declaration: its name and fields. Unannotated fields are f64.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StructDef(pub(crate) String, pub(crate) Vec<(String, Type)>);
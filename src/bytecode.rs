@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use super::ast::{Expr, Function, Prototype};
+use super::error::{Error, ErrorKind};
+use super::toplevel::Item;
+use super::typeck;
+
+const MAGIC: &[u8; 4] = b"KSBC";
+const VERSION: u32 = 1;
+
+/// A stack machine instruction. Operands index into the current frame's locals, the current
+/// chunk's code, or the module's function and extern tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    Const(f64),
+    Load(u32),
+    Store(u32),
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Lt,
+    Jump(u32),
+    /// Pops the condition and jumps if it is 0.0 or NaN.
+    JumpIfFalse(u32),
+    Call(u32),
+    CallExtern(u32),
+    Ret,
+}
+
+/// The compiled body of one function. Parameters occupy the first `arity` local slots.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Chunk {
+    pub(crate) name: String,
+    pub(crate) arity: u32,
+    pub(crate) locals: u32,
+    pub(crate) code: Vec<Op>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Extern {
+    pub(crate) name: String,
+    pub(crate) arity: u32,
+}
+
+/// A unit of bytecode, as kept by the VM and written to disk.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Module {
+    pub(crate) externs: Vec<Extern>,
+    pub(crate) functions: Vec<Chunk>,
+    /// Anonymous chunks for the top-level expressions of a compiled file, in source order.
+    pub(crate) entries: Vec<u32>,
+}
+
+fn codegen_error(msg: String) -> Error {
+    Error::from(ErrorKind::Codegen(msg))
+}
+
+fn bytecode_error(msg: &str) -> Error {
+    Error::from(ErrorKind::Bytecode(msg.to_owned()))
+}
+
+struct FunctionCompiler<'a> {
+    module: &'a Module,
+    scope: HashMap<String, u32>,
+    locals: u32,
+    code: Vec<Op>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new_local(&mut self) -> u32 {
+        self.locals += 1;
+        self.locals - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.code.len() as u32
+    }
+
+    fn patch(&mut self, at: u32) {
+        let target = self.here();
+        match &mut self.code[at as usize] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<(), Error> {
+        match e {
+            Expr::Number(n) => self.code.push(Op::Const(*n)),
+            // Every value is a double at runtime. The checker only takes i64 for the JIT, so
+            // integers do not get here; bools are erased to 0 or 1.
+            Expr::Int(n) => self.code.push(Op::Const(*n as f64)),
+            Expr::Cast(_, e) | Expr::At(_, e) => self.expr(e)?,
+            Expr::Str(_)
+            | Expr::Array(_)
+            | Expr::Index(..)
+            | Expr::SetIndex(..)
+            | Expr::Struct(..)
+            | Expr::Field(..)
+            | Expr::Lambda(..)
+            | Expr::Apply(..)
+            | Expr::While(..)
+            | Expr::Break
+            | Expr::Continue
+            | Expr::Return(_) => {
+                return Err(codegen_error(
+                    "this expression is only supported by the JIT backend".to_owned(),
+                ))
+            }
+            Expr::Variable(name) => match self.scope.get(name) {
+                Some(slot) => self.code.push(Op::Load(*slot)),
+                None => return Err(codegen_error(format!("unknown variable name: {}", name))),
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                self.code.push(match op {
+                    '+' => Op::Add,
+                    '-' => Op::Sub,
+                    '*' => Op::Mul,
+                    '<' => Op::Lt,
+                    _ => return Err(codegen_error(format!("invalid binary operator: {}", op))),
+                });
+            }
+            Expr::Call(callee, args) => {
+                let (op, arity) = if let Some(i) = self.module.find_function(callee) {
+                    (Op::Call(i), self.module.functions[i as usize].arity)
+                } else if let Some(i) = self.module.find_extern(callee) {
+                    (Op::CallExtern(i), self.module.externs[i as usize].arity)
+                } else {
+                    return Err(codegen_error(format!("unknown function: {}", callee)));
+                };
+
+                if arity as usize != args.len() {
+                    return Err(codegen_error("incorrect # arguments passed".to_owned()));
+                }
+
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                self.code.push(op);
+            }
+            Expr::If(cond, then, els) => {
+                self.expr(cond)?;
+                let to_else = self.here();
+                self.code.push(Op::JumpIfFalse(0));
+                self.expr(then)?;
+                let to_end = self.here();
+                self.code.push(Op::Jump(0));
+                self.patch(to_else);
+                self.expr(els)?;
+                self.patch(to_end);
+            }
+            Expr::Let(name, value, body) => {
+                self.expr(value)?;
+                let slot = self.new_local();
+                self.code.push(Op::Store(slot));
+                let old_slot = self.scope.insert(name.clone(), slot);
+                let ret = self.expr(body);
+                match old_slot {
+                    Some(slot) => self.scope.insert(name.clone(), slot),
+                    None => self.scope.remove(name),
+                };
+                ret?;
+            }
+            Expr::For(var_name, start, end, step, body) => {
+                // Same evaluation order as codegen: body, step, end condition, then the
+                // induction variable is advanced.
+                self.expr(start)?;
+                let var = self.new_local();
+                let step_slot = self.new_local();
+                self.code.push(Op::Store(var));
+
+                let old_slot = self.scope.insert(var_name.clone(), var);
+
+                let loop_start = self.here();
+                let ret = (|| -> Result<(), Error> {
+                    self.expr(body)?;
+                    self.code.push(Op::Pop);
+                    match step.as_ref() {
+                        Some(step) => self.expr(step)?,
+                        None => self.code.push(Op::Const(1.0)),
+                    }
+                    self.code.push(Op::Store(step_slot));
+                    self.expr(end)?;
+                    self.code.extend(&[
+                        Op::Load(var),
+                        Op::Load(step_slot),
+                        Op::Add,
+                        Op::Store(var),
+                    ]);
+                    let to_after = self.here();
+                    self.code.push(Op::JumpIfFalse(0));
+                    self.code.push(Op::Jump(loop_start));
+                    self.patch(to_after);
+                    self.code.push(Op::Const(0.0));
+                    Ok(())
+                })();
+
+                match old_slot {
+                    Some(slot) => self.scope.insert(var_name.clone(), slot),
+                    None => self.scope.remove(var_name),
+                };
+
+                ret?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Module {
+    pub(crate) fn find_function(&self, name: &str) -> Option<u32> {
+        self.functions
+            .iter()
+            .position(|f| !f.name.is_empty() && f.name == name)
+            .map(|i| i as u32)
+    }
+
+    pub(crate) fn find_extern(&self, name: &str) -> Option<u32> {
+        self.externs
+            .iter()
+            .position(|e| e.name == name)
+            .map(|i| i as u32)
+    }
+
+    pub(crate) fn declare_extern(&mut self, Prototype(name, args, ..): &Prototype) -> u32 {
+        match self.find_extern(name) {
+            Some(i) => i,
+            None => {
+                self.externs.push(Extern {
+                    name: name.clone(),
+                    arity: args.len() as u32,
+                });
+                self.externs.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Compiles `f` into the function table, replacing any previous definition of the same
+    /// name, and returns its index. Top-level expressions (with an empty name) always get a
+    /// fresh slot.
+    pub(crate) fn compile_function(
+        &mut self,
+        Function(proto, body): &Function,
+    ) -> Result<u32, Error> {
+        let Prototype(name, args, ..) = proto.as_ref();
+
+        // Register the chunk before compiling the body so that it can call itself.
+        let placeholder = Chunk {
+            name: name.clone(),
+            arity: args.len() as u32,
+            locals: 0,
+            code: vec![],
+        };
+        let (index, previous) = match self.find_function(name) {
+            Some(i) => (
+                i,
+                Some(std::mem::replace(
+                    &mut self.functions[i as usize],
+                    placeholder,
+                )),
+            ),
+            None => {
+                self.functions.push(placeholder);
+                (self.functions.len() as u32 - 1, None)
+            }
+        };
+
+        let mut fc = FunctionCompiler {
+            module: self,
+            scope: args
+                .iter()
+                .enumerate()
+                .map(|(i, a)| (a.clone(), i as u32))
+                .collect(),
+            locals: args.len() as u32,
+            code: vec![],
+        };
+
+        match fc.expr(body) {
+            Ok(()) => {
+                fc.code.push(Op::Ret);
+                let (locals, code) = (fc.locals, fc.code);
+                let chunk = &mut self.functions[index as usize];
+                chunk.locals = locals;
+                chunk.code = code;
+                Ok(index)
+            }
+            Err(e) => {
+                match previous {
+                    Some(chunk) => self.functions[index as usize] = chunk,
+                    None => {
+                        self.functions.pop();
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks that every operand refers to something that exists, so that a module read
+    /// from disk can't make the VM index out of bounds.
+    pub(crate) fn verify(&self) -> Result<(), Error> {
+        for chunk in &self.functions {
+            if chunk.arity > chunk.locals || chunk.code.last() != Some(&Op::Ret) {
+                return Err(bytecode_error("malformed chunk"));
+            }
+            for op in &chunk.code {
+                let ok = match *op {
+                    Op::Load(s) | Op::Store(s) => s < chunk.locals,
+                    Op::Jump(t) | Op::JumpIfFalse(t) => (t as usize) < chunk.code.len(),
+                    Op::Call(f) => (f as usize) < self.functions.len(),
+                    Op::CallExtern(e) => (e as usize) < self.externs.len(),
+                    _ => true,
+                };
+                if !ok {
+                    return Err(bytecode_error("operand out of range"));
+                }
+            }
+        }
+
+        if self
+            .entries
+            .iter()
+            .any(|&e| e as usize >= self.functions.len())
+        {
+            return Err(bytecode_error("entry out of range"));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        fn u32<W: Write>(w: &mut W, v: u32) -> std::io::Result<()> {
+            w.write_all(&v.to_le_bytes())
+        }
+        fn string<W: Write>(w: &mut W, s: &str) -> std::io::Result<()> {
+            u32(w, s.len() as u32)?;
+            w.write_all(s.as_bytes())
+        }
+
+        w.write_all(MAGIC)?;
+        u32(w, VERSION)?;
+
+        u32(w, self.externs.len() as u32)?;
+        for e in &self.externs {
+            string(w, &e.name)?;
+            u32(w, e.arity)?;
+        }
+
+        u32(w, self.functions.len() as u32)?;
+        for f in &self.functions {
+            string(w, &f.name)?;
+            u32(w, f.arity)?;
+            u32(w, f.locals)?;
+            u32(w, f.code.len() as u32)?;
+            for op in &f.code {
+                match *op {
+                    Op::Const(n) => {
+                        w.write_all(&[0])?;
+                        w.write_all(&n.to_le_bytes())?;
+                    }
+                    Op::Load(s) => {
+                        w.write_all(&[1])?;
+                        u32(w, s)?;
+                    }
+                    Op::Store(s) => {
+                        w.write_all(&[2])?;
+                        u32(w, s)?;
+                    }
+                    Op::Pop => w.write_all(&[3])?,
+                    Op::Add => w.write_all(&[4])?,
+                    Op::Sub => w.write_all(&[5])?,
+                    Op::Mul => w.write_all(&[6])?,
+                    Op::Lt => w.write_all(&[7])?,
+                    Op::Jump(t) => {
+                        w.write_all(&[8])?;
+                        u32(w, t)?;
+                    }
+                    Op::JumpIfFalse(t) => {
+                        w.write_all(&[9])?;
+                        u32(w, t)?;
+                    }
+                    Op::Call(f) => {
+                        w.write_all(&[10])?;
+                        u32(w, f)?;
+                    }
+                    Op::CallExtern(e) => {
+                        w.write_all(&[11])?;
+                        u32(w, e)?;
+                    }
+                    Op::Ret => w.write_all(&[12])?,
+                }
+            }
+        }
+
+        u32(w, self.entries.len() as u32)?;
+        for e in &self.entries {
+            u32(w, *e)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read<R: Read>(r: &mut R) -> Result<Module, Error> {
+        fn bytes<R: Read, const N: usize>(r: &mut R) -> Result<[u8; N], Error> {
+            let mut buf = [0; N];
+            r.read_exact(&mut buf)
+                .map_err(|_| bytecode_error("unexpected end of file"))?;
+            Ok(buf)
+        }
+        fn u32<R: Read>(r: &mut R) -> Result<u32, Error> {
+            bytes(r).map(u32::from_le_bytes)
+        }
+        fn string<R: Read>(r: &mut R) -> Result<String, Error> {
+            let len = u32(r)? as u64;
+            let mut buf = Vec::new();
+            r.take(len)
+                .read_to_end(&mut buf)
+                .map_err(|_| bytecode_error("unexpected end of file"))?;
+            if buf.len() as u64 != len {
+                return Err(bytecode_error("unexpected end of file"));
+            }
+            String::from_utf8(buf).map_err(|_| bytecode_error("invalid name"))
+        }
+
+        if &bytes::<_, 4>(r)? != MAGIC {
+            return Err(bytecode_error("not a bytecode file"));
+        }
+        if u32(r)? != VERSION {
+            return Err(bytecode_error("unsupported bytecode version"));
+        }
+
+        let mut module = Module::default();
+
+        for _ in 0..u32(r)? {
+            let name = string(r)?;
+            let arity = u32(r)?;
+            module.externs.push(Extern { name, arity });
+        }
+
+        for _ in 0..u32(r)? {
+            let name = string(r)?;
+            let arity = u32(r)?;
+            let locals = u32(r)?;
+            let mut code = Vec::new();
+            for _ in 0..u32(r)? {
+                code.push(match bytes::<_, 1>(r)?[0] {
+                    0 => Op::Const(f64::from_le_bytes(bytes(r)?)),
+                    1 => Op::Load(u32(r)?),
+                    2 => Op::Store(u32(r)?),
+                    3 => Op::Pop,
+                    4 => Op::Add,
+                    5 => Op::Sub,
+                    6 => Op::Mul,
+                    7 => Op::Lt,
+                    8 => Op::Jump(u32(r)?),
+                    9 => Op::JumpIfFalse(u32(r)?),
+                    10 => Op::Call(u32(r)?),
+                    11 => Op::CallExtern(u32(r)?),
+                    12 => Op::Ret,
+                    _ => return Err(bytecode_error("unknown opcode")),
+                });
+            }
+            module.functions.push(Chunk {
+                name,
+                arity,
+                locals,
+                code,
+            });
+        }
+
+        for _ in 0..u32(r)? {
+            module.entries.push(u32(r)?);
+        }
+
+        module.verify()?;
+
+        Ok(module)
+    }
+}
+
+/// Compiles a whole source file, keeping its top-level expressions as entries to run on load.
+pub(crate) fn compile(items: &[Item]) -> Result<Module, Error> {
+    let mut module = Module::default();
+
+    for item in &typeck::check_items(items)? {
+        match item {
+            Item::Definition(f) => {
+                module.compile_function(f)?;
+            }
+            Item::Extern(p) => {
+                module.declare_extern(p);
+            }
+            Item::TopLevel(f) => {
+                let index = module.compile_function(f)?;
+                module.entries.push(index);
+            }
+            // Rejected by the type checker.
+            Item::Struct(_) | Item::Import(_) => (),
+        }
+    }
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::toplevel;
+    use super::*;
+
+    fn compile_source(src: &str) -> Module {
+        compile(&toplevel::parse_source(src).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_compile() {
+        let module = compile_source("def f(x) x*2; f(3);");
+        assert_eq!(
+            module.functions[0].code,
+            vec![Op::Load(0), Op::Const(2.0), Op::Mul, Op::Ret]
+        );
+        assert_eq!(
+            module.functions[1].code,
+            vec![Op::Const(3.0), Op::Call(0), Op::Ret]
+        );
+        assert_eq!(module.entries, vec![1]);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let items = toplevel::parse_source("def f(x) y;").unwrap();
+        assert!(compile(&items).is_err());
+
+        // A failed redefinition keeps the previous chunk.
+        let mut module = compile_source("def f(x) x;");
+        let items = toplevel::parse_source("def f(x) f(x, x);").unwrap();
+        match &items[0] {
+            Item::Definition(f) => assert!(module.compile_function(f).is_err()),
+            _ => unreachable!(),
+        }
+        assert_eq!(module.functions[0].code, vec![Op::Load(0), Op::Ret]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let module = compile_source(
+            "extern sin(x); def f(x y) if x < y then sin(x) else for i = 0, i < y in x*i; f(1, 2);",
+        );
+        let mut buf = Vec::new();
+        module.write(&mut buf).unwrap();
+        assert_eq!(Module::read(&mut buf.as_slice()).unwrap(), module);
+
+        assert!(Module::read(&mut &buf[..buf.len() - 1]).is_err());
+        assert!(Module::read(&mut &b"KSBX"[..]).is_err());
+    }
+}
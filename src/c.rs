@@ -0,0 +1,583 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use super::ast::{Expr, Function, Prototype};
+use super::error::{Error, ErrorKind};
+use super::toplevel::Item;
+use super::typeck;
+
+/// Words that cannot name a C function or variable.
+const RESERVED: &[&str] = &[
+    "asm", "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "main", "register",
+    "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+    "typeof", "union", "unsigned", "void", "volatile", "while",
+];
+
+/// Rust keywords, which `rust_externs` writes as raw identifiers.
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+fn codegen_error(msg: String) -> Error {
+    Error::from(ErrorKind::Codegen(msg))
+}
+
+/// Formats `n` as a C expression of type `double`.
+fn literal(n: f64) -> String {
+    if n.is_nan() {
+        "(0.0 / 0.0)".to_owned()
+    } else if n.is_infinite() {
+        if n > 0.0 {
+            "(1.0 / 0.0)"
+        } else {
+            "(-1.0 / 0.0)"
+        }
+        .to_owned()
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+/// Lowers one function body to C statements. Every subexpression with side effects or control
+/// flow is evaluated into its own temporary, so C's unspecified evaluation order never matters.
+struct FuncCompiler<'a> {
+    // Functions callable from this one, with their arities.
+    functions: &'a HashMap<String, usize>,
+    // Kaleidoscope variable names to the C locals currently holding them.
+    scope: HashMap<String, String>,
+    // Every C local used so far; Kaleidoscope identifiers are alphanumeric, so temporaries are
+    // prefixed with an underscore and renamed locals get a `_N` suffix.
+    locals: HashSet<String>,
+    temps: usize,
+    indent: usize,
+    out: String,
+    // With -g, the source file, and the line the statements being emitted come from.
+    file: Option<&'a str>,
+    source_line: u32,
+}
+
+impl<'a> FuncCompiler<'a> {
+    fn new_local(&mut self, name: &str) -> String {
+        let mut unique = name.to_owned();
+        let mut n = 0;
+        while self.locals.contains(&unique)
+            || self.functions.contains_key(&unique)
+            || RESERVED.contains(&unique.as_str())
+        {
+            n += 1;
+            unique = format!("{}_{}", name, n);
+        }
+        self.locals.insert(unique.clone());
+        unique
+    }
+
+    fn new_temp(&mut self) -> String {
+        self.temps += 1;
+        format!("_t{}", self.temps - 1)
+    }
+
+    fn line(&mut self, s: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    /// Emits the statements computing `e` and returns a C expression for its value that is free
+    /// of side effects.
+    fn expr(&mut self, e: &Expr) -> Result<String, Error> {
+        match e {
+            Expr::Number(n) => Ok(literal(*n)),
+            // Everything is a double in the generated C; i64 is the JIT's alone, and bools are 0
+            // or 1.
+            Expr::Int(n) => Ok(literal(*n as f64)),
+            Expr::At(pos, e) => {
+                if let Some(file) = self.file {
+                    if pos.line != self.source_line {
+                        self.source_line = pos.line;
+                        self.line(&format!("#line {} {:?}", pos.line, file));
+                    }
+                }
+                self.expr(e)
+            }
+            Expr::Cast(_, e) => self.expr(e),
+            Expr::Str(_)
+            | Expr::Array(_)
+            | Expr::Index(..)
+            | Expr::SetIndex(..)
+            | Expr::Struct(..)
+            | Expr::Field(..)
+            | Expr::Lambda(..)
+            | Expr::Apply(..)
+            | Expr::While(..)
+            | Expr::Break
+            | Expr::Continue
+            | Expr::Return(_) => Err(codegen_error(
+                "this expression is only supported by the JIT backend".to_owned(),
+            )),
+            Expr::Variable(name) => self
+                .scope
+                .get(name)
+                .cloned()
+                .ok_or_else(|| codegen_error(format!("unknown variable name: {}", name))),
+            Expr::Binary(op, lhs, rhs) => {
+                let l = self.expr(lhs)?;
+                let r = self.expr(rhs)?;
+                let value = match op {
+                    '+' | '-' | '*' => format!("{} {} {}", l, op, r),
+                    // `fcmp ult` is true when unordered, unlike C's `<`.
+                    '<' => format!("!({} >= {})", l, r),
+                    _ => return Err(codegen_error(format!("invalid binary operator: {}", op))),
+                };
+                let t = self.new_temp();
+                self.line(&format!("double {} = {};", t, value));
+                Ok(t)
+            }
+            Expr::Call(callee, args) => {
+                match self.functions.get(callee) {
+                    None => return Err(codegen_error(format!("unknown function: {}", callee))),
+                    Some(&arity) if arity != args.len() => {
+                        return Err(codegen_error("incorrect # arguments passed".to_owned()))
+                    }
+                    Some(_) => {}
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let t = self.new_temp();
+                let callee = c_name(callee);
+                self.line(&format!("double {} = {}({});", t, callee, args.join(", ")));
+                Ok(t)
+            }
+            Expr::If(cond, then, els) => {
+                let c = self.expr(cond)?;
+                let t = self.new_temp();
+                self.line(&format!("double {};", t));
+                // Ordered comparisons, so NaN is false as with `fcmp one`.
+                self.line(&format!("if ({} < 0.0 || {} > 0.0) {{", c, c));
+                self.indent += 1;
+                let v = self.expr(then)?;
+                self.line(&format!("{} = {};", t, v));
+                self.indent -= 1;
+                self.line("} else {");
+                self.indent += 1;
+                let v = self.expr(els)?;
+                self.line(&format!("{} = {};", t, v));
+                self.indent -= 1;
+                self.line("}");
+                Ok(t)
+            }
+            Expr::Let(name, value, body) => {
+                let v = self.expr(value)?;
+                let var = self.new_local(name);
+                self.line(&format!("double {} = {};", var, v));
+                // The body need not use it, and unused variables are warnings.
+                self.line(&format!("(void){};", var));
+                let old = self.scope.insert(name.clone(), var);
+                let ret = self.expr(body);
+                match old {
+                    Some(v) => self.scope.insert(name.clone(), v),
+                    None => self.scope.remove(name),
+                };
+                ret
+            }
+            Expr::For(var_name, start, end, step, body) => {
+                let start = self.expr(start)?;
+                let var = self.new_local(var_name);
+                let step_t = self.new_temp();
+                let cond_t = self.new_temp();
+                self.line(&format!("double {} = {};", var, start));
+                self.line(&format!("double {}, {};", step_t, cond_t));
+
+                let old = self.scope.insert(var_name.clone(), var.clone());
+
+                let ret = (|| -> Result<(), Error> {
+                    self.line("do {");
+                    self.indent += 1;
+                    let v = self.expr(body)?;
+                    if v.starts_with('_') {
+                        self.line(&format!("(void){};", v));
+                    }
+                    let s = match step.as_ref() {
+                        Some(step) => self.expr(step)?,
+                        None => "1.0".to_owned(),
+                    };
+                    self.line(&format!("{} = {};", step_t, s));
+                    let c = self.expr(end)?;
+                    self.line(&format!("{} = {} < 0.0 || {} > 0.0;", cond_t, c, c));
+                    self.line(&format!("{} = {} + {};", var, var, step_t));
+                    self.indent -= 1;
+                    self.line(&format!("}} while ({});", cond_t));
+                    Ok(())
+                })();
+
+                match old {
+                    Some(v) => self.scope.insert(var_name.clone(), v),
+                    None => self.scope.remove(var_name),
+                };
+
+                ret.map(|_| "0.0".to_owned())
+            }
+        }
+    }
+}
+
+/// The C name of a function: those of imported files, such as `geometry.area`, become
+/// `geometry_area`.
+fn c_name(name: &str) -> String {
+    name.replace('.', "_")
+}
+
+/// How `name` is spelled in Rust source. The keywords that cannot be raw identifiers get a
+/// trailing underscore instead.
+fn rust_name(name: &str) -> String {
+    match name {
+        "self" | "Self" | "super" | "crate" => format!("{}_", name),
+        _ if RUST_KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name.to_owned(),
+    }
+}
+
+fn signature(name: &str, params: &[String]) -> String {
+    if params.is_empty() {
+        format!("double {}(void)", c_name(name))
+    } else {
+        format!("double {}({})", c_name(name), params.join(", "))
+    }
+}
+
+/// Translates a whole program to C99 source, one function over `double` per `def`. Externs
+/// become declarations and top-level expressions become `double __anon_exprN(void)`.
+pub(crate) fn compile(items: &[Item]) -> Result<String, Error> {
+    compile_items(&typeck::check_items(items)?, None)
+}
+
+/// Like `compile`, but with `#line` directives mapping the C back to `file`, for debuggers.
+pub(crate) fn compile_debug(items: &[Item], file: &str) -> Result<String, Error> {
+    let mut checker = typeck::Checker::new();
+    checker.positions = true;
+    compile_items(&typeck::check_items_with(checker, items)?, Some(file))
+}
+
+fn compile_items(items: &[Item], file: Option<&str>) -> Result<String, Error> {
+    let mut functions: HashMap<String, usize> = HashMap::new();
+    let mut defined = HashSet::new();
+    let mut anon_count = 0;
+    let mut out = String::new();
+
+    for item in items {
+        let (Function(proto, body), name) = match item {
+            Item::Extern(Prototype(name, args, ..)) => {
+                if RESERVED.contains(&name.as_str()) {
+                    return Err(codegen_error(format!("reserved name in C: {}", name)));
+                }
+                if defined.contains(name) {
+                    return Err(codegen_error(format!("redefinition of function: {}", name)));
+                }
+                let params = vec!["double".to_owned(); args.len()];
+                if functions.insert(name.clone(), args.len()).is_none() {
+                    let _ = writeln!(out, "{};\n", signature(name, &params));
+                }
+                continue;
+            }
+            Item::Definition(f) | Item::TopLevel(f) if f.0 .0.is_empty() => {
+                anon_count += 1;
+                (f, format!("__anon_expr{}", anon_count - 1))
+            }
+            Item::Definition(f) | Item::TopLevel(f) => (f, f.0 .0.clone()),
+            // Rejected by the type checker.
+            Item::Struct(_) | Item::Import(_) => continue,
+        };
+
+        if RESERVED.contains(&name.as_str()) {
+            return Err(codegen_error(format!("reserved name in C: {}", name)));
+        }
+        if functions.contains_key(&name) {
+            return Err(codegen_error(format!("redefinition of function: {}", name)));
+        }
+        // Visible to its own body, for recursion.
+        functions.insert(name.clone(), proto.1.len());
+
+        let mut fc = FuncCompiler {
+            functions: &functions,
+            scope: HashMap::new(),
+            locals: HashSet::new(),
+            temps: 0,
+            indent: 1,
+            out: String::new(),
+            file,
+            source_line: 0,
+        };
+        let mut params = Vec::new();
+        for arg in &proto.1 {
+            let local = fc.new_local(arg);
+            params.push(format!("double {}", local));
+            fc.scope.insert(arg.clone(), local);
+        }
+        let ret = match fc.expr(body) {
+            Ok(v) => v,
+            Err(e) => {
+                functions.remove(&name);
+                return Err(e);
+            }
+        };
+        fc.line(&format!("return {};", ret));
+
+        let _ = writeln!(out, "{} {{\n{}}}\n", signature(&name, &params), fc.out);
+        defined.insert(name);
+    }
+
+    out.pop();
+    Ok(out)
+}
+
+/// The prototypes of the named `def`s in `items`, which is all that C code linking against the
+/// compiled module can call.
+fn exported(items: &[Item]) -> Vec<&Prototype> {
+    let mut protos: Vec<&Prototype> = Vec::new();
+    for item in items {
+        if let Item::Definition(Function(proto, _)) = item {
+            if !proto.0.is_empty() && !protos.iter().any(|p| p.0 == proto.0) {
+                protos.push(proto);
+            }
+        }
+    }
+    protos
+}
+
+/// Checks `items` as `compile` does, reserved names included, so that a header only ever
+/// describes code that compiles.
+fn checked(items: &[Item]) -> Result<Vec<Item>, Error> {
+    let items = typeck::check_items(items)?;
+    compile_items(&items, None)?;
+    Ok(items)
+}
+
+/// A C header declaring every named `def` in `items`, guarded by `NAME_H`.
+pub(crate) fn header(items: &[Item], name: &str) -> Result<String, Error> {
+    let items = checked(items)?;
+    let guard = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>()
+        + "_H";
+
+    let mut out = format!("#ifndef {}\n#define {}\n\n", guard, guard);
+    out.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+    for proto in exported(&items) {
+        let params = vec!["double".to_owned(); proto.1.len()];
+        let _ = writeln!(out, "{};", signature(&proto.0, &params));
+    }
+    out.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n");
+    let _ = writeln!(out, "#endif /* {} */", guard);
+    Ok(out)
+}
+
+/// The same declarations as [`header`], as a Rust `extern "C"` block.
+pub(crate) fn rust_externs(items: &[Item]) -> Result<String, Error> {
+    let items = checked(items)?;
+    let mut out = String::from("extern \"C\" {\n");
+    for proto in exported(&items) {
+        let params = proto
+            .1
+            .iter()
+            .map(|p| format!("{}: f64", rust_name(p)))
+            .collect::<Vec<_>>();
+        let symbol = c_name(&proto.0);
+        let name = rust_name(&symbol);
+        // A raw identifier links under the plain name; a renamed one needs the symbol spelled out.
+        if name.ends_with('_') && !symbol.ends_with('_') {
+            let _ = writeln!(out, "    #[link_name = \"{}\"]", symbol);
+        }
+        let _ = writeln!(out, "    pub fn {}({}) -> f64;", name, params.join(", "));
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+/// Compiles `source` together with a driver that prints the bits of each top-level expression,
+/// builds it with the system C compiler and returns the results.
+#[cfg(test)]
+pub(crate) fn run_with_cc(source: &str, anon_exprs: usize) -> Vec<f64> {
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let mut driver = String::from("#include <stdio.h>\n#include <string.h>\n\n");
+    for i in 0..anon_exprs {
+        let _ = writeln!(driver, "double __anon_expr{}(void);", i);
+    }
+    driver.push_str("\nstatic void print(double d) {\n    unsigned long long bits;\n");
+    driver.push_str("    memcpy(&bits, &d, sizeof bits);\n    printf(\"%llx\\n\", bits);\n}\n\n");
+    driver.push_str("int main(void) {\n");
+    for i in 0..anon_exprs {
+        let _ = writeln!(driver, "    print(__anon_expr{}());", i);
+    }
+    driver.push_str("    return 0;\n}\n");
+
+    let dir = std::env::temp_dir().join(format!(
+        "kaleidoscope-c-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("program.c"), source).unwrap();
+    std::fs::write(dir.join("driver.c"), driver).unwrap();
+
+    let status = Command::new("cc")
+        .current_dir(&dir)
+        .args(["-std=c99", "-Wall", "-Werror"])
+        .args(["-o", "program", "program.c", "driver.c", "-lm"])
+        .status()
+        .unwrap();
+    assert!(status.success(), "cc failed on:\n{}", source);
+
+    let output = Command::new(dir.join("program")).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| f64::from_bits(u64::from_str_radix(l, 16).unwrap()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::toplevel;
+    use super::*;
+
+    fn compile_source(src: &str) -> Result<String, Error> {
+        compile(&toplevel::parse_source(src).unwrap())
+    }
+
+    #[test]
+    fn test_run() {
+        let source = compile_source(
+            "extern sqrt(x);
+             def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2);
+             def f(i) (for i = 0, i < 3 in i) + i;
+             def g(int) int * 2;
+             def h(x) (let x = x * 2 in let y = x + 1 in x * y) + x;
+             fib(10); sqrt(16); f(7); g(4); h(3); let unused = 1 in 2;",
+        )
+        .unwrap();
+        assert_eq!(
+            run_with_cc(&source, 6),
+            vec![55.0, 4.0, 7.0, 8.0, 45.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn test_source() {
+        assert_eq!(
+            compile_source("def twice(x) x*2; twice(3);").unwrap(),
+            "double twice(double x) {
+    double _t0 = x * 2.0;
+    return _t0;
+}
+
+double __anon_expr0(void) {
+    double _t0 = twice(3.0);
+    return _t0;
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_line_directives() {
+        let src = "def fib(x)\n  if x < 3 then 1\n  else fib(x-1)+fib(x-2);\nfib(10);";
+        let source = compile_debug(&toplevel::parse_source(src).unwrap(), "fib.ks").unwrap();
+        assert!(source.contains("#line 2 \"fib.ks\""));
+        assert!(source.contains("#line 3 \"fib.ks\""));
+        assert!(source.contains("#line 4 \"fib.ks\""));
+        assert_eq!(run_with_cc(&source, 1), vec![55.0]);
+    }
+
+    #[test]
+    fn test_header() {
+        let items = toplevel::parse_source(
+            "extern sqrt(x); def hyp(a b) sqrt(a*a + b*b); def one() 1; hyp(3, 4);",
+        )
+        .unwrap();
+        assert_eq!(
+            header(&items, "geometry").unwrap(),
+            "#ifndef GEOMETRY_H
+#define GEOMETRY_H
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+double hyp(double, double);
+double one(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* GEOMETRY_H */
+"
+        );
+        assert_eq!(
+            rust_externs(&items).unwrap(),
+            "extern \"C\" {
+    pub fn hyp(a: f64, b: f64) -> f64;
+    pub fn one() -> f64;
+}
+"
+        );
+
+        // The header has to agree with the definitions it describes.
+        let source = header(&items, "geometry").unwrap() + &compile(&items).unwrap();
+        assert_eq!(run_with_cc(&source, 1), vec![5.0]);
+
+        // Only doubles cross into C.
+        let items = toplevel::parse_source("def twice(n: i64) -> i64 n * 2;").unwrap();
+        assert!(header(&items, "twice").is_err());
+        assert!(rust_externs(&items).is_err());
+
+        // Names that cannot be declared in C are rejected as they are by `compile`.
+        let items = toplevel::parse_source("def int(x) x;").unwrap();
+        assert_eq!(
+            header(&items, "int").unwrap_err().to_string(),
+            "Codegen error: reserved name in C: int"
+        );
+        assert!(rust_externs(&items).is_err());
+
+        // Rust keywords are escaped, or renamed where Rust has no raw form for them.
+        let items =
+            toplevel::parse_source("def match(type self) type + self; def self(x) x;").unwrap();
+        assert_eq!(
+            rust_externs(&items).unwrap(),
+            "extern \"C\" {
+    pub fn r#match(r#type: f64, self_: f64) -> f64;
+    #[link_name = \"self\"]
+    pub fn self_(x: f64) -> f64;
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(compile_source("def f(x) g(x);").is_err());
+        assert!(compile_source("def f(x) x; def f(y) y;").is_err());
+        assert!(compile_source("def int(x) x;").is_err());
+    }
+}
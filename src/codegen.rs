@@ -1,56 +1,900 @@
 use llvm_sys::analysis::*;
 use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
 use llvm_sys::prelude::*;
-use llvm_sys::LLVMRealPredicate;
-use std::cell::RefCell;
+use llvm_sys::{
+    LLVMAttributeFunctionIndex, LLVMIntPredicate, LLVMLinkage, LLVMModuleFlagBehavior,
+    LLVMRealPredicate,
+};
 use std::collections::HashMap;
-use std::convert::TryInto;
 use std::ffi::CString;
+use std::path::Path;
 
-use super::ast::{Expr, Function, Prototype};
+use super::ast::{intern_fn, Expr, FnType, Function, Pos, Prototype, StructDef, Type};
 use super::error::{Error, ErrorKind};
+use super::runtime;
+
+// The bindings in llvm-sys 80 predate the SysRoot and SDK parameters that LLVM 11 added to
+// compile units, which the LLVM we link against expects, and lack LLVMDIBuilderFinalizeSubprogram.
+mod dwarf {
+    use llvm_sys::debuginfo::{LLVMDWARFEmissionKind, LLVMDWARFSourceLanguage};
+    use llvm_sys::prelude::*;
+
+    extern "C" {
+        pub(super) fn LLVMDIBuilderCreateCompileUnit(
+            builder: LLVMDIBuilderRef,
+            lang: LLVMDWARFSourceLanguage,
+            file: LLVMMetadataRef,
+            producer: *const libc::c_char,
+            producer_len: libc::size_t,
+            is_optimized: LLVMBool,
+            flags: *const libc::c_char,
+            flags_len: libc::size_t,
+            runtime_ver: libc::c_uint,
+            split_name: *const libc::c_char,
+            split_name_len: libc::size_t,
+            kind: LLVMDWARFEmissionKind,
+            dwo_id: libc::c_uint,
+            split_debug_inlining: LLVMBool,
+            debug_info_for_profiling: LLVMBool,
+            sys_root: *const libc::c_char,
+            sys_root_len: libc::size_t,
+            sdk: *const libc::c_char,
+            sdk_len: libc::size_t,
+        ) -> LLVMMetadataRef;
+
+        pub(super) fn LLVMDIBuilderFinalizeSubprogram(
+            builder: LLVMDIBuilderRef,
+            subprogram: LLVMMetadataRef,
+        );
+    }
+}
+
+/// Where the code being emitted comes from: the subprogram of the function, and the location
+/// of the expression, if any.
+#[derive(Clone, Copy)]
+struct DebugScope {
+    subprogram: LLVMMetadataRef,
+    location: LLVMMetadataRef,
+    line: u32,
+}
+
+/// The DWARF debug information of the current module, which `-g` asks for.
+struct DebugInfo {
+    // The source file and the directory it is in.
+    file_name: String,
+    directory: String,
+    builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    scope: DebugScope,
+}
 
 pub(crate) struct Context {
     context: LLVMContextRef,
     pub(crate) the_module: LLVMModuleRef,
     builder: LLVMBuilderRef,
     pub(crate) double_type: LLVMTypeRef,
+    i64_type: LLVMTypeRef,
+    bool_type: LLVMTypeRef,
+    // An i8*, for pointers to anything, such as closure environments.
+    ptr_type: LLVMTypeRef,
+    // A pointer and a length, laid out like `runtime::Str`.
+    str_type: LLVMTypeRef,
+    // Likewise for `runtime::Array`.
+    array_type: LLVMTypeRef,
+    // Each struct's type and field names, in layout order.
+    structs: HashMap<&'static str, (LLVMTypeRef, Vec<String>)>,
     named_values: HashMap<String, LLVMValueRef>,
+    // Where `continue` and `break` go in each loop being emitted, innermost last.
+    loops: Vec<(LLVMBasicBlockRef, LLVMBasicBlockRef)>,
+    // The block that `return`s outside tail position branch to, and the PHI node there of the
+    // values they return, once the function being emitted has one.
+    exit: Option<(LLVMBasicBlockRef, LLVMValueRef)>,
+    // Every function declared so far, so that calls can be resolved from later modules.
+    function_protos: HashMap<String, Prototype>,
+    anon_count: usize,
+    debug: Option<DebugInfo>,
+    // The limits that code compiled with fuel checks counts down, if any.
+    limits: Option<*mut runtime::Limits>,
 }
 
 impl Context {
     pub(crate) fn new() -> Self {
         let context = unsafe { LLVMContextCreate() };
-        let the_module = unsafe {
-            LLVMModuleCreateWithNameInContext(b"my cool jit\0".as_ptr() as *const _, context)
-        };
         let builder = unsafe { LLVMCreateBuilderInContext(context) };
         let double_type = unsafe { LLVMDoubleTypeInContext(context) };
+        let i64_type = unsafe { LLVMInt64TypeInContext(context) };
+        let bool_type = unsafe { LLVMInt1TypeInContext(context) };
+        let ptr_type = unsafe { LLVMPointerType(LLVMInt8TypeInContext(context), 0) };
+        let str_type = unsafe {
+            let mut fields = [ptr_type, i64_type];
+            LLVMStructTypeInContext(context, fields.as_mut_ptr(), 2, 0)
+        };
+        let array_type = unsafe {
+            let mut fields = [LLVMPointerType(double_type, 0), i64_type];
+            LLVMStructTypeInContext(context, fields.as_mut_ptr(), 2, 0)
+        };
         let named_values = HashMap::new();
 
-        Context {
+        let mut c = Context {
             context,
-            the_module,
+            the_module: std::ptr::null_mut(),
             builder,
             double_type,
+            i64_type,
+            bool_type,
+            ptr_type,
+            str_type,
+            array_type,
+            structs: HashMap::new(),
             named_values,
+            loops: Vec::new(),
+            exit: None,
+            function_protos: HashMap::new(),
+            anon_count: 0,
+            debug: None,
+            limits: None,
+        };
+        c.new_module();
+        c
+    }
+
+    /// Starts a fresh module for subsequent code; the previous one is left to the caller,
+    /// which is expected to have handed it over to the execution engine.
+    pub(crate) fn new_module(&mut self) -> LLVMModuleRef {
+        self.the_module = unsafe {
+            LLVMModuleCreateWithNameInContext(b"my cool jit\0".as_ptr() as *const _, self.context)
+        };
+        unsafe { self.start_debug_info() };
+        self.the_module
+    }
+
+    /// Describes the code of this and later modules as coming from the source file `path`.
+    pub(crate) fn enable_debug_info(&mut self, path: &Path) {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let directory = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy(),
+            _ => ".".into(),
+        };
+        self.debug = Some(DebugInfo {
+            file_name: file_name.into_owned(),
+            directory: directory.into_owned(),
+            builder: std::ptr::null_mut(),
+            file: std::ptr::null_mut(),
+            scope: DebugScope {
+                subprogram: std::ptr::null_mut(),
+                location: std::ptr::null_mut(),
+                line: 0,
+            },
+        });
+        unsafe { self.start_debug_info() };
+    }
+
+    /// Puts fuel checks, against `limits`, in the code compiled from now on.
+    pub(crate) fn enable_limits(&mut self, limits: *mut runtime::Limits) {
+        self.limits = Some(limits);
+    }
+
+    /// Gives the current module a compile unit, if there is debug information.
+    unsafe fn start_debug_info(&mut self) {
+        let d = match &mut self.debug {
+            Some(d) => d,
+            None => return,
+        };
+        d.builder = LLVMCreateDIBuilder(self.the_module);
+        d.file = LLVMDIBuilderCreateFile(
+            d.builder,
+            d.file_name.as_ptr() as *const _,
+            d.file_name.len(),
+            d.directory.as_ptr() as *const _,
+            d.directory.len(),
+        );
+        let producer = "kaleidoscope";
+        dwarf::LLVMDIBuilderCreateCompileUnit(
+            d.builder,
+            LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+            d.file,
+            producer.as_ptr() as *const _,
+            producer.len(),
+            0,
+            b"".as_ptr() as *const _,
+            0,
+            0,
+            b"".as_ptr() as *const _,
+            0,
+            LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+            0,
+            0,
+            0,
+            b"".as_ptr() as *const _,
+            0,
+            b"".as_ptr() as *const _,
+            0,
+        );
+        let key = "Debug Info Version";
+        let version = LLVMConstInt(
+            LLVMInt32TypeInContext(self.context),
+            LLVMDebugMetadataVersion() as u64,
+            0,
+        );
+        LLVMAddModuleFlag(
+            self.the_module,
+            LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+            key.as_ptr() as *const _,
+            key.len(),
+            LLVMValueAsMetadata(version),
+        );
+    }
+
+    /// Completes the debug information of the current module, which must be done before it is
+    /// handed over to the execution engine.
+    pub(crate) fn finish_debug_info(&mut self) {
+        if let Some(d) = &mut self.debug {
+            unsafe {
+                LLVMDIBuilderFinalize(d.builder);
+                LLVMDisposeDIBuilder(d.builder);
+            }
+            d.builder = std::ptr::null_mut();
+        }
+    }
+
+    fn llvm_type(&self, t: Type) -> LLVMTypeRef {
+        match t {
+            Type::F64 => self.double_type,
+            Type::I64 => self.i64_type,
+            Type::Bool => self.bool_type,
+            Type::Str => self.str_type,
+            Type::Array => self.array_type,
+            Type::Struct(name) => self.structs[name].0,
+            // The function and its environment.
+            Type::Fn(sig) => unsafe {
+                let mut fields = [LLVMPointerType(self.closure_fn_type(sig), 0), self.ptr_type];
+                LLVMStructTypeInContext(self.context, fields.as_mut_ptr(), 2, 0)
+            },
         }
     }
+
+    /// The type of the functions closures of type `sig` point to, which take the environment
+    /// before the arguments.
+    unsafe fn closure_fn_type(&self, FnType(params, ret): &FnType) -> LLVMTypeRef {
+        let mut params = Some(self.ptr_type)
+            .into_iter()
+            .chain(params.iter().map(|t| self.llvm_type(*t)))
+            .collect::<Vec<_>>();
+        LLVMFunctionType(
+            self.llvm_type(*ret),
+            params.as_mut_ptr(),
+            params.len() as u32,
+            0,
+        )
+    }
+
+    /// A callee for a function of the runtime, by address, since it has no symbol to link to.
+    unsafe fn runtime_fn(
+        &self,
+        addr: usize,
+        ret: LLVMTypeRef,
+        params: &mut [LLVMTypeRef],
+    ) -> LLVMValueRef {
+        let ft = LLVMFunctionType(ret, params.as_mut_ptr(), params.len() as u32, 0);
+        LLVMConstIntToPtr(
+            LLVMConstInt(self.i64_type, addr as u64, 0),
+            LLVMPointerType(ft, 0),
+        )
+    }
+}
+
+// The type checker has made all conversions explicit, so operations can simply follow the
+// type of their operands.
+unsafe fn is_double(c: &Context, v: LLVMValueRef) -> bool {
+    LLVMTypeOf(v) == c.double_type
+}
+
+/// Converts a condition of any type to an i1 by comparing non-equal to zero.
+unsafe fn codegen_cond(c: &mut Context, v: LLVMValueRef, name: &[u8]) -> LLVMValueRef {
+    let ty = LLVMTypeOf(v);
+    if ty == c.bool_type {
+        v
+    } else if is_double(c, v) {
+        LLVMBuildFCmp(
+            c.builder,
+            LLVMRealPredicate::LLVMRealONE,
+            v,
+            LLVMConstReal(ty, 0.0),
+            name.as_ptr() as *const _,
+        )
+    } else {
+        LLVMBuildICmp(
+            c.builder,
+            LLVMIntPredicate::LLVMIntNE,
+            v,
+            LLVMConstInt(ty, 0, 0),
+            name.as_ptr() as *const _,
+        )
+    }
+}
+
+/// Returns a pointer to element `index` of `array`, trapping unless `index` is in bounds. A
+/// double index must also be a whole number, so it converts to an integer exactly.
+unsafe fn codegen_element(
+    c: &mut Context,
+    array: LLVMValueRef,
+    index: LLVMValueRef,
+) -> LLVMValueRef {
+    let len = LLVMBuildExtractValue(c.builder, array, 1, b"len\0".as_ptr() as *const _);
+    let (in_bounds, indexi) = if is_double(c, index) {
+        let lenf = LLVMBuildSIToFP(
+            c.builder,
+            len,
+            c.double_type,
+            b"lenf\0".as_ptr() as *const _,
+        );
+        let lower = LLVMBuildFCmp(
+            c.builder,
+            LLVMRealPredicate::LLVMRealOGE,
+            index,
+            LLVMConstReal(c.double_type, 0.0),
+            b"lower\0".as_ptr() as *const _,
+        );
+        let upper = LLVMBuildFCmp(
+            c.builder,
+            LLVMRealPredicate::LLVMRealOLT,
+            index,
+            lenf,
+            b"upper\0".as_ptr() as *const _,
+        );
+        let in_range = LLVMBuildAnd(c.builder, lower, upper, b"inrange\0".as_ptr() as *const _);
+        // The conversion is poison out of range, which the select keeps from the branch.
+        let indexi = LLVMBuildFPToSI(
+            c.builder,
+            index,
+            c.i64_type,
+            b"indexi\0".as_ptr() as *const _,
+        );
+        let whole = LLVMBuildFCmp(
+            c.builder,
+            LLVMRealPredicate::LLVMRealOEQ,
+            LLVMBuildSIToFP(
+                c.builder,
+                indexi,
+                c.double_type,
+                b"indexw\0".as_ptr() as *const _,
+            ),
+            index,
+            b"whole\0".as_ptr() as *const _,
+        );
+        (
+            LLVMBuildSelect(
+                c.builder,
+                in_range,
+                whole,
+                LLVMConstInt(c.bool_type, 0, 0),
+                b"inbounds\0".as_ptr() as *const _,
+            ),
+            indexi,
+        )
+    } else {
+        // Negative indexes are too large as unsigned.
+        (
+            LLVMBuildICmp(
+                c.builder,
+                LLVMIntPredicate::LLVMIntULT,
+                index,
+                len,
+                b"inbounds\0".as_ptr() as *const _,
+            ),
+            index,
+        )
+    };
+
+    let the_function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(c.builder));
+    let fail_bb = LLVMAppendBasicBlockInContext(
+        c.context,
+        the_function,
+        b"outofbounds\0".as_ptr() as *const _,
+    );
+    let ok_bb =
+        LLVMAppendBasicBlockInContext(c.context, the_function, b"inbounds\0".as_ptr() as *const _);
+    LLVMBuildCondBr(c.builder, in_bounds, ok_bb, fail_bb);
+
+    LLVMPositionBuilderAtEnd(c.builder, fail_bb);
+    let index_error = c.runtime_fn(
+        runtime::index_error as *const () as usize,
+        LLVMVoidTypeInContext(c.context),
+        &mut [c.double_type, c.i64_type],
+    );
+    let mut args = [
+        if is_double(c, index) {
+            index
+        } else {
+            LLVMBuildSIToFP(
+                c.builder,
+                index,
+                c.double_type,
+                b"indexf\0".as_ptr() as *const _,
+            )
+        },
+        len,
+    ];
+    LLVMBuildCall(
+        c.builder,
+        index_error,
+        args.as_mut_ptr(),
+        2,
+        b"\0".as_ptr() as *const _,
+    );
+    LLVMBuildUnreachable(c.builder);
+
+    LLVMPositionBuilderAtEnd(c.builder, ok_bb);
+    let mut index = indexi;
+    let elements = LLVMBuildExtractValue(c.builder, array, 0, b"elements\0".as_ptr() as *const _);
+    LLVMBuildInBoundsGEP(
+        c.builder,
+        elements,
+        &mut index,
+        1,
+        b"element\0".as_ptr() as *const _,
+    )
+}
+
+/// Adds the variables `e` uses but does not bind to `free`, in order of appearance.
+fn free_variables(e: &Expr, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    match e {
+        Expr::Variable(name) => {
+            if !bound.contains(name) && !free.contains(name) {
+                free.push(name.clone());
+            }
+        }
+        Expr::Number(_) | Expr::Str(_) | Expr::Int(_) | Expr::Break | Expr::Continue => (),
+        Expr::Binary(_, l, r) | Expr::Index(l, r) | Expr::While(l, r) => {
+            free_variables(l, bound, free);
+            free_variables(r, bound, free);
+        }
+        Expr::If(a, b, c) | Expr::SetIndex(a, b, c) => {
+            for e in [a, b, c] {
+                free_variables(e, bound, free);
+            }
+        }
+        Expr::Call(_, es) | Expr::Array(es) => {
+            for e in es {
+                free_variables(e, bound, free);
+            }
+        }
+        Expr::Apply(f, args) => {
+            free_variables(f, bound, free);
+            for e in args {
+                free_variables(e, bound, free);
+            }
+        }
+        Expr::For(var_name, start, end, step, body) => {
+            free_variables(start, bound, free);
+            bound.push(var_name.clone());
+            for e in [&**end, &**body].iter().copied().chain(step.as_ref()) {
+                free_variables(e, bound, free);
+            }
+            bound.pop();
+        }
+        Expr::Let(name, value, body) => {
+            free_variables(value, bound, free);
+            bound.push(name.clone());
+            free_variables(body, bound, free);
+            bound.pop();
+        }
+        Expr::Struct(_, fields, base) => {
+            for (_, e) in fields {
+                free_variables(e, bound, free);
+            }
+            if let Some(base) = base {
+                free_variables(base, bound, free);
+            }
+        }
+        Expr::Field(e, _) | Expr::Cast(_, e) | Expr::At(_, e) | Expr::Return(e) => {
+            free_variables(e, bound, free)
+        }
+        Expr::Lambda(proto, body) => {
+            let n = bound.len();
+            bound.extend(proto.1.iter().cloned());
+            free_variables(body, bound, free);
+            bound.truncate(n);
+        }
+    }
+}
+
+// DWARF base type encodings.
+const DW_ATE_BOOLEAN: LLVMDWARFTypeEncoding = 0x02;
+const DW_ATE_FLOAT: LLVMDWARFTypeEncoding = 0x04;
+const DW_ATE_SIGNED: LLVMDWARFTypeEncoding = 0x05;
+
+unsafe fn debug_type(builder: LLVMDIBuilderRef, t: Type) -> LLVMMetadataRef {
+    let name = t.to_string();
+    let (bits, encoding) = match t {
+        Type::F64 => (64, DW_ATE_FLOAT),
+        Type::I64 => (64, DW_ATE_SIGNED),
+        Type::Bool => (8, DW_ATE_BOOLEAN),
+        // Debuggers get the name of anything else, but not its layout.
+        _ => {
+            return LLVMDIBuilderCreateUnspecifiedType(
+                builder,
+                name.as_ptr() as *const _,
+                name.len(),
+            )
+        }
+    };
+    LLVMDIBuilderCreateBasicType(
+        builder,
+        name.as_ptr() as *const _,
+        name.len(),
+        bits,
+        encoding,
+        0,
+    )
+}
+
+/// Moves the builder to `scope`, which later instructions are attributed to.
+unsafe fn set_debug_scope(c: &mut Context, scope: DebugScope) {
+    if let Some(d) = &mut c.debug {
+        d.scope = scope;
+        let location = match scope.location {
+            l if l.is_null() => std::ptr::null_mut(),
+            l => LLVMMetadataAsValue(c.context, l),
+        };
+        LLVMSetCurrentDebugLocation(c.builder, location);
+    }
+}
+
+/// Gives `func` a subprogram at `line`, whose type is made of the return type and parameter
+/// types in `types`, and attributes the code that follows to it. Returns the scope to go back to
+/// once `func` is done.
+unsafe fn begin_debug_function(
+    c: &mut Context,
+    func: LLVMValueRef,
+    types: &[Type],
+    line: u32,
+) -> Option<DebugScope> {
+    let d = c.debug.as_ref()?;
+    let mut len = 0;
+    let name = LLVMGetValueName2(func, &mut len);
+    let mut types = types
+        .iter()
+        .map(|t| debug_type(d.builder, *t))
+        .collect::<Vec<_>>();
+    let ty = LLVMDIBuilderCreateSubroutineType(
+        d.builder,
+        d.file,
+        types.as_mut_ptr(),
+        types.len() as u32,
+        0,
+    );
+    let local = LLVMGetLinkage(func) == LLVMLinkage::LLVMInternalLinkage;
+    let subprogram = LLVMDIBuilderCreateFunction(
+        d.builder,
+        d.file,
+        name,
+        len,
+        name,
+        len,
+        d.file,
+        line,
+        ty,
+        local as LLVMBool,
+        1,
+        line,
+        LLVMDIFlagPrototyped,
+        0,
+    );
+    LLVMSetSubprogram(func, subprogram);
+
+    let outer = d.scope;
+    let location =
+        LLVMDIBuilderCreateDebugLocation(c.context, line, 0, subprogram, std::ptr::null_mut());
+    set_debug_scope(
+        c,
+        DebugScope {
+            subprogram,
+            location,
+            line,
+        },
+    );
+    Some(outer)
+}
+
+/// Completes the subprogram of the function just emitted, whose variables are only final once
+/// the whole body is.
+unsafe fn end_debug_function(c: &Context) {
+    if let Some(d) = &c.debug {
+        if !d.scope.subprogram.is_null() {
+            dwarf::LLVMDIBuilderFinalizeSubprogram(d.builder, d.scope.subprogram);
+        }
+    }
+}
+
+/// Describes the parameters of the function being emitted, whose values are `values`, at the end
+/// of `block`.
+unsafe fn debug_params(
+    c: &mut Context,
+    Prototype(_, args, types, _): &Prototype,
+    values: &[LLVMValueRef],
+    block: LLVMBasicBlockRef,
+) {
+    let d = match &c.debug {
+        Some(d) if !d.scope.subprogram.is_null() => d,
+        _ => return,
+    };
+    for (i, (name, value)) in args.iter().zip(values).enumerate() {
+        let ty = debug_type(d.builder, types[i].unwrap_or(Type::F64));
+        let var = LLVMDIBuilderCreateParameterVariable(
+            d.builder,
+            d.scope.subprogram,
+            name.as_ptr() as *const _,
+            name.len(),
+            i as u32 + 1,
+            d.file,
+            d.scope.line,
+            ty,
+            1,
+            0,
+        );
+        LLVMDIBuilderInsertDbgValueAtEnd(
+            d.builder,
+            *value,
+            var,
+            LLVMDIBuilderCreateExpression(d.builder, std::ptr::null_mut(), 0),
+            d.scope.location,
+            block,
+        );
+    }
+}
+
+/// Runs `emit`, attributing the code it emits to `pos` if there is debug information.
+unsafe fn located<T>(c: &mut Context, pos: Pos, emit: impl FnOnce(&mut Context) -> T) -> T {
+    let outer = match &c.debug {
+        Some(d) if !d.scope.subprogram.is_null() => d.scope,
+        _ => return emit(c),
+    };
+    let location = LLVMDIBuilderCreateDebugLocation(
+        c.context,
+        pos.line,
+        pos.col,
+        outer.subprogram,
+        std::ptr::null_mut(),
+    );
+    set_debug_scope(
+        c,
+        DebugScope {
+            location,
+            line: pos.line,
+            ..outer
+        },
+    );
+    let ret = emit(c);
+    set_debug_scope(c, outer);
+    ret
+}
+
+/// Takes a step's worth of fuel, if there are limits, having the runtime refill the tank or
+/// give up once it is empty. Emitted at function entries and loop headers.
+unsafe fn codegen_fuel(c: &mut Context) {
+    let limits = match c.limits {
+        Some(limits) => limits,
+        None => return,
+    };
+    let limits = LLVMConstInt(c.i64_type, limits as u64, 0);
+    let tank = LLVMConstIntToPtr(limits, LLVMPointerType(c.i64_type, 0));
+    let fuel = LLVMBuildLoad(c.builder, tank, b"fuel\0".as_ptr() as *const _);
+    let fuel = LLVMBuildSub(
+        c.builder,
+        fuel,
+        LLVMConstInt(c.i64_type, 1, 0),
+        b"fuel\0".as_ptr() as *const _,
+    );
+    LLVMBuildStore(c.builder, fuel, tank);
+    let empty = LLVMBuildICmp(
+        c.builder,
+        LLVMIntPredicate::LLVMIntSLT,
+        fuel,
+        LLVMConstInt(c.i64_type, 0, 0),
+        b"empty\0".as_ptr() as *const _,
+    );
+
+    let the_function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(c.builder));
+    let refuel_bb =
+        LLVMAppendBasicBlockInContext(c.context, the_function, b"refuel\0".as_ptr() as *const _);
+    let fueled_bb =
+        LLVMAppendBasicBlockInContext(c.context, the_function, b"fueled\0".as_ptr() as *const _);
+    LLVMBuildCondBr(c.builder, empty, refuel_bb, fueled_bb);
+
+    LLVMPositionBuilderAtEnd(c.builder, refuel_bb);
+    let refuel = c.runtime_fn(
+        runtime::refuel as *const () as usize,
+        LLVMVoidTypeInContext(c.context),
+        &mut [c.i64_type],
+    );
+    let mut args = [limits];
+    LLVMBuildCall(
+        c.builder,
+        refuel,
+        args.as_mut_ptr(),
+        1,
+        b"\0".as_ptr() as *const _,
+    );
+    LLVMBuildBr(c.builder, fueled_bb);
+
+    LLVMPositionBuilderAtEnd(c.builder, fueled_bb);
+}
+
+/// Emits the body of `func`, which `emit` returns the value of, from the middle of another
+/// function; the builder and variables are left as they were.
+unsafe fn codegen_nested(
+    c: &mut Context,
+    func: LLVMValueRef,
+    emit: impl FnOnce(&mut Context) -> Result<LLVMValueRef, Error>,
+) -> Result<(), Error> {
+    let block = LLVMGetInsertBlock(c.builder);
+    let named_values = std::mem::take(&mut c.named_values);
+    let loops = std::mem::take(&mut c.loops);
+    let exit = c.exit.take();
+    let line = c.debug.as_ref().map_or(0, |d| d.scope.line);
+    let debug = begin_debug_function(c, func, &[], line);
+    let bb = LLVMAppendBasicBlockInContext(c.context, func, b"entry\0".as_ptr() as *const _);
+    LLVMPositionBuilderAtEnd(c.builder, bb);
+    codegen_fuel(c);
+    let ret = emit(c).and_then(|v| {
+        LLVMBuildRet(c.builder, v);
+        codegen_exit(c);
+        end_debug_function(c);
+        if LLVMVerifyFunction(func, LLVMVerifierFailureAction::LLVMPrintMessageAction) != 0 {
+            return Err(Error::from(ErrorKind::Codegen(
+                "invalid closure generated".to_owned(),
+            )));
+        }
+        Ok(())
+    });
+    c.named_values = named_values;
+    c.loops = loops;
+    c.exit = exit;
+    if let Some(outer) = debug {
+        set_debug_scope(c, outer);
+    }
+    LLVMPositionBuilderAtEnd(c.builder, block);
+    ret
+}
+
+/// Finishes the exit block of the function just emitted, if it has one, and moves it last.
+unsafe fn codegen_exit(c: &mut Context) {
+    if let Some((exit_bb, phi)) = c.exit.take() {
+        LLVMMoveBasicBlockAfter(exit_bb, LLVMGetInsertBlock(c.builder));
+        LLVMPositionBuilderAtEnd(c.builder, exit_bb);
+        LLVMBuildRet(c.builder, phi);
+    }
+}
+
+/// Branches to `target`. Anything after the jump is unreachable, but still needs a block to go
+/// in; the jump itself has no value.
+unsafe fn codegen_jump(c: &mut Context, target: LLVMBasicBlockRef) -> LLVMValueRef {
+    LLVMBuildBr(c.builder, target);
+    let the_function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(c.builder));
+    let dead_bb =
+        LLVMAppendBasicBlockInContext(c.context, the_function, b"afterjump\0".as_ptr() as *const _);
+    LLVMPositionBuilderAtEnd(c.builder, dead_bb);
+    LLVMGetUndef(c.double_type)
+}
+
+/// Emits the body of a loop, in which `continue` and `break` jump to the given blocks.
+unsafe fn codegen_loop_body(
+    c: &mut Context,
+    body: &Expr,
+    continue_bb: LLVMBasicBlockRef,
+    break_bb: LLVMBasicBlockRef,
+) -> Result<LLVMValueRef, Error> {
+    c.loops.push((continue_bb, break_bb));
+    let ret = codegen_expr(c, body);
+    c.loops.pop();
+    ret
+}
+
+unsafe fn codegen_closure(c: &mut Context, func: LLVMValueRef, env: LLVMValueRef) -> LLVMValueRef {
+    let mut fields = [LLVMTypeOf(func), c.ptr_type];
+    let ty = LLVMStructTypeInContext(c.context, fields.as_mut_ptr(), 2, 0);
+    let name = b"closure\0".as_ptr() as *const _;
+    let v = LLVMBuildInsertValue(c.builder, LLVMGetUndef(ty), func, 0, name);
+    LLVMBuildInsertValue(c.builder, v, env, 1, name)
+}
+
+/// A closure of the named function `func`, through a wrapper that drops the environment.
+unsafe fn codegen_function_value(
+    c: &mut Context,
+    name: &str,
+    func: LLVMValueRef,
+) -> Result<LLVMValueRef, Error> {
+    let wrapper_name = CString::new(format!("{}.closure", name)).unwrap();
+    let mut wrapper = LLVMGetNamedFunction(c.the_module, wrapper_name.as_ptr());
+    if wrapper.is_null() {
+        let Prototype(_, args, types, ret) = c.function_protos[name].clone();
+        let params = types.iter().map(|t| t.unwrap_or(Type::F64)).collect();
+        let sig = intern_fn(params, ret.unwrap_or(Type::F64));
+        wrapper = LLVMAddFunction(c.the_module, wrapper_name.as_ptr(), c.closure_fn_type(sig));
+        LLVMSetLinkage(wrapper, LLVMLinkage::LLVMInternalLinkage);
+        codegen_nested(c, wrapper, |c| {
+            let mut args = (1..=args.len())
+                .map(|i| LLVMGetParam(wrapper, i as u32))
+                .collect::<Vec<_>>();
+            Ok(LLVMBuildCall(
+                c.builder,
+                func,
+                args.as_mut_ptr(),
+                args.len() as u32,
+                b"calltmp\0".as_ptr() as *const _,
+            ))
+        })?;
+    }
+    Ok(codegen_closure(c, wrapper, LLVMConstNull(c.ptr_type)))
+}
+
+unsafe fn get_function(c: &mut Context, name: &str) -> Result<Option<LLVMValueRef>, Error> {
+    let func = LLVMGetNamedFunction(c.the_module, CString::new(name).unwrap().as_ptr());
+    if !func.is_null() {
+        return Ok(Some(func));
+    }
+
+    // The function lives in an earlier module; emit a declaration for it in this one.
+    match c.function_protos.get(name).cloned() {
+        Some(proto) => codegen_proto(c, &proto).map(Some),
+        None => Ok(None),
+    }
 }
 
 unsafe fn codegen_expr(c: &mut Context, e: &Expr) -> Result<LLVMValueRef, Error> {
     match e {
         Expr::Number(n) => Ok(LLVMConstReal(c.double_type, *n)),
+        Expr::Int(n) => Ok(LLVMConstInt(c.i64_type, *n as u64, 1)),
+        Expr::Str(s) => {
+            // The bytes go into a private constant of the current module, which the execution
+            // engine keeps alive.
+            let bytes =
+                LLVMConstStringInContext(c.context, s.as_ptr() as *const _, s.len() as u32, 1);
+            let global = LLVMAddGlobal(
+                c.the_module,
+                LLVMTypeOf(bytes),
+                b"str\0".as_ptr() as *const _,
+            );
+            LLVMSetInitializer(global, bytes);
+            LLVMSetGlobalConstant(global, 1);
+            LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+            let mut fields = [
+                LLVMConstBitCast(global, LLVMStructGetTypeAtIndex(c.str_type, 0)),
+                LLVMConstInt(c.i64_type, s.len() as u64, 0),
+            ];
+            Ok(LLVMConstNamedStruct(c.str_type, fields.as_mut_ptr(), 2))
+        }
         Expr::Variable(name) => match c.named_values.get(name) {
-            Some(v) => Ok(v.clone()),
-            None => Err(Error::from(ErrorKind::Codegen(format!(
-                "unknown variable name: {}",
-                name
-            )))),
+            Some(v) => Ok(*v),
+            None => match get_function(c, name)? {
+                Some(func) => codegen_function_value(c, name, func),
+                None => Err(Error::from(ErrorKind::Codegen(format!(
+                    "unknown variable name: {}",
+                    name
+                )))),
+            },
         },
         Expr::Binary(op, lhs, rhs) => {
             let lhs_val = codegen_expr(c, lhs)?;
             let rhs_val = codegen_expr(c, rhs)?;
+            if !is_double(c, lhs_val) {
+                let name = b"inttmp\0".as_ptr() as *const _;
+                return match op {
+                    '+' => Ok(LLVMBuildAdd(c.builder, lhs_val, rhs_val, name)),
+                    '-' => Ok(LLVMBuildSub(c.builder, lhs_val, rhs_val, name)),
+                    '*' => Ok(LLVMBuildMul(c.builder, lhs_val, rhs_val, name)),
+                    '<' => Ok(LLVMBuildICmp(
+                        c.builder,
+                        LLVMIntPredicate::LLVMIntSLT,
+                        lhs_val,
+                        rhs_val,
+                        b"cmptmp\0".as_ptr() as *const _,
+                    )),
+                    _ => Err(Error::from(ErrorKind::Codegen("op '<' failed".to_owned()))),
+                };
+            }
             match op {
                 '+' => Ok(LLVMBuildFAdd(
                     c.builder,
@@ -71,43 +915,36 @@ unsafe fn codegen_expr(c: &mut Context, e: &Expr) -> Result<LLVMValueRef, Error>
                     rhs_val,
                     b"multmp\0".as_ptr() as *const _,
                 )),
-                '<' => {
-                    let i = LLVMBuildFCmp(
-                        c.builder,
-                        LLVMRealPredicate::LLVMRealULT,
-                        lhs_val,
-                        rhs_val,
-                        b"cmptmp\0".as_ptr() as *const _,
-                    );
-                    Ok(LLVMBuildUIToFP(
-                        c.builder,
-                        i,
-                        c.double_type,
-                        b"booltmp\0".as_ptr() as *const _,
-                    ))
-                }
+                '<' => Ok(LLVMBuildFCmp(
+                    c.builder,
+                    LLVMRealPredicate::LLVMRealULT,
+                    lhs_val,
+                    rhs_val,
+                    b"cmptmp\0".as_ptr() as *const _,
+                )),
                 _ => Err(Error::from(ErrorKind::Codegen("op '<' failed".to_owned()))),
             }
         }
         Expr::Call(callee, args) => {
-            let func =
-                LLVMGetNamedFunction(c.the_module, CString::new(callee.clone()).unwrap().as_ptr());
-            if LLVMIsNull(func) == 1 {
-                return Err(Error::from(ErrorKind::Codegen(format!(
-                    "unknown function: {}",
-                    callee
-                ))));
-            }
+            let func = match get_function(c, callee)? {
+                Some(func) => func,
+                None => {
+                    return Err(Error::from(ErrorKind::Codegen(format!(
+                        "unknown function: {}",
+                        callee
+                    ))))
+                }
+            };
 
             let param_cnt = LLVMCountParams(func);
             if param_cnt as usize != args.len() {
-                return Err(Error::from(ErrorKind::Codegen(format!(
-                    "incorrect # arguments passed",
-                ))));
+                return Err(Error::from(ErrorKind::Codegen(
+                    "incorrect # arguments passed".to_owned(),
+                )));
             }
 
             let mut args = args
-                .into_iter()
+                .iter()
                 .map(|e| codegen_expr(c, e))
                 .collect::<Result<Vec<_>, _>>()?;
 
@@ -119,20 +956,457 @@ unsafe fn codegen_expr(c: &mut Context, e: &Expr) -> Result<LLVMValueRef, Error>
                 b"calltmp\0".as_ptr() as *const _,
             ))
         }
-        _ => unimplemented!(),
+        Expr::If(cond, then, els) => {
+            let cond_v = codegen_expr(c, cond)?;
+
+            let cond_v = codegen_cond(c, cond_v, b"ifcond\0");
+
+            let the_function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(c.builder));
+
+            let then_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"then\0".as_ptr() as *const _,
+            );
+            let else_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"else\0".as_ptr() as *const _,
+            );
+            let merge_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"ifcont\0".as_ptr() as *const _,
+            );
+
+            LLVMBuildCondBr(c.builder, cond_v, then_bb, else_bb);
+
+            LLVMPositionBuilderAtEnd(c.builder, then_bb);
+            let mut then_v = codegen_expr(c, then)?;
+            LLVMBuildBr(c.builder, merge_bb);
+            // Codegen of 'then' can change the current block, update then_bb for the PHI.
+            let mut then_bb = LLVMGetInsertBlock(c.builder);
+
+            LLVMPositionBuilderAtEnd(c.builder, else_bb);
+            let mut else_v = codegen_expr(c, els)?;
+            LLVMBuildBr(c.builder, merge_bb);
+            let mut else_bb = LLVMGetInsertBlock(c.builder);
+
+            // A branch that jumps has no value of its own.
+            if then.jumps() {
+                then_v = LLVMGetUndef(LLVMTypeOf(else_v));
+            } else if els.jumps() {
+                else_v = LLVMGetUndef(LLVMTypeOf(then_v));
+            }
+
+            LLVMPositionBuilderAtEnd(c.builder, merge_bb);
+            let pn = LLVMBuildPhi(
+                c.builder,
+                LLVMTypeOf(then_v),
+                b"iftmp\0".as_ptr() as *const _,
+            );
+            LLVMAddIncoming(pn, &mut then_v, &mut then_bb, 1);
+            LLVMAddIncoming(pn, &mut else_v, &mut else_bb, 1);
+
+            Ok(pn)
+        }
+        Expr::For(var_name, start, end, step, body) => {
+            // Emit the start code first, without 'variable' in scope.
+            let mut start_val = codegen_expr(c, start)?;
+
+            // Make the new basic block for the loop header, inserting after current block.
+            let mut preheader_bb = LLVMGetInsertBlock(c.builder);
+            let the_function = LLVMGetBasicBlockParent(preheader_bb);
+            let loop_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"loop\0".as_ptr() as *const _,
+            );
+
+            // Insert an explicit fall through from the current block to the loop_bb.
+            LLVMBuildBr(c.builder, loop_bb);
+
+            LLVMPositionBuilderAtEnd(c.builder, loop_bb);
+
+            // Start the PHI node with an entry for start.
+            let var_type = LLVMTypeOf(start_val);
+            let variable = LLVMBuildPhi(
+                c.builder,
+                var_type,
+                CString::new(var_name.clone()).unwrap().as_ptr(),
+            );
+            LLVMAddIncoming(variable, &mut start_val, &mut preheader_bb, 1);
+            codegen_fuel(c);
+
+            // Within the loop, the variable is defined equal to the PHI node. If it shadows an
+            // existing variable, we have to restore it, so save it now.
+            let old_val = c.named_values.insert(var_name.clone(), variable);
+
+            let ret = (|| {
+                // `continue` goes on to the step, and `break` past the loop.
+                let cont_bb = LLVMAppendBasicBlockInContext(
+                    c.context,
+                    the_function,
+                    b"forcont\0".as_ptr() as *const _,
+                );
+                let after_bb = LLVMAppendBasicBlockInContext(
+                    c.context,
+                    the_function,
+                    b"afterloop\0".as_ptr() as *const _,
+                );
+
+                // Emit the body of the loop. Like any other expr, this can change the current
+                // block. Note that we ignore the value computed by the body.
+                codegen_loop_body(c, body, cont_bb, after_bb)?;
+                LLVMBuildBr(c.builder, cont_bb);
+                LLVMMoveBasicBlockAfter(cont_bb, LLVMGetInsertBlock(c.builder));
+                LLVMPositionBuilderAtEnd(c.builder, cont_bb);
+
+                let step_val = match step.as_ref() {
+                    Some(step) => codegen_expr(c, step)?,
+                    None if var_type == c.double_type => LLVMConstReal(var_type, 1.0),
+                    None => LLVMConstInt(var_type, 1, 0),
+                };
+
+                let mut next_var = if var_type == c.double_type {
+                    LLVMBuildFAdd(
+                        c.builder,
+                        variable,
+                        step_val,
+                        b"nextvar\0".as_ptr() as *const _,
+                    )
+                } else {
+                    LLVMBuildAdd(
+                        c.builder,
+                        variable,
+                        step_val,
+                        b"nextvar\0".as_ptr() as *const _,
+                    )
+                };
+
+                // Compute the end condition and convert it to a bool.
+                let end_cond = codegen_expr(c, end)?;
+                let end_cond = codegen_cond(c, end_cond, b"loopcond\0");
+
+                // Move the "after loop" block after the rest.
+                let mut loop_end_bb = LLVMGetInsertBlock(c.builder);
+                LLVMMoveBasicBlockAfter(after_bb, loop_end_bb);
+
+                LLVMBuildCondBr(c.builder, end_cond, loop_bb, after_bb);
+
+                LLVMPositionBuilderAtEnd(c.builder, after_bb);
+
+                // Add a new entry to the PHI node for the backedge.
+                LLVMAddIncoming(variable, &mut next_var, &mut loop_end_bb, 1);
+
+                // for expr always returns 0.0.
+                Ok(LLVMConstReal(c.double_type, 0.0))
+            })();
+
+            // Restore the unshadowed variable.
+            match old_val {
+                Some(v) => c.named_values.insert(var_name.clone(), v),
+                None => c.named_values.remove(var_name),
+            };
+
+            ret
+        }
+        Expr::Let(name, value, body) => {
+            let v = codegen_expr(c, value)?;
+            let old_val = c.named_values.insert(name.clone(), v);
+            let ret = codegen_expr(c, body);
+            match old_val {
+                Some(v) => c.named_values.insert(name.clone(), v),
+                None => c.named_values.remove(name),
+            };
+            ret
+        }
+        Expr::While(cond, body) => {
+            let the_function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(c.builder));
+            let cond_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"whilecond\0".as_ptr() as *const _,
+            );
+            let body_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"whilebody\0".as_ptr() as *const _,
+            );
+            let after_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"afterwhile\0".as_ptr() as *const _,
+            );
+            LLVMBuildBr(c.builder, cond_bb);
+
+            LLVMPositionBuilderAtEnd(c.builder, cond_bb);
+            codegen_fuel(c);
+            let cond_v = codegen_expr(c, cond)?;
+            let cond_v = codegen_cond(c, cond_v, b"whilecond\0");
+            LLVMBuildCondBr(c.builder, cond_v, body_bb, after_bb);
+
+            LLVMPositionBuilderAtEnd(c.builder, body_bb);
+            codegen_loop_body(c, body, cond_bb, after_bb)?;
+            LLVMBuildBr(c.builder, cond_bb);
+
+            LLVMMoveBasicBlockAfter(after_bb, LLVMGetInsertBlock(c.builder));
+            LLVMPositionBuilderAtEnd(c.builder, after_bb);
+
+            // Like for, while always returns 0.0.
+            Ok(LLVMConstReal(c.double_type, 0.0))
+        }
+        Expr::Break | Expr::Continue => {
+            let (continue_bb, break_bb) = match c.loops.last() {
+                Some(targets) => *targets,
+                None => {
+                    return Err(Error::from(ErrorKind::Codegen(
+                        "jump outside of a loop".to_owned(),
+                    )))
+                }
+            };
+            let target = if *e == Expr::Break {
+                break_bb
+            } else {
+                continue_bb
+            };
+            Ok(codegen_jump(c, target))
+        }
+        Expr::Return(value) => {
+            let mut v = codegen_expr(c, value)?;
+            let mut bb = LLVMGetInsertBlock(c.builder);
+            let (exit_bb, phi) = match c.exit {
+                Some(exit) => exit,
+                None => {
+                    let the_function = LLVMGetBasicBlockParent(bb);
+                    let exit_bb = LLVMAppendBasicBlockInContext(
+                        c.context,
+                        the_function,
+                        b"return\0".as_ptr() as *const _,
+                    );
+                    LLVMPositionBuilderAtEnd(c.builder, exit_bb);
+                    let phi =
+                        LLVMBuildPhi(c.builder, LLVMTypeOf(v), b"retval\0".as_ptr() as *const _);
+                    LLVMPositionBuilderAtEnd(c.builder, bb);
+                    c.exit = Some((exit_bb, phi));
+                    (exit_bb, phi)
+                }
+            };
+            LLVMAddIncoming(phi, &mut v, &mut bb, 1);
+            Ok(codegen_jump(c, exit_bb))
+        }
+        Expr::Array(elements) => {
+            let values = elements
+                .iter()
+                .map(|e| codegen_expr(c, e))
+                .collect::<Result<Vec<_>, _>>()?;
+            let alloc = c.runtime_fn(
+                runtime::array as *const () as usize,
+                c.array_type,
+                &mut [c.double_type],
+            );
+            let mut len = LLVMConstReal(c.double_type, values.len() as f64);
+            let array = LLVMBuildCall(
+                c.builder,
+                alloc,
+                &mut len,
+                1,
+                b"arraytmp\0".as_ptr() as *const _,
+            );
+            let ptr =
+                LLVMBuildExtractValue(c.builder, array, 0, b"elements\0".as_ptr() as *const _);
+            for (i, v) in values.into_iter().enumerate() {
+                let mut index = LLVMConstInt(c.i64_type, i as u64, 0);
+                let element = LLVMBuildInBoundsGEP(
+                    c.builder,
+                    ptr,
+                    &mut index,
+                    1,
+                    b"element\0".as_ptr() as *const _,
+                );
+                LLVMBuildStore(c.builder, v, element);
+            }
+            Ok(array)
+        }
+        Expr::Index(array, index) => {
+            let array = codegen_expr(c, array)?;
+            let index = codegen_expr(c, index)?;
+            let element = codegen_element(c, array, index);
+            Ok(LLVMBuildLoad(
+                c.builder,
+                element,
+                b"loadtmp\0".as_ptr() as *const _,
+            ))
+        }
+        Expr::SetIndex(array, index, value) => {
+            let array = codegen_expr(c, array)?;
+            let index = codegen_expr(c, index)?;
+            let value = codegen_expr(c, value)?;
+            let element = codegen_element(c, array, index);
+            LLVMBuildStore(c.builder, value, element);
+            Ok(value)
+        }
+        Expr::Struct(name, fields, base) => {
+            let (ty, names) = c.structs[name.as_str()].clone();
+            let values = fields
+                .iter()
+                .map(|(f, e)| Ok((f, codegen_expr(c, e)?)))
+                .collect::<Result<Vec<_>, Error>>()?;
+            let mut v = match base {
+                Some(base) => codegen_expr(c, base)?,
+                None => LLVMGetUndef(ty),
+            };
+            for (f, value) in values {
+                let i = names.iter().position(|n| n == f).unwrap();
+                v = LLVMBuildInsertValue(
+                    c.builder,
+                    v,
+                    value,
+                    i as u32,
+                    b"structtmp\0".as_ptr() as *const _,
+                );
+            }
+            Ok(v)
+        }
+        Expr::Field(e, field) => {
+            let v = codegen_expr(c, e)?;
+            let name = std::ffi::CStr::from_ptr(LLVMGetStructName(LLVMTypeOf(v)));
+            let (_, names) = &c.structs[name.to_str().unwrap()];
+            let i = names.iter().position(|n| n == field).unwrap();
+            Ok(LLVMBuildExtractValue(
+                c.builder,
+                v,
+                i as u32,
+                b"fieldtmp\0".as_ptr() as *const _,
+            ))
+        }
+        Expr::Lambda(proto, body) => {
+            let Prototype(_, args, types, ret) = proto.as_ref();
+            let params = types.iter().map(|t| t.unwrap_or(Type::F64)).collect();
+            let sig = intern_fn(params, ret.unwrap_or(Type::F64));
+
+            // Variables are captured by value, into an environment on the heap that is never
+            // freed.
+            let mut captures = Vec::new();
+            free_variables(body, &mut args.clone(), &mut captures);
+            captures.retain(|name| c.named_values.contains_key(name));
+            let values = captures
+                .iter()
+                .map(|name| c.named_values[name])
+                .collect::<Vec<_>>();
+            let mut types = values.iter().map(|v| LLVMTypeOf(*v)).collect::<Vec<_>>();
+            let env_type =
+                LLVMStructTypeInContext(c.context, types.as_mut_ptr(), types.len() as u32, 0);
+            let env = if captures.is_empty() {
+                LLVMConstNull(c.ptr_type)
+            } else {
+                let env = LLVMBuildMalloc(c.builder, env_type, b"env\0".as_ptr() as *const _);
+                for (i, v) in values.into_iter().enumerate() {
+                    let field = LLVMBuildStructGEP(
+                        c.builder,
+                        env,
+                        i as u32,
+                        b"capture\0".as_ptr() as *const _,
+                    );
+                    LLVMBuildStore(c.builder, v, field);
+                }
+                LLVMBuildBitCast(c.builder, env, c.ptr_type, b"env\0".as_ptr() as *const _)
+            };
+
+            let func = LLVMAddFunction(
+                c.the_module,
+                b"__lambda\0".as_ptr() as *const _,
+                c.closure_fn_type(sig),
+            );
+            LLVMSetLinkage(func, LLVMLinkage::LLVMInternalLinkage);
+            codegen_nested(c, func, |c| {
+                let env = LLVMBuildBitCast(
+                    c.builder,
+                    LLVMGetParam(func, 0),
+                    LLVMPointerType(env_type, 0),
+                    b"env\0".as_ptr() as *const _,
+                );
+                for (i, name) in captures.iter().enumerate() {
+                    let field = LLVMBuildStructGEP(
+                        c.builder,
+                        env,
+                        i as u32,
+                        b"capture\0".as_ptr() as *const _,
+                    );
+                    let cname = CString::new(name.clone()).unwrap();
+                    let v = LLVMBuildLoad(c.builder, field, cname.as_ptr());
+                    c.named_values.insert(name.clone(), v);
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    c.named_values
+                        .insert(arg.clone(), LLVMGetParam(func, i as u32 + 1));
+                }
+                codegen_expr(c, body)
+            })?;
+            Ok(codegen_closure(c, func, env))
+        }
+        Expr::Apply(callee, args) => {
+            let closure = codegen_expr(c, callee)?;
+            let func = LLVMBuildExtractValue(c.builder, closure, 0, b"fn\0".as_ptr() as *const _);
+            let env = LLVMBuildExtractValue(c.builder, closure, 1, b"env\0".as_ptr() as *const _);
+            let mut values = vec![env];
+            for arg in args {
+                values.push(codegen_expr(c, arg)?);
+            }
+            Ok(LLVMBuildCall(
+                c.builder,
+                func,
+                values.as_mut_ptr(),
+                values.len() as u32,
+                b"calltmp\0".as_ptr() as *const _,
+            ))
+        }
+        Expr::At(pos, e) => located(c, *pos, |c| codegen_expr(c, e)),
+        Expr::Cast(t, e) => {
+            let v = codegen_expr(c, e)?;
+            let ty = c.llvm_type(*t);
+            let name = b"casttmp\0".as_ptr() as *const _;
+            Ok(match (LLVMTypeOf(v) == c.bool_type, *t) {
+                (true, Type::F64) => LLVMBuildUIToFP(c.builder, v, ty, name),
+                (false, Type::F64) => LLVMBuildSIToFP(c.builder, v, ty, name),
+                _ => LLVMBuildZExt(c.builder, v, ty, name),
+            })
+        }
     }
 }
 
+/// Creates the named LLVM type for a struct. The type checker only lets a struct be redefined
+/// identically, so an existing type is kept.
+pub(crate) unsafe fn codegen_struct(c: &mut Context, StructDef(name, fields): &StructDef) {
+    let name = super::ast::intern(name);
+    if c.structs.contains_key(name) {
+        return;
+    }
+    let mut types = fields
+        .iter()
+        .map(|(_, t)| c.llvm_type(*t))
+        .collect::<Vec<_>>();
+    let cname = CString::new(name).unwrap();
+    let ty = LLVMStructCreateNamed(c.context, cname.as_ptr());
+    LLVMStructSetBody(ty, types.as_mut_ptr(), types.len() as u32, 0);
+    c.structs
+        .insert(name, (ty, fields.iter().map(|(f, _)| f.clone()).collect()));
+}
+
 pub(crate) unsafe fn codegen_proto(
     c: &mut Context,
-    Prototype(name, args): &Prototype,
+    proto: &Prototype,
 ) -> Result<LLVMValueRef, Error> {
+    let Prototype(name, args, types, ret) = proto;
     let func = LLVMGetNamedFunction(c.the_module, CString::new(name.clone()).unwrap().as_ptr());
-    let func = if LLVMIsNull(func) == 0 {
-        let mut doubles = vec![c.double_type; args.len()];
+    let func = if func.is_null() {
+        let mut params = types
+            .iter()
+            .map(|t| c.llvm_type(t.unwrap_or(Type::F64)))
+            .collect::<Vec<_>>();
         let ft = LLVMFunctionType(
-            c.double_type,
-            doubles.as_mut_ptr(),
+            c.llvm_type(ret.unwrap_or(Type::F64)),
+            params.as_mut_ptr(),
             args.len() as u32,
             0, /* isvararg is false*/
         );
@@ -141,28 +1415,106 @@ pub(crate) unsafe fn codegen_proto(
             CString::new(name.clone()).unwrap().as_ptr(),
             ft,
         )
+    } else if LLVMCountBasicBlocks(func) != 0 {
+        return Err(Error::from(ErrorKind::Codegen(format!(
+            "function {} is already defined in this module",
+            name
+        ))));
+    } else if LLVMCountParams(func) as usize != args.len() {
+        return Err(Error::from(ErrorKind::Codegen(format!(
+            "function {} was declared with {} parameters",
+            name,
+            LLVMCountParams(func)
+        ))));
     } else {
-        //TODO: If `func` already has a body, reject this.
-
-        //TODO: If `func` took a different number of arguments, reject.
         func
     };
 
     // Set names for all arguments.
-    let pcnt = LLVMCountParams(func) as usize;
-    for i in 0..pcnt {
+    for (i, arg_name) in args.iter().enumerate() {
         let arg = LLVMGetParam(func, i as u32);
         LLVMSetValueName2(
             arg,
-            CString::new(args[i].clone()).unwrap().as_ptr(),
-            args[i].len(),
+            CString::new(arg_name.clone()).unwrap().as_ptr(),
+            arg_name.len(),
         );
-        c.named_values.insert(args[i].clone(), arg);
     }
 
+    c.function_protos.insert(name.clone(), proto.clone());
+
     Ok(func)
 }
 
+/// Where calls a function makes to itself in tail position go instead: back to the header,
+/// with the arguments as the new values of the parameters.
+struct Recursion<'a> {
+    name: &'a str,
+    header: LLVMBasicBlockRef,
+    params: Vec<LLVMValueRef>,
+}
+
+/// Emits `e` in tail position, returning its value from the function. Recursion there becomes
+/// a loop, so that recursive functions written as loops run in constant stack space; other
+/// calls are marked as tail calls.
+unsafe fn codegen_tail(c: &mut Context, e: &Expr, this: &Recursion) -> Result<(), Error> {
+    match e {
+        Expr::At(pos, e) => located(c, *pos, |c| codegen_tail(c, e, this)),
+        Expr::Return(e) => codegen_tail(c, e, this),
+        Expr::If(cond, then, els) => {
+            let cond_v = codegen_expr(c, cond)?;
+            let cond_v = codegen_cond(c, cond_v, b"ifcond\0");
+
+            let the_function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(c.builder));
+            let then_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"then\0".as_ptr() as *const _,
+            );
+            let else_bb = LLVMAppendBasicBlockInContext(
+                c.context,
+                the_function,
+                b"else\0".as_ptr() as *const _,
+            );
+            LLVMBuildCondBr(c.builder, cond_v, then_bb, else_bb);
+
+            LLVMPositionBuilderAtEnd(c.builder, then_bb);
+            codegen_tail(c, then, this)?;
+            LLVMPositionBuilderAtEnd(c.builder, else_bb);
+            codegen_tail(c, els, this)
+        }
+        Expr::Let(name, value, body) => {
+            let v = codegen_expr(c, value)?;
+            let old_val = c.named_values.insert(name.clone(), v);
+            let ret = codegen_tail(c, body, this);
+            match old_val {
+                Some(v) => c.named_values.insert(name.clone(), v),
+                None => c.named_values.remove(name),
+            };
+            ret
+        }
+        Expr::Call(callee, args) if callee == this.name => {
+            let args = args
+                .iter()
+                .map(|e| codegen_expr(c, e))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut bb = LLVMGetInsertBlock(c.builder);
+            for (phi, mut arg) in this.params.iter().zip(args) {
+                LLVMAddIncoming(*phi, &mut arg, &mut bb, 1);
+            }
+            LLVMBuildBr(c.builder, this.header);
+            Ok(())
+        }
+        _ => {
+            let v = codegen_expr(c, e)?;
+            if !LLVMIsACallInst(v).is_null() {
+                LLVMSetTailCall(v, 1);
+            }
+            LLVMBuildRet(c.builder, v);
+            Ok(())
+        }
+    }
+}
+
 pub(crate) unsafe fn codegen_func(
     c: &mut Context,
     the_fpm: LLVMPassManagerRef,
@@ -170,30 +1522,162 @@ pub(crate) unsafe fn codegen_func(
 ) -> Result<LLVMValueRef, Error> {
     c.named_values.clear();
 
+    // Top-level expressions get a unique name so the JIT can look each of them up.
+    let anon;
+    let proto = if proto.0.is_empty() {
+        anon = Prototype::new(format!("__anon_expr{}", c.anon_count), vec![]);
+        c.anon_count += 1;
+        &anon
+    } else {
+        proto.as_ref()
+    };
+
+    let known = c.function_protos.contains_key(&proto.0);
     let the_function = codegen_proto(c, proto)?;
-    let ret = {
-        let bb =
+    // Prototypes have no position of their own; use that of the body.
+    let line = match body.as_ref() {
+        Expr::At(pos, _) => pos.line,
+        _ => 0,
+    };
+    let types = Some(proto.3)
+        .iter()
+        .chain(&proto.2)
+        .map(|t| t.unwrap_or(Type::F64))
+        .collect::<Vec<_>>();
+    let debug = begin_debug_function(c, the_function, &types, line);
+    let ret = (|| {
+        let mut bb =
             LLVMAppendBasicBlockInContext(c.context, the_function, b"entry\0".as_ptr() as *const _);
+        let header = LLVMAppendBasicBlockInContext(
+            c.context,
+            the_function,
+            b"tailrecurse\0".as_ptr() as *const _,
+        );
         LLVMPositionBuilderAtEnd(c.builder, bb);
+        LLVMBuildBr(c.builder, header);
+
+        // The arguments are PHI nodes in the header, so that a call to the function in tail
+        // position can jump back to it with new ones. Record them in the named_values map.
+        LLVMPositionBuilderAtEnd(c.builder, header);
+        let mut params = Vec::new();
+        for (i, arg_name) in proto.1.iter().enumerate() {
+            let mut arg = LLVMGetParam(the_function, i as u32);
+            let name = CString::new(arg_name.clone()).unwrap();
+            let phi = LLVMBuildPhi(c.builder, LLVMTypeOf(arg), name.as_ptr());
+            LLVMAddIncoming(phi, &mut arg, &mut bb, 1);
+            c.named_values.insert(arg_name.clone(), phi);
+            params.push(phi);
+        }
 
-        let ret_val = codegen_expr(c, body)?;
-        let _ = LLVMBuildRet(c.builder, ret_val);
+        debug_params(c, proto, &params, header);
+        // Every call and tail call passes through here.
+        codegen_fuel(c);
+
+        let this = Recursion {
+            name: &proto.0,
+            header,
+            params,
+        };
+        c.exit = None;
+        codegen_tail(c, body, &this)?;
+        codegen_exit(c);
+        end_debug_function(c);
 
         //Validate the generated code, checking for consistency.
-        LLVMVerifyFunction(
+        if LLVMVerifyFunction(
             the_function,
-            LLVMVerifierFailureAction::LLVMAbortProcessAction,
-        );
+            LLVMVerifierFailureAction::LLVMPrintMessageAction,
+        ) != 0
+        {
+            return Err(Error::from(ErrorKind::Codegen(format!(
+                "invalid function generated: {}",
+                proto.0
+            ))));
+        }
 
         LLVMRunFunctionPassManager(the_fpm, the_function);
 
         Ok(the_function)
-    };
+    })();
+
+    if let Some(outer) = debug {
+        set_debug_scope(c, outer);
+    }
 
-    if let Err(_) = ret {
-        println!("codegen_func errro:{:?}", ret);
+    if ret.is_err() {
         LLVMDeleteFunction(the_function);
+        if !known {
+            c.function_protos.remove(&proto.0);
+        }
     }
 
     ret
 }
+
+/// Emits `i32 __catch(double ()* f, i8* buf, double* out)`, which stores the result of `f` in
+/// `out` and returns 0, unless the runtime abandons `f` with a `siglongjmp` to `buf`, in which
+/// case it returns the value passed to that. Doing the `sigsetjmp` in LLVM code rather than in
+/// Rust is what makes returning twice sound.
+pub(crate) unsafe fn codegen_catch(c: &mut Context) -> LLVMValueRef {
+    let i32_type = LLVMInt32TypeInContext(c.context);
+    let i8_ptr = LLVMPointerType(LLVMInt8TypeInContext(c.context), 0);
+    let body_type = LLVMFunctionType(c.double_type, std::ptr::null_mut(), 0, 0);
+    let mut params = [
+        LLVMPointerType(body_type, 0),
+        i8_ptr,
+        LLVMPointerType(c.double_type, 0),
+    ];
+    let ft = LLVMFunctionType(i32_type, params.as_mut_ptr(), 3, 0);
+    let the_function = LLVMAddFunction(c.the_module, b"__catch\0".as_ptr() as *const _, ft);
+
+    // glibc's name for the function behind the `sigsetjmp` macro.
+    let mut params = [i8_ptr, i32_type];
+    let ft = LLVMFunctionType(i32_type, params.as_mut_ptr(), 2, 0);
+    let setjmp = LLVMAddFunction(c.the_module, b"__sigsetjmp\0".as_ptr() as *const _, ft);
+    let name = "returns_twice";
+    let kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const _, name.len());
+    let returns_twice = LLVMCreateEnumAttribute(c.context, kind, 0);
+    LLVMAddAttributeAtIndex(setjmp, LLVMAttributeFunctionIndex, returns_twice);
+
+    let entry =
+        LLVMAppendBasicBlockInContext(c.context, the_function, b"entry\0".as_ptr() as *const _);
+    let run = LLVMAppendBasicBlockInContext(c.context, the_function, b"run\0".as_ptr() as *const _);
+    let trapped =
+        LLVMAppendBasicBlockInContext(c.context, the_function, b"trapped\0".as_ptr() as *const _);
+
+    LLVMPositionBuilderAtEnd(c.builder, entry);
+    // Save the signal mask too, for traps raised from signal handlers.
+    let mut args = [LLVMGetParam(the_function, 1), LLVMConstInt(i32_type, 1, 0)];
+    let jumped = LLVMBuildCall(
+        c.builder,
+        setjmp,
+        args.as_mut_ptr(),
+        2,
+        b"jumped\0".as_ptr() as *const _,
+    );
+    LLVMAddCallSiteAttribute(jumped, LLVMAttributeFunctionIndex, returns_twice);
+    let first = LLVMBuildICmp(
+        c.builder,
+        LLVMIntPredicate::LLVMIntEQ,
+        jumped,
+        LLVMConstInt(i32_type, 0, 0),
+        b"first\0".as_ptr() as *const _,
+    );
+    LLVMBuildCondBr(c.builder, first, run, trapped);
+
+    LLVMPositionBuilderAtEnd(c.builder, run);
+    let result = LLVMBuildCall(
+        c.builder,
+        LLVMGetParam(the_function, 0),
+        std::ptr::null_mut(),
+        0,
+        b"result\0".as_ptr() as *const _,
+    );
+    LLVMBuildStore(c.builder, result, LLVMGetParam(the_function, 2));
+    LLVMBuildRet(c.builder, LLVMConstInt(i32_type, 0, 0));
+
+    LLVMPositionBuilderAtEnd(c.builder, trapped);
+    LLVMBuildRet(c.builder, jumped);
+
+    the_function
+}
@@ -0,0 +1,246 @@
+//! Differential testing: random well-formed programs are run through the LLVM JIT, the bytecode
+//! VM, the WebAssembly and C backends and the tree-walking interpreter, which serves as the reference
+//! evaluator, and the results are compared bit for bit.
+
+use super::ast::{Expr, Function, Prototype};
+use super::c;
+use super::error::Error;
+use super::interp::Interpreter;
+use super::jit::Engine;
+use super::toplevel::{self, Backend, Item};
+use super::vm::Vm;
+use super::wasm;
+
+/// xorshift64*, good enough for generating test programs without pulling in a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// The externs programs may call. Both are exact, so every backend rounds them alike, and `sqrt`
+/// of a negative number brings NaN into comparisons and conditions.
+const EXTERNS: [&str; 2] = ["sqrt", "floor"];
+
+/// Generates programs that always terminate: calls only go to previously generated functions
+/// and every `for` loop counts up to a constant bound.
+struct Gen {
+    rng: Rng,
+    functions: Vec<(String, usize)>,
+    loop_vars: usize,
+}
+
+impl Gen {
+    fn new(seed: u64) -> Self {
+        Gen {
+            rng: Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1),
+            functions: Vec::new(),
+            loop_vars: 0,
+        }
+    }
+
+    fn number(&mut self) -> Expr {
+        // Quarters keep the constants exact while still exercising fractional arithmetic.
+        Expr::Number((self.rng.below(81) as f64 - 40.0) / 4.0)
+    }
+
+    fn leaf(&mut self, vars: &[String]) -> Expr {
+        if vars.is_empty() || self.rng.below(3) == 0 {
+            self.number()
+        } else {
+            Expr::Variable(vars[self.rng.below(vars.len() as u64) as usize].clone())
+        }
+    }
+
+    fn expr(&mut self, depth: u32, vars: &[String]) -> Expr {
+        if depth == 0 || self.rng.below(5) == 0 {
+            return self.leaf(vars);
+        }
+
+        match self.rng.below(11) {
+            0..=4 => {
+                let op = ['+', '-', '*', '<'][self.rng.below(4) as usize];
+                Expr::Binary(
+                    op,
+                    Box::new(self.expr(depth - 1, vars)),
+                    Box::new(self.expr(depth - 1, vars)),
+                )
+            }
+            5 | 6 if !self.functions.is_empty() => {
+                let (name, arity) =
+                    self.functions[self.rng.below(self.functions.len() as u64) as usize].clone();
+                let args = (0..arity).map(|_| self.expr(depth - 1, vars)).collect();
+                Expr::Call(name, args)
+            }
+            7 => {
+                let name = EXTERNS[self.rng.below(EXTERNS.len() as u64) as usize];
+                Expr::Call(name.to_owned(), vec![self.expr(depth - 1, vars)])
+            }
+            8 | 9 => Expr::If(
+                Box::new(self.expr(depth - 1, vars)),
+                Box::new(self.expr(depth - 1, vars)),
+                Box::new(self.expr(depth - 1, vars)),
+            ),
+            _ => {
+                // Reuse an existing name now and then to exercise shadowing.
+                let var = if !vars.is_empty() && self.rng.below(4) == 0 {
+                    vars[self.rng.below(vars.len() as u64) as usize].clone()
+                } else {
+                    self.loop_vars += 1;
+                    format!("i{}", self.loop_vars)
+                };
+                let start = self.rng.below(5) as f64;
+                let end = start + self.rng.below(5) as f64;
+                let step = if self.rng.below(2) == 0 {
+                    None
+                } else {
+                    Some(Expr::Number(1.0 + self.rng.below(2) as f64))
+                };
+
+                let mut body_vars = vars.to_vec();
+                body_vars.push(var.clone());
+                let body = self.expr(depth - 1, &body_vars);
+
+                Expr::For(
+                    var.clone(),
+                    Box::new(Expr::Number(start)),
+                    Box::new(Expr::Binary(
+                        '<',
+                        Box::new(Expr::Variable(var)),
+                        Box::new(Expr::Number(end)),
+                    )),
+                    Box::new(step),
+                    Box::new(body),
+                )
+            }
+        }
+    }
+
+    fn function(&mut self) -> Function {
+        let name = format!("f{}", self.functions.len());
+        let params = (0..self.rng.below(4))
+            .map(|i| format!("a{}", i))
+            .collect::<Vec<_>>();
+        let body = self.expr(4, &params);
+        self.functions.push((name.clone(), params.len()));
+        Function(Box::new(Prototype::new(name, params)), Box::new(body))
+    }
+
+    fn toplevel(&mut self) -> Function {
+        Function(
+            Box::new(Prototype::new("".to_owned(), vec![])),
+            Box::new(self.expr(3, &[])),
+        )
+    }
+}
+
+fn same(a: f64, b: f64) -> bool {
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+fn run(backend: &mut dyn Backend, items: &[Item]) -> Result<Vec<f64>, Error> {
+    let mut results = Vec::new();
+    for item in items.iter().cloned() {
+        match item {
+            Item::Definition(f) => backend.define(f)?,
+            Item::Extern(p) => backend.declare(p)?,
+            Item::TopLevel(f) => results.push(backend.eval(f)?),
+            Item::Struct(s) => backend.define_struct(s)?,
+            Item::Import(_) => unreachable!(),
+        }
+    }
+    Ok(results)
+}
+
+// `u64::is_multiple_of` needs Rust 1.87.
+#[allow(clippy::manual_is_multiple_of)]
+fn check(seed: u64) {
+    let mut gen = Gen::new(seed);
+    let mut items = EXTERNS
+        .iter()
+        .map(|name| Item::Extern(Prototype::new(name.to_string(), vec!["x".to_owned()])))
+        .collect::<Vec<_>>();
+    for _ in 0..1 + gen.rng.below(4) {
+        items.push(Item::Definition(gen.function()));
+    }
+    for _ in 0..3 {
+        items.push(Item::TopLevel(gen.toplevel()));
+    }
+
+    let expected = run(&mut Interpreter::new(), &items).unwrap();
+
+    let exports = ["__anon_expr0", "__anon_expr1", "__anon_expr2"];
+    let results = [
+        ("vm", run(&mut Vm::new(), &items)),
+        ("jit", run(&mut Engine::new(), &items)),
+        (
+            "wasm",
+            wasm::compile(&items).map(|m| wasm::run_exports(&m.to_wasm(), &exports)),
+        ),
+        // Building with cc is slow, so only a sample of the programs go through C.
+        (
+            "c",
+            if seed % 10 == 0 {
+                c::compile(&items).map(|source| c::run_with_cc(&source, exports.len()))
+            } else {
+                Ok(expected.clone())
+            },
+        ),
+    ];
+
+    for (backend, actual) in results.iter() {
+        let actual = actual
+            .as_ref()
+            .unwrap_or_else(|e| panic!("seed {}: {} failed: {}\n{:#?}", seed, backend, e, items));
+        assert!(
+            expected.len() == actual.len()
+                && expected.iter().zip(actual).all(|(e, a)| same(*e, *a)),
+            "seed {}: {} returned {:?} but the interpreter returned {:?}\n{:#?}",
+            seed,
+            backend,
+            actual,
+            expected,
+            items
+        );
+    }
+}
+
+#[test]
+fn test_integer_overflow() {
+    // 21! overflows i64, which wraps on the JIT; the other backends compute in doubles, so
+    // rather than round they refuse i64 altogether.
+    let items = toplevel::parse_source(
+        "def fact(n: i64) -> i64 if n < 2 then 1 else n * fact(n - 1); fact(21);",
+    )
+    .unwrap();
+    let mut jit = Engine::new();
+    assert_eq!(
+        run(&mut jit, &items).unwrap(),
+        vec![51_090_942_171_709_440_000u128 as i64 as f64]
+    );
+    let refused = "Type error: i64 is only supported by the JIT backend";
+    for result in [
+        run(&mut Interpreter::new(), &items).map(|_| ()),
+        run(&mut Vm::new(), &items).map(|_| ()),
+        wasm::compile(&items).map(|_| ()),
+        c::compile(&items).map(|_| ()),
+    ] {
+        assert_eq!(result.unwrap_err().to_string(), refused);
+    }
+}
+
+#[test]
+fn test_backends_agree() {
+    for seed in 0..300 {
+        check(seed);
+    }
+}
@@ -0,0 +1,403 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, stdin, stdout, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+
+// History kept across sessions; older lines are dropped from the front of the file.
+const MAX_HISTORY: usize = 1000;
+
+/// Reads lines from the terminal with emacs-style editing, history and tab completion. When
+/// stdin is not a terminal it reads plain lines, so piped input works as before.
+pub(crate) struct Editor {
+    history: Vec<String>,
+    file: Option<PathBuf>,
+}
+
+/// Puts the terminal in raw mode for as long as it lives.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        unsafe {
+            let mut old: libc::termios = std::mem::zeroed();
+            if libc::isatty(libc::STDIN_FILENO) == 0
+                || libc::tcgetattr(libc::STDIN_FILENO, &mut old) != 0
+            {
+                return None;
+            }
+            let mut raw = old;
+            raw.c_iflag &= !(libc::ICRNL | libc::IXON | libc::BRKINT | libc::ISTRIP);
+            raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG | libc::IEXTEN);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) != 0 {
+                return None;
+            }
+            Some(RawMode(old))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.0) };
+    }
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillEnd,
+    KillStart,
+    KillWord,
+    Clear,
+    Interrupt,
+    Eof,
+    Other,
+}
+
+fn read_byte(input: &mut impl Read) -> Option<u8> {
+    let mut b = [0];
+    match input.read(&mut b) {
+        Ok(1) => Some(b[0]),
+        _ => None,
+    }
+}
+
+fn read_key(input: &mut impl Read) -> Option<Key> {
+    let b = read_byte(input)?;
+    Some(match b {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        127 | 8 => Key::Backspace,
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,
+        6 => Key::Right,
+        11 => Key::KillEnd,
+        12 => Key::Clear,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillStart,
+        23 => Key::KillWord,
+        27 => match (read_byte(input)?, read_byte(input)?) {
+            (b'[', b'A') | (b'O', b'A') => Key::Up,
+            (b'[', b'B') | (b'O', b'B') => Key::Down,
+            (b'[', b'C') | (b'O', b'C') => Key::Right,
+            (b'[', b'D') | (b'O', b'D') => Key::Left,
+            (b'[', b'H') | (b'O', b'H') => Key::Home,
+            (b'[', b'F') | (b'O', b'F') => Key::End,
+            (b'[', n @ b'0'..=b'9') => {
+                // Sequences like `ESC [ 3 ~`; anything longer is skipped whole.
+                let mut last = read_byte(input)?;
+                let single = last == b'~';
+                while !(0x40..=0x7e).contains(&last) {
+                    last = read_byte(input)?;
+                }
+                match n {
+                    b'3' if single => Key::Delete,
+                    b'1' | b'7' if single => Key::Home,
+                    b'4' | b'8' if single => Key::End,
+                    _ => Key::Other,
+                }
+            }
+            _ => Key::Other,
+        },
+        0..=31 => Key::Other,
+        _ => {
+            // The rest of a UTF-8 sequence follows its leading byte.
+            let len = match b {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![b];
+            for _ in 1..len {
+                bytes.push(read_byte(input)?);
+            }
+            match std::str::from_utf8(&bytes) {
+                Ok(s) => Key::Char(s.chars().next().unwrap()),
+                Err(_) => Key::Other,
+            }
+        }
+    })
+}
+
+/// The completions of the word that ends at `cursor` in `line`: the index where the word
+/// starts and the candidates it is a prefix of, sorted and without duplicates. A word is an
+/// identifier, or a command when the line starts with `:`.
+pub(crate) fn complete(
+    line: &[char],
+    cursor: usize,
+    candidates: &[String],
+) -> (usize, Vec<String>) {
+    let mut start = cursor;
+    while start > 0 && line[start - 1].is_alphanumeric() {
+        start -= 1;
+    }
+    if start == 1 && line[0] == ':' {
+        start = 0;
+    }
+    let word = line[start..cursor].iter().collect::<String>();
+    let mut matches = candidates
+        .iter()
+        .filter(|c| c.starts_with(&word))
+        .cloned()
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches.dedup();
+    (start, matches)
+}
+
+fn common_prefix(words: &[String]) -> String {
+    let mut prefix = words[0].clone();
+    for w in &words[1..] {
+        let len = prefix
+            .chars()
+            .zip(w.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+impl Editor {
+    /// An editor whose history is loaded from, and saved to, `file`.
+    pub(crate) fn new(file: Option<PathBuf>) -> Self {
+        let history = file
+            .as_ref()
+            .and_then(|path| File::open(path).ok())
+            .map(|f| {
+                let lines = BufReader::new(f)
+                    .lines()
+                    .map_while(Result::ok)
+                    .collect::<Vec<_>>();
+                let skip = lines.len().saturating_sub(MAX_HISTORY);
+                lines[skip..].to_vec()
+            })
+            .unwrap_or_default();
+        Editor { history, file }
+    }
+
+    /// Adds `line` to the history unless it is blank or repeats the previous line.
+    pub(crate) fn add_history(&mut self, line: &str) {
+        let line = line.trim_end();
+        if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_owned());
+        if let Some(path) = &self.file {
+            // Losing history is no reason to stop the session.
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", line));
+        }
+    }
+
+    /// Reads a line after showing `prompt`, offering `candidates()` on tab. Returns `None` at
+    /// the end of input, which on a terminal is Ctrl-D on an empty line.
+    pub(crate) fn read_line(
+        &mut self,
+        prompt: &str,
+        candidates: &dyn Fn() -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        print!("{}", prompt);
+        stdout().flush()?;
+        let _raw = match RawMode::enable() {
+            Some(raw) => raw,
+            None => {
+                let mut line = String::new();
+                return Ok(match stdin().read_line(&mut line)? {
+                    0 => None,
+                    _ => Some(line),
+                });
+            }
+        };
+        self.edit(prompt, &mut stdin().lock(), candidates)
+    }
+
+    fn edit(
+        &mut self,
+        prompt: &str,
+        input: &mut impl Read,
+        candidates: &dyn Fn() -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let mut out = stdout();
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Where in the history the line came from, and what was typed before going there.
+        let mut entry = self.history.len();
+        let mut typed: Vec<char> = Vec::new();
+        loop {
+            let key = match read_key(input) {
+                Some(key) => key,
+                None => return Ok(None),
+            };
+            match key {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => {
+                    write!(out, "\r\n")?;
+                    out.flush()?;
+                    let mut line = line.into_iter().collect::<String>();
+                    line.push('\n');
+                    return Ok(Some(line));
+                }
+                Key::Eof if line.is_empty() => {
+                    write!(out, "\r\n")?;
+                    out.flush()?;
+                    return Ok(None);
+                }
+                Key::Eof | Key::Delete => {
+                    if cursor < line.len() {
+                        line.remove(cursor);
+                    }
+                }
+                Key::Backspace => {
+                    if cursor > 0 {
+                        cursor -= 1;
+                        line.remove(cursor);
+                    }
+                }
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = (cursor + 1).min(line.len()),
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::KillEnd => line.truncate(cursor),
+                Key::KillStart => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::KillWord => {
+                    let mut start = cursor;
+                    while start > 0 && line[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    while start > 0 && !line[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    line.drain(start..cursor);
+                    cursor = start;
+                }
+                Key::Up | Key::Down => {
+                    let next = match key {
+                        Key::Up if entry > 0 => entry - 1,
+                        Key::Down if entry < self.history.len() => entry + 1,
+                        _ => continue,
+                    };
+                    if entry == self.history.len() {
+                        typed = line.clone();
+                    }
+                    entry = next;
+                    line = match self.history.get(entry) {
+                        Some(l) => l.chars().collect(),
+                        None => typed.clone(),
+                    };
+                    cursor = line.len();
+                }
+                Key::Tab => {
+                    let (start, matches) = complete(&line, cursor, &candidates());
+                    if matches.is_empty() {
+                        continue;
+                    }
+                    let prefix = common_prefix(&matches);
+                    if prefix.chars().count() > cursor - start {
+                        line.splice(start..cursor, prefix.chars());
+                        cursor = start + prefix.chars().count();
+                        if matches.len() == 1 && line.get(cursor) != Some(&' ') {
+                            line.insert(cursor, ' ');
+                            cursor += 1;
+                        }
+                    } else if matches.len() > 1 {
+                        write!(out, "\r\n{}\r\n", matches.join("  "))?;
+                    }
+                }
+                Key::Clear => write!(out, "\x1b[H\x1b[2J")?,
+                Key::Interrupt => {
+                    write!(out, "^C\r\n")?;
+                    line.clear();
+                    cursor = 0;
+                    entry = self.history.len();
+                }
+                Key::Other => continue,
+            }
+            // Redraws the line and puts the cursor back where it belongs.
+            let text = line.iter().collect::<String>();
+            write!(out, "\r{}{}\x1b[K\r", prompt, text)?;
+            let column = prompt.chars().count() + cursor;
+            if column > 0 {
+                write!(out, "\x1b[{}C", column)?;
+            }
+            out.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_complete() {
+        let candidates = ["def", "double", "do", ":list", ":load", "sq"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            complete(&chars("1 + do"), 6, &candidates),
+            (4, vec!["do".to_owned(), "double".to_owned()])
+        );
+        assert_eq!(
+            complete(&chars(":l"), 2, &candidates),
+            (0, vec![":list".to_owned(), ":load".to_owned()])
+        );
+        assert_eq!(
+            complete(&chars(":ir s"), 5, &candidates),
+            (4, vec!["sq".to_owned()])
+        );
+        assert_eq!(complete(&chars("x"), 1, &candidates).1.len(), 0);
+        assert_eq!(common_prefix(&candidates[..2]), "d");
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut editor = Editor::new(None);
+        editor.add_history("def sq(x) x * x;");
+        editor.add_history("sq(2);");
+        editor.add_history("sq(2);");
+        assert_eq!(editor.history.len(), 2);
+        let candidates = || vec!["sqrt".to_owned(), "sq".to_owned()];
+        let mut edit = |keys: &[u8]| editor.edit("> ", &mut &keys[..], &candidates).unwrap();
+        // Left arrow, a character, Ctrl-E, backspace, Enter.
+        assert_eq!(edit(b"ab\x1b[Dx\x05\x7f\r"), Some("ax\n".to_owned()));
+        // Up twice goes back to the first line, down once to the second.
+        assert_eq!(edit(b"\x1b[A\x1b[A\x1b[B\r"), Some("sq(2);\n".to_owned()));
+        // Tab fills in the common prefix.
+        assert_eq!(edit(b"s\t\r"), Some("sq\n".to_owned()));
+        // Ctrl-U, and Ctrl-D at the end of a line does nothing.
+        assert_eq!(edit(b"abc\x15d\x04\r"), Some("d\n".to_owned()));
+        assert_eq!(edit(b"\x04"), None);
+        assert_eq!(edit(b""), None);
+    }
+}
@@ -9,8 +9,22 @@ pub struct Error {
 
 #[derive(Fail, Debug)]
 pub enum ErrorKind {
+    #[fail(display = "Parse error: {}", _0)]
+    Parse(String),
+    #[fail(display = "Type error: {}", _0)]
+    Type(String),
     #[fail(display = "Codegen error: {}", _0)]
     Codegen(String),
+    #[fail(display = "Runtime error: {}", _0)]
+    Runtime(String),
+    #[fail(display = "Limit exceeded: {}", _0)]
+    LimitExceeded(String),
+    #[fail(display = "Bytecode error: {}", _0)]
+    Bytecode(String),
+    #[fail(display = "Import error: {}", _0)]
+    Import(String),
+    #[fail(display = "I/O error: {}", _0)]
+    Io(String),
 }
 
 impl Fail for Error {
@@ -0,0 +1,354 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::ast::{Expr, Function};
+use super::error::{Error, ErrorKind};
+use super::toplevel::{self, Item};
+
+fn import_error(msg: String) -> Error {
+    Error::from(ErrorKind::Import(msg))
+}
+
+/// Renames the uses of the functions in `names` within `e` to `ns.name`, except where a local
+/// variable shadows them.
+fn qualify(e: &mut Expr, ns: &str, names: &HashSet<String>, bound: &mut Vec<String>) {
+    let rename = |name: &mut String, bound: &[String]| {
+        if names.contains(name) && !bound.contains(name) {
+            *name = format!("{}.{}", ns, name);
+        }
+    };
+
+    match e {
+        Expr::Number(_) | Expr::Str(_) | Expr::Int(_) | Expr::Break | Expr::Continue => (),
+        Expr::Variable(name) => rename(name, bound),
+        Expr::Call(callee, args) => {
+            rename(callee, bound);
+            for e in args {
+                qualify(e, ns, names, bound);
+            }
+        }
+        Expr::Binary(_, l, r) | Expr::Index(l, r) | Expr::While(l, r) => {
+            qualify(l, ns, names, bound);
+            qualify(r, ns, names, bound);
+        }
+        Expr::If(a, b, c) | Expr::SetIndex(a, b, c) => {
+            for e in [a, b, c].iter_mut() {
+                qualify(e, ns, names, bound);
+            }
+        }
+        Expr::Array(es) => {
+            for e in es {
+                qualify(e, ns, names, bound);
+            }
+        }
+        Expr::Apply(f, args) => {
+            qualify(f, ns, names, bound);
+            for e in args {
+                qualify(e, ns, names, bound);
+            }
+        }
+        Expr::For(var_name, start, end, step, body) => {
+            qualify(start, ns, names, bound);
+            bound.push(var_name.clone());
+            qualify(end, ns, names, bound);
+            if let Some(step) = step.as_mut() {
+                qualify(step, ns, names, bound);
+            }
+            qualify(body, ns, names, bound);
+            bound.pop();
+        }
+        Expr::Let(name, value, body) => {
+            qualify(value, ns, names, bound);
+            bound.push(name.clone());
+            qualify(body, ns, names, bound);
+            bound.pop();
+        }
+        Expr::Struct(_, fields, base) => {
+            for (_, e) in fields {
+                qualify(e, ns, names, bound);
+            }
+            if let Some(base) = base {
+                qualify(base, ns, names, bound);
+            }
+        }
+        Expr::Field(e, _) | Expr::Cast(_, e) | Expr::At(_, e) | Expr::Return(e) => {
+            qualify(e, ns, names, bound)
+        }
+        Expr::Lambda(proto, body) => {
+            let n = bound.len();
+            bound.extend(proto.1.iter().cloned());
+            qualify(body, ns, names, bound);
+            bound.truncate(n);
+        }
+    }
+}
+
+/// Loads the files named by `import` items. Each file is compiled once, however often it is
+/// imported, and its `def`s are renamed into a namespace named after it: `area` in
+/// `geometry.ks` becomes `geometry.area`. Externs and structs stay global.
+pub(crate) struct Imports {
+    // Where to look for files that are not next to the one importing them.
+    search_path: Vec<PathBuf>,
+    // Canonical paths of the files imported so far, and of those being imported, innermost
+    // last.
+    loaded: HashSet<PathBuf>,
+    loading: Vec<PathBuf>,
+}
+
+impl Imports {
+    pub(crate) fn new(search_path: Vec<PathBuf>) -> Self {
+        Imports {
+            search_path,
+            loaded: HashSet::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    /// Forgets the files imported so far, for a new session.
+    pub(crate) fn reset(&mut self) {
+        self.loaded.clear();
+    }
+
+    /// Finds `name` in `dir`, the directory of the importing file, or else along the search
+    /// path.
+    fn find(&self, name: &str, dir: &Path) -> Result<PathBuf, Error> {
+        std::iter::once(dir)
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|d| d.join(name))
+            .find(|p| p.is_file())
+            .ok_or_else(|| import_error(format!("cannot find {}", name)))?
+            .canonicalize()
+            .map_err(|e| import_error(format!("{}: {}", name, e)))
+    }
+
+    /// Returns the items of the file `name`, imported from a file in `dir`, preceded by those of
+    /// the files it imports in turn. Nothing is returned for a file imported before.
+    pub(crate) fn import(&mut self, name: &str, dir: &Path) -> Result<Vec<Item>, Error> {
+        let path = self.find(name, dir)?;
+        if let Some(i) = self.loading.iter().position(|p| *p == path) {
+            let cycle = self.loading[i..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(import_error(format!(
+                "import cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        if self.loaded.contains(&path) {
+            return Ok(Vec::new());
+        }
+
+        let ns = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if ns.is_empty() || !ns.chars().all(char::is_alphanumeric) {
+            return Err(import_error(format!(
+                "cannot import {}: {:?} is not a valid module name",
+                name, ns
+            )));
+        }
+
+        let src = fs::read_to_string(&path)
+            .map_err(|e| Error::from(ErrorKind::Io(format!("{}: {}", path.display(), e))))?;
+        let items = toplevel::parse_source(&src)?;
+
+        self.loading.push(path.clone());
+        let items = self.module(items, &ns, path.parent().unwrap_or(dir));
+        self.loading.pop();
+
+        let items = items?;
+        self.loaded.insert(path);
+        Ok(items)
+    }
+
+    /// Replaces the imports among `items`, those of a file in `dir`, with the items they
+    /// import.
+    pub(crate) fn expand(&mut self, items: Vec<Item>, dir: &Path) -> Result<Vec<Item>, Error> {
+        let mut expanded = Vec::new();
+        for item in items {
+            match item {
+                Item::Import(name) => expanded.extend(self.import(&name, dir)?),
+                item => expanded.push(item),
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Like `expand`, but also moves the functions of the file into namespace `ns`.
+    fn module(&mut self, items: Vec<Item>, ns: &str, dir: &Path) -> Result<Vec<Item>, Error> {
+        let mut names = HashSet::new();
+        let mut expanded = Vec::new();
+        for item in items {
+            match item {
+                Item::Import(name) => expanded.extend(self.import(&name, dir)?),
+                Item::Definition(Function(mut proto, mut body)) => {
+                    // Visible to its own body, for recursion.
+                    names.insert(proto.0.clone());
+                    qualify(&mut body, ns, &names, &mut proto.1.clone());
+                    proto.0 = format!("{}.{}", ns, proto.0);
+                    expanded.push(Item::Definition(Function(proto, body)));
+                }
+                Item::TopLevel(Function(proto, mut body)) => {
+                    qualify(&mut body, ns, &names, &mut Vec::new());
+                    expanded.push(Item::TopLevel(Function(proto, body)));
+                }
+                item @ Item::Extern(_) | item @ Item::Struct(_) => expanded.push(item),
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::c;
+    use super::super::interp::Interpreter;
+    use super::super::jit::Engine;
+    use super::super::toplevel::Backend;
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    /// Writes `files` to a fresh directory, which is returned.
+    fn files(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "kaleidoscope-import-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, src) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, src).unwrap();
+        }
+        dir
+    }
+
+    fn run(
+        backend: &mut dyn Backend,
+        imports: &mut Imports,
+        src: &str,
+        dir: &Path,
+    ) -> Result<Vec<f64>, Error> {
+        let mut results = Vec::new();
+        for item in imports.expand(toplevel::parse_source(src)?, dir)? {
+            match item {
+                Item::Definition(f) => backend.define(f)?,
+                Item::Extern(p) => backend.declare(p)?,
+                Item::TopLevel(f) => results.push(backend.eval(f)?),
+                Item::Struct(s) => backend.define_struct(s)?,
+                Item::Import(_) => unreachable!(),
+            }
+        }
+        Ok(results)
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let dir = files(&[
+            (
+                "geometry.ks",
+                "import \"square.ks\"
+                 def area(r) 3 * square.of(r);
+                 def twice(r) area(r) + area(r);
+                 def shadow(area) area + 1;",
+            ),
+            (
+                "lib/square.ks",
+                "def of(x) x * x; def fact(n) if n < 2 then 1 else n * fact(n - 1);",
+            ),
+        ]);
+        let mut imports = Imports::new(vec![dir.join("lib")]);
+        let src = "import \"geometry.ks\"
+                   import \"square.ks\"
+                   def area(x) 0;
+                   geometry.area(2); geometry.twice(1); geometry.shadow(4); area(5);
+                   square.fact(5);";
+        assert_eq!(
+            run(&mut Interpreter::new(), &mut imports, src, &dir).unwrap(),
+            vec![12.0, 6.0, 5.0, 0.0, 120.0]
+        );
+
+        // The functions of a module are only visible through its namespace.
+        let mut imports = Imports::new(vec![dir.join("lib")]);
+        let src = "import \"square.ks\" of(2);";
+        assert!(run(&mut Interpreter::new(), &mut imports, src, &dir).is_err());
+
+        // Whole-program backends see the qualified names too.
+        let mut imports = Imports::new(vec![dir.join("lib")]);
+        let src = "import \"geometry.ks\" geometry.area(2);";
+        let items = imports
+            .expand(toplevel::parse_source(src).unwrap(), &dir)
+            .unwrap();
+        let source = c::compile(&items).unwrap();
+        assert!(source.contains("double geometry_area(double r) {"));
+        assert_eq!(c::run_with_cc(&source, 1), vec![12.0]);
+    }
+
+    #[test]
+    fn test_jit() {
+        let dir = files(&[(
+            "shapes.ks",
+            "struct Circle { r, area: fn(f64) -> f64 }
+             def area(r) 3 * r * r;
+             def circle(r) Circle { r: r, area: area };",
+        )]);
+        let mut imports = Imports::new(Vec::new());
+        let mut e = Engine::new();
+        // A field of a local struct is called like a function of a module.
+        let src = "import \"shapes.ks\"
+                   def f(c: Circle) c.area(c.r);
+                   f(shapes.circle(2)); shapes.area(1);";
+        assert_eq!(
+            run(&mut e, &mut imports, src, &dir).unwrap(),
+            vec![12.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = files(&[
+            ("a.ks", "import \"b.ks\" def f() 1;"),
+            ("b.ks", "import \"a.ks\" def g() 2;"),
+            ("once.ks", "def h() 3;"),
+            ("bad-name.ks", "def h() 3;"),
+        ]);
+        let mut imports = Imports::new(Vec::new());
+        let e = run(
+            &mut Interpreter::new(),
+            &mut imports,
+            "import \"a.ks\"",
+            &dir,
+        )
+        .unwrap_err();
+        let a = dir.join("a.ks").canonicalize().unwrap();
+        let b = dir.join("b.ks").canonicalize().unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Import error: import cycle: {} -> {} -> {}",
+                a.display(),
+                b.display(),
+                a.display()
+            )
+        );
+
+        // A second import of the same file is a no-op, not a redefinition.
+        let mut imports = Imports::new(Vec::new());
+        let src = "import \"once.ks\" import \"once.ks\" once.h();";
+        assert_eq!(
+            run(&mut Interpreter::new(), &mut imports, src, &dir).unwrap(),
+            vec![3.0]
+        );
+
+        let e = imports.import("missing.ks", &dir).unwrap_err();
+        assert_eq!(e.to_string(), "Import error: cannot find missing.ks");
+        assert!(imports.import("bad-name.ks", &dir).is_err());
+    }
+}
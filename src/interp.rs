@@ -0,0 +1,319 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::ast::{Expr, Function, Prototype};
+use super::error::{Error, ErrorKind};
+use super::runtime::{self, Builtin};
+use super::toplevel::Backend;
+use super::typeck;
+
+/// Calls nest on the native stack, so runaway recursion is reported before it overflows the
+/// main thread's. The larger cases of `eval_expr` have functions of their own to keep its frame,
+/// and so the stack used per call, small.
+const MAX_DEPTH: usize = 1 << 10;
+
+/// Tree-walking evaluator over the AST, the `--backend=interp` alternative to the JIT.
+pub(crate) struct Interpreter {
+    functions: HashMap<String, Rc<Function>>,
+    externs: HashMap<String, Builtin>,
+    types: typeck::Checker,
+    depth: Cell<usize>,
+}
+
+fn runtime_error(msg: String) -> Error {
+    Error::from(ErrorKind::Runtime(msg))
+}
+
+impl Interpreter {
+    pub(crate) fn new() -> Self {
+        Interpreter {
+            functions: HashMap::new(),
+            externs: HashMap::new(),
+            types: typeck::Checker::new(),
+            depth: Cell::new(0),
+        }
+    }
+
+    fn call(&self, callee: &str, args: Vec<f64>) -> Result<f64, Error> {
+        if let Some(func) = self.functions.get(callee) {
+            let Function(proto, body) = func.as_ref();
+            let Prototype(_, params, ..) = proto.as_ref();
+            if params.len() != args.len() {
+                return Err(runtime_error("incorrect # arguments passed".to_owned()));
+            }
+
+            if self.depth.get() >= MAX_DEPTH {
+                return Err(runtime_error("call stack overflow".to_owned()));
+            }
+            let mut env = params.iter().cloned().zip(args).collect();
+            self.depth.set(self.depth.get() + 1);
+            let ret = self.eval_expr(&mut env, body);
+            self.depth.set(self.depth.get() - 1);
+            return ret;
+        }
+
+        match self.externs.get(callee) {
+            Some(builtin) if builtin.arity() == args.len() => Ok(builtin.call(&args)),
+            Some(_) => Err(runtime_error("incorrect # arguments passed".to_owned())),
+            None => Err(runtime_error(format!("unknown function: {}", callee))),
+        }
+    }
+
+    fn eval_expr(&self, env: &mut HashMap<String, f64>, e: &Expr) -> Result<f64, Error> {
+        match e {
+            Expr::Number(n) => Ok(*n),
+            // Values are untyped doubles here; the checker has already done its job.
+            Expr::Int(n) => Ok(*n as f64),
+            Expr::Cast(_, e) | Expr::At(_, e) => self.eval_expr(env, e),
+            Expr::Str(_)
+            | Expr::Array(_)
+            | Expr::Index(..)
+            | Expr::SetIndex(..)
+            | Expr::Struct(..)
+            | Expr::Field(..)
+            | Expr::Lambda(..)
+            | Expr::Apply(..)
+            | Expr::While(..)
+            | Expr::Break
+            | Expr::Continue
+            | Expr::Return(_) => Err(runtime_error(
+                "this expression is only supported by the JIT backend".to_owned(),
+            )),
+            Expr::Variable(name) => match env.get(name) {
+                Some(v) => Ok(*v),
+                None => Err(runtime_error(format!("unknown variable name: {}", name))),
+            },
+            Expr::Binary(op, lhs, rhs) => self.eval_binary(env, *op, lhs, rhs),
+            Expr::Call(callee, args) => self.eval_call(env, callee, args),
+            Expr::If(cond, then, els) => {
+                // `fcmp one` against 0.0: NaN is false, like in codegen.
+                let c = self.eval_expr(env, cond)?;
+                if c != 0.0 && !c.is_nan() {
+                    self.eval_expr(env, then)
+                } else {
+                    self.eval_expr(env, els)
+                }
+            }
+            Expr::Let(name, value, body) => {
+                let v = self.eval_expr(env, value)?;
+                let old_val = env.insert(name.clone(), v);
+                let ret = self.eval_expr(env, body);
+                match old_val {
+                    Some(v) => env.insert(name.clone(), v),
+                    None => env.remove(name),
+                };
+                ret
+            }
+            Expr::For(var_name, start, end, step, body) => {
+                self.eval_for(env, var_name, start, end, step.as_ref().as_ref(), body)
+            }
+        }
+    }
+
+    fn eval_binary(
+        &self,
+        env: &mut HashMap<String, f64>,
+        op: char,
+        lhs: &Expr,
+        rhs: &Expr,
+    ) -> Result<f64, Error> {
+        let l = self.eval_expr(env, lhs)?;
+        let r = self.eval_expr(env, rhs)?;
+        match op {
+            '+' => Ok(l + r),
+            '-' => Ok(l - r),
+            '*' => Ok(l * r),
+            // Matches the unordered `fcmp ult` emitted by codegen: NaN compares as true.
+            '<' => Ok(if l < r || l.is_nan() || r.is_nan() {
+                1.0
+            } else {
+                0.0
+            }),
+            _ => Err(runtime_error(format!("invalid binary operator: {}", op))),
+        }
+    }
+
+    fn eval_call(
+        &self,
+        env: &mut HashMap<String, f64>,
+        callee: &str,
+        args: &[Expr],
+    ) -> Result<f64, Error> {
+        let args = args
+            .iter()
+            .map(|e| self.eval_expr(env, e))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.call(callee, args)
+    }
+
+    fn eval_for(
+        &self,
+        env: &mut HashMap<String, f64>,
+        var_name: &str,
+        start: &Expr,
+        end: &Expr,
+        step: Option<&Expr>,
+        body: &Expr,
+    ) -> Result<f64, Error> {
+        let mut var = self.eval_expr(env, start)?;
+        let old_val = env.get(var_name).cloned();
+
+        // The body always runs once; the end condition is checked against the
+        // value of the induction variable before it is stepped.
+        let ret = loop {
+            env.insert(var_name.to_owned(), var);
+            if let Err(e) = self.eval_expr(env, body) {
+                break Err(e);
+            }
+            let step_val = match step {
+                Some(step) => match self.eval_expr(env, step) {
+                    Ok(v) => v,
+                    Err(e) => break Err(e),
+                },
+                None => 1.0,
+            };
+            let end_cond = match self.eval_expr(env, end) {
+                Ok(v) => v,
+                Err(e) => break Err(e),
+            };
+            var += step_val;
+            if end_cond == 0.0 || end_cond.is_nan() {
+                break Ok(0.0);
+            }
+        };
+
+        match old_val {
+            Some(v) => env.insert(var_name.to_owned(), v),
+            None => env.remove(var_name),
+        };
+
+        ret
+    }
+}
+
+impl Backend for Interpreter {
+    fn define(&mut self, f: Function) -> Result<(), Error> {
+        let f = self.types.function(&f)?;
+        let Function(proto, _) = &f;
+        let Prototype(name, ..) = proto.as_ref();
+        self.functions.insert(name.clone(), Rc::new(f));
+        Ok(())
+    }
+
+    fn declare(&mut self, p: Prototype) -> Result<(), Error> {
+        self.types.declare(&p)?;
+        let Prototype(name, args, ..) = p;
+        match runtime::lookup(&name) {
+            Some(builtin) if builtin.arity() == args.len() => {
+                self.externs.insert(name, builtin);
+                Ok(())
+            }
+            Some(_) => Err(runtime_error(format!(
+                "extern {} declared with the wrong # arguments",
+                name
+            ))),
+            None => Err(runtime_error(format!("unknown extern function: {}", name))),
+        }
+    }
+
+    fn eval(&mut self, f: Function) -> Result<f64, Error> {
+        let Function(_, body) = self.types.function(&f)?;
+        self.eval_expr(&mut HashMap::new(), &body)
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.types.listing()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::toplevel;
+    use super::*;
+
+    fn run(src: &str) -> Vec<f64> {
+        toplevel::run_source(&mut Interpreter::new(), src).unwrap()
+    }
+
+    #[test]
+    fn test_arith() {
+        assert_eq!(
+            run("1+2*3; (1+2)*3; 4-5; 1<2; 2<1;"),
+            vec![7.0, 9.0, -1.0, 1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_def_and_call() {
+        assert_eq!(
+            run("def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2); fib(10);"),
+            vec![55.0]
+        );
+    }
+
+    #[test]
+    fn test_extern() {
+        assert_eq!(run("extern sqrt(x); sqrt(16);"), vec![4.0]);
+        assert!(toplevel::run_source(&mut Interpreter::new(), "extern nosuch(x);").is_err());
+    }
+
+    #[test]
+    fn test_for() {
+        assert_eq!(run("for i = 0, i < 3, 2 in 1;"), vec![0.0]);
+
+        // The body runs even if the end condition is false from the start.
+        assert!(
+            toplevel::run_source(&mut Interpreter::new(), "for i = 0, 0 in nosuch(i);").is_err()
+        );
+
+        // The loop variable shadows a parameter only inside the loop.
+        assert_eq!(
+            run("def f(i) (for i = 0, i < 3 in i) + i; f(7);"),
+            vec![7.0]
+        );
+    }
+
+    #[test]
+    fn test_let() {
+        // Bindings nest and shadow a parameter only inside the body.
+        assert_eq!(
+            run("def f(x) (let x = x * 2 in let y = x + 1 in x * y) + x; f(3);"),
+            vec![45.0]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut i = Interpreter::new();
+        assert!(toplevel::run_source(&mut i, "x;").is_err());
+        assert!(toplevel::run_source(&mut i, "nosuch(1);").is_err());
+        assert!(toplevel::run_source(&mut i, "def f(x) x; f(1, 2);").is_err());
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // Test threads get a smaller stack than the main thread the limit is sized for.
+        let overflow = || {
+            let mut i = Interpreter::new();
+            let err =
+                toplevel::run_source(&mut i, "def deep(n) 1 + deep(n+1); deep(0);").unwrap_err();
+            assert_eq!(err.to_string(), "Runtime error: call stack overflow");
+            // The interpreter is still usable, and recursion below the limit still works.
+            assert_eq!(
+                toplevel::run_source(
+                    &mut i,
+                    "4; def down(n) if n < 1 then 0 else 1 + down(n-1); down(1000);"
+                )
+                .unwrap(),
+                vec![4.0, 1000.0]
+            );
+        };
+        std::thread::Builder::new()
+            .stack_size(1 << 23)
+            .spawn(overflow)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
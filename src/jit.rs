@@ -0,0 +1,751 @@
+use llvm_sys::core::*;
+use llvm_sys::disassembler::*;
+use llvm_sys::execution_engine::*;
+use llvm_sys::prelude::*;
+use llvm_sys::target::*;
+use llvm_sys::target_machine::LLVMGetDefaultTargetTriple;
+use llvm_sys::transforms::{instcombine, scalar};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem::{transmute, MaybeUninit};
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::Once;
+use std::time::Duration;
+
+use super::ast::{Function, Prototype, StructDef};
+use super::codegen;
+use super::error::{Error, ErrorKind};
+use super::perf::{self, Profile};
+use super::runtime;
+use super::toplevel::Backend;
+use super::typeck;
+
+static INIT: Once = Once::new();
+
+type Catch = extern "C" fn(extern "C" fn() -> f64, *mut runtime::JmpBuf, *mut f64) -> libc::c_int;
+
+/// A named function as it was last compiled.
+struct Compiled {
+    // Type checked, so that it can be compiled again when something it calls is redefined.
+    def: Function,
+    module: LLVMModuleRef,
+    value: LLVMValueRef,
+}
+
+/// The LLVM MCJIT backend. Every item is compiled into its own module, which is handed over to
+/// the execution engine once codegen is done; calls across modules are resolved by name.
+pub(crate) struct Engine {
+    c: codegen::Context,
+    types: typeck::Checker,
+    the_fpm: LLVMPassManagerRef,
+    the_execution_engine: LLVMExecutionEngineRef,
+    // Top-level expressions run through this, so that the runtime can trap out of them.
+    catch: Catch,
+    // The functions compiled so far, for profiles and perf maps.
+    symbols: perf::Symbols,
+    // Boxed, since compiled code refers to it by address.
+    limits: Box<runtime::Limits>,
+    // The named functions defined so far, for redefinitions, `:ir` and `:asm`.
+    functions: HashMap<String, Compiled>,
+    pub(crate) dump_ir: bool,
+}
+
+impl Engine {
+    pub(crate) fn new() -> Self {
+        INIT.call_once(|| unsafe {
+            // robust code should check that these calls complete successfully
+            // each of these calls is necessary to setup an execution engine which compiles to native
+            // code
+            LLVMLinkInMCJIT();
+            LLVM_InitializeNativeTarget();
+            LLVM_InitializeNativeAsmPrinter();
+            LLVM_InitializeNativeAsmParser();
+            LLVM_InitializeNativeDisassembler();
+        });
+
+        let mut the_execution_engine = MaybeUninit::<LLVMExecutionEngineRef>::uninit();
+
+        unsafe {
+            let mut c = codegen::Context::new();
+
+            LLVMCreateExecutionEngineForModule(
+                the_execution_engine.as_mut_ptr(),
+                c.the_module,
+                null_mut::<*mut ::libc::c_char>(),
+            );
+
+            let the_execution_engine = the_execution_engine.assume_init();
+
+            // for debug
+            //            let target_machine =
+            //                execution_engine::LLVMGetExecutionEngineTargetMachine(the_execution_engine);
+            // let triplet = target_machine::LLVMGetTargetMachineTriple(target_machine);
+            // println!("triple: {}", CString::from_raw(triplet).to_str().unwrap());
+
+            codegen::codegen_catch(&mut c);
+            let addr =
+                LLVMGetFunctionAddress(the_execution_engine, b"__catch\0".as_ptr() as *const _);
+
+            let mut engine = Engine {
+                c,
+                types: typeck::Checker::for_jit(),
+                the_fpm: null_mut(),
+                the_execution_engine,
+                catch: transmute::<usize, Catch>(addr as usize),
+                symbols: perf::Symbols::new(),
+                limits: Box::new(runtime::Limits::new(None, None)),
+                functions: HashMap::new(),
+                dump_ir: false,
+            };
+            engine.init_module_and_pass_manager();
+            // Arrays are only available here, and come with their length without an extern.
+            let len = Prototype("len".to_owned(), vec!["a".to_owned()], vec![None], None);
+            engine.declare(len).unwrap();
+            engine
+        }
+    }
+
+    unsafe fn init_module_and_pass_manager(&mut self) {
+        let the_module = self.c.new_module();
+
+        let data_layout = LLVMGetExecutionEngineTargetData(self.the_execution_engine);
+
+        LLVMSetModuleDataLayout(the_module, data_layout);
+
+        if !self.the_fpm.is_null() {
+            LLVMFinalizeFunctionPassManager(self.the_fpm);
+            LLVMDisposePassManager(self.the_fpm);
+        }
+
+        self.the_fpm = LLVMCreateFunctionPassManagerForModule(the_module);
+
+        instcombine::LLVMAddInstructionCombiningPass(self.the_fpm);
+
+        scalar::LLVMAddReassociatePass(self.the_fpm);
+
+        scalar::LLVMAddGVNPass(self.the_fpm);
+
+        scalar::LLVMAddCFGSimplificationPass(self.the_fpm);
+
+        LLVMInitializeFunctionPassManager(self.the_fpm);
+    }
+
+    /// Emits DWARF debug information for what is compiled from now on, which comes from the
+    /// source file `path`. MCJIT registers the code of each module with gdb's JIT interface, so
+    /// that breakpoints on Kaleidoscope functions work.
+    pub(crate) fn enable_debug_info(&mut self, path: &Path) {
+        self.c.enable_debug_info(path);
+        self.types.positions = true;
+    }
+
+    /// Limits each evaluation to `fuel` steps, that is function calls and loop iterations, and
+    /// to `timeout`. Only code compiled from now on is checked.
+    pub(crate) fn set_limits(&mut self, fuel: Option<u64>, timeout: Option<Duration>) {
+        *self.limits = runtime::Limits::new(fuel, timeout);
+        self.c.enable_limits(&mut *self.limits);
+    }
+
+    /// Writes `/tmp/perf-<pid>.map` as functions are compiled, so that `perf` can name them.
+    pub(crate) fn enable_perf_map(&mut self) -> Result<(), Error> {
+        self.symbols.update()?;
+        self.symbols.enable_map()
+    }
+
+    /// Compiles and runs a top-level expression, sampling it if `profile` is set.
+    unsafe fn run(&mut self, f: Function, profile: bool) -> Result<(f64, Option<Profile>), Error> {
+        let f = self.types.function(&f)?;
+        let the_function = codegen::codegen_func(&mut self.c, self.the_fpm, &f)?;
+        if self.dump_ir {
+            LLVMDumpValue(the_function);
+        }
+
+        let mut len = 0;
+        let name = CStr::from_ptr(LLVMGetValueName2(the_function, &mut len)).to_owned();
+
+        self.finish_module();
+
+        let addr = LLVMGetFunctionAddress(self.the_execution_engine, name.as_ptr());
+        if addr == 0 {
+            return Err(Error::from(ErrorKind::Codegen(format!(
+                "failed to compile {}",
+                name.to_string_lossy()
+            ))));
+        }
+        self.symbols.update()?;
+        let fp = transmute::<usize, extern "C" fn() -> f64>(addr as usize);
+        let catch = self.catch;
+        self.limits.start();
+        let call = || {
+            let mut result = 0.0;
+            runtime::catch(|buf| catch(fp, buf, &mut result)).map(|()| result)
+        };
+        let (result, profile) = if profile {
+            let (result, samples, cpu_time) = perf::sample(call)?;
+            (result, Some(self.symbols.profile(&samples, cpu_time)))
+        } else {
+            (call(), None)
+        };
+        let result = result.map_err(|trap| match trap {
+            runtime::Trap::StackOverflow(pc) => {
+                Error::from(ErrorKind::Runtime(match self.symbols.lookup(pc) {
+                    Some(s) => format!("stack overflow in `{}`", s.name),
+                    None => "stack overflow".to_owned(),
+                }))
+            }
+            trap => Error::from(trap),
+        })?;
+
+        Ok((result, profile))
+    }
+
+    fn function(&self, name: &str) -> Result<LLVMValueRef, Error> {
+        self.functions
+            .get(name)
+            .map(|f| f.value)
+            .ok_or_else(|| Error::from(ErrorKind::Codegen(format!("no function named {}", name))))
+    }
+
+    /// Hands the current module over to the execution engine and starts a new one.
+    unsafe fn finish_module(&mut self) {
+        self.c.finish_debug_info();
+        LLVMAddModule(self.the_execution_engine, self.c.the_module);
+        self.init_module_and_pass_manager();
+    }
+
+    /// Replaces the code of the function `f` redefines. Loaded code is bound to the addresses
+    /// of what it calls, so whatever calls the old code, directly or not, is compiled again,
+    /// and so is everything sharing a module with that, since modules leave the engine whole.
+    /// It all goes into one module, in which calls among the new code resolve to each other.
+    unsafe fn redefine(&mut self, f: Function) -> Result<(), Error> {
+        let mut stale = vec![f.0 .0.clone()];
+        let mut i = 0;
+        while i < stale.len() {
+            let name = CString::new(stale[i].as_str()).unwrap();
+            let module = self.functions[&stale[i]].module;
+            for (other, compiled) in &self.functions {
+                if !stale.contains(other)
+                    && (compiled.module == module
+                        || !LLVMGetNamedFunction(compiled.module, name.as_ptr()).is_null())
+                {
+                    stale.push(other.clone());
+                }
+            }
+            i += 1;
+        }
+
+        let value = codegen::codegen_func(&mut self.c, self.the_fpm, &f)?;
+        if self.dump_ir {
+            LLVMDumpValue(value);
+        }
+        let mut compiled = vec![(f, value)];
+        for name in &stale[1..] {
+            let def = self.functions[name].def.clone();
+            match codegen::codegen_func(&mut self.c, self.the_fpm, &def) {
+                Ok(v) => compiled.push((def, v)),
+                Err(e) => {
+                    // Leaves the old code in place.
+                    let module = self.c.the_module;
+                    self.init_module_and_pass_manager();
+                    LLVMDisposeModule(module);
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut old_modules = stale
+            .iter()
+            .map(|name| self.functions[name].module)
+            .collect::<Vec<_>>();
+        old_modules.sort();
+        old_modules.dedup();
+        for old in old_modules {
+            let mut removed = null_mut();
+            let mut error = null_mut();
+            if LLVMRemoveModule(self.the_execution_engine, old, &mut removed, &mut error) == 0 {
+                LLVMDisposeModule(removed);
+            } else {
+                LLVMDisposeMessage(error);
+            }
+        }
+
+        let module = self.c.the_module;
+        self.finish_module();
+        // Loading the new code now makes its symbols the ones later code is bound to.
+        LLVMGetPointerToGlobal(self.the_execution_engine, value);
+        for (def, value) in compiled {
+            let name = def.0 .0.clone();
+            self.functions.insert(name, Compiled { def, module, value });
+        }
+        Ok(())
+    }
+}
+
+impl Backend for Engine {
+    fn define(&mut self, f: Function) -> Result<(), Error> {
+        let f = self.types.function(&f)?;
+        if self.functions.contains_key(&f.0 .0) {
+            return unsafe { self.redefine(f) };
+        }
+        unsafe {
+            let value = codegen::codegen_func(&mut self.c, self.the_fpm, &f)?;
+            if self.dump_ir {
+                LLVMDumpValue(value);
+            }
+            let module = self.c.the_module;
+            self.finish_module();
+            self.functions.insert(
+                f.0 .0.clone(),
+                Compiled {
+                    def: f,
+                    module,
+                    value,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn define_struct(&mut self, s: StructDef) -> Result<(), Error> {
+        self.types.define_struct(&s)?;
+        unsafe { codegen::codegen_struct(&mut self.c, &s) };
+        Ok(())
+    }
+
+    fn declare(&mut self, p: Prototype) -> Result<(), Error> {
+        let p = self.types.declare(&p)?;
+        unsafe {
+            let v = codegen::codegen_proto(&mut self.c, &p)?;
+            if let Some(builtin) = runtime::lookup(&p.0) {
+                LLVMAddGlobalMapping(self.the_execution_engine, v, builtin.addr());
+            }
+            if self.dump_ir {
+                LLVMDumpValue(v);
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&mut self, f: Function) -> Result<f64, Error> {
+        unsafe { self.run(f, false) }.map(|(v, _)| v)
+    }
+
+    fn profile(&mut self, f: Function) -> Result<(f64, Profile), Error> {
+        unsafe { self.run(f, true) }.map(|(v, profile)| (v, profile.unwrap()))
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.types.listing()
+    }
+
+    fn ir(&self, name: &str) -> Result<String, Error> {
+        let f = self.function(name)?;
+        unsafe {
+            let ir = LLVMPrintValueToString(f);
+            let s = CStr::from_ptr(ir).to_string_lossy().into_owned();
+            LLVMDisposeMessage(ir);
+            Ok(s)
+        }
+    }
+
+    fn asm(&mut self, name: &str) -> Result<String, Error> {
+        self.function(name)?;
+        let name = CString::new(name).unwrap();
+        unsafe {
+            // Compiled on first use, which may be now.
+            let addr = LLVMGetFunctionAddress(self.the_execution_engine, name.as_ptr()) as usize;
+            self.symbols.update()?;
+            match self.symbols.lookup(addr) {
+                Some(s) if s.start == addr => Ok(disassemble(s.start, s.size)),
+                _ => Err(Error::from(ErrorKind::Codegen(format!(
+                    "no machine code for {}",
+                    name.to_string_lossy()
+                )))),
+            }
+        }
+    }
+}
+
+/// One line per instruction of the machine code at `start`.
+unsafe fn disassemble(start: usize, size: usize) -> String {
+    let triple = LLVMGetDefaultTargetTriple();
+    let dc = LLVMCreateDisasm(triple, null_mut(), 0, None, None);
+    LLVMDisposeMessage(triple);
+    let mut out = String::new();
+    let mut offset = 0;
+    let mut text = [0 as libc::c_char; 256];
+    while offset < size {
+        let n = LLVMDisasmInstruction(
+            dc,
+            (start + offset) as *mut u8,
+            (size - offset) as u64,
+            (start + offset) as u64,
+            text.as_mut_ptr(),
+            text.len(),
+        );
+        if n == 0 {
+            // Not an instruction; padding, most likely.
+            break;
+        }
+        let instruction = CStr::from_ptr(text.as_ptr()).to_string_lossy();
+        out.push_str(&format!("{:x}:{}\n", start + offset, instruction));
+        offset += n;
+    }
+    LLVMDisasmDispose(dc);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::toplevel;
+    use super::*;
+
+    fn run(src: &str) -> Vec<f64> {
+        let mut e = Engine::new();
+        toplevel::run_source(&mut e, src).unwrap()
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(
+            run("def f(x) x*2; f(3); extern sin(x); sin(0); f(4)+1;"),
+            vec![6.0, 0.0, 9.0]
+        );
+    }
+
+    #[test]
+    fn test_redefinition() {
+        assert_eq!(run("def f(x) 1; def f(x) 2; f(0);"), vec![2.0]);
+        // Callers compiled, and run, against the old code call the new code.
+        assert_eq!(
+            run("def f(x) 1; def g(x) f(x) + 10; g(0); def f(x) 2; g(0);"),
+            vec![11.0, 12.0]
+        );
+        assert_eq!(
+            run("def k(x) 1;
+                 extern odd(n);
+                 def even(n) if n < 1 then k(n) else odd(n - 1);
+                 def odd(n) if n < 1 then 0 else even(n - 1);
+                 even(4);
+                 def k(x) 5;
+                 even(4); odd(3);
+                 def k(x) 7;
+                 odd(3);
+                 def even(n) 100;
+                 odd(3);"),
+            vec![1.0, 5.0, 5.0, 7.0, 100.0]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        assert_eq!(
+            run("def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2); fib(10);"),
+            vec![55.0]
+        );
+        assert_eq!(
+            run("def f(i) (for i = 0, i < 3 in i) + i; f(7);"),
+            vec![7.0]
+        );
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // A million frames would overflow the stack.
+        assert_eq!(
+            run("def loop(n acc) if n < 1 then acc else loop(n-1, acc+n); loop(1000000, 0);
+                 def count(n: i64 acc: i64) -> i64
+                   if n < 1 then acc else if n < 2 then count(n - 1, acc + 1) else count(n - 2, acc + 2);
+                 count(1000001, 0);"),
+            vec![500000500000.0, 1000001.0]
+        );
+        // Calls that are not in tail position still recurse.
+        assert_eq!(
+            run("def sum(n) if n < 1 then 0 else n + sum(n - 1); sum(100);"),
+            vec![5050.0]
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(
+            run(
+                "def countdown(n) (while 0 < n[0] do n[0] = n[0] - 1) + n[0];
+                 def sum(a n) (for i = 0, i < 10 in
+                   if n < i then break else a[0] = a[0] + i) + a[0];
+                 def skip(a) (while a[0] < 10 do
+                   if (a[0] = a[0] + 1) < 5 then continue else a[1] = a[1] + 1) + a[1];
+                 countdown([3]); sum([0], 3); skip([0, 0]);"
+            ),
+            vec![0.0, 6.0, 6.0]
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        assert_eq!(
+            run("extern len(a);
+                 def find(a x) (for i = 0, i < len(a) - 1 in if a[i] < x then 0 else return i) - 1;
+                 def clamp(x: i64) -> i64 if x < 0 then return 0 else if 10 < x then 10 else x;
+                 def loop(n acc) if n < 1 then return acc else return loop(n - 1, acc + 1);
+                 def early(x) (if x < 0 then return 1 else x) * 2;
+                 def apply(f x) f(x);
+                 find([1, 5, 9], 4); find([1, 5, 9], 10); clamp(0 - 3) + clamp(4) + clamp(20);
+                 loop(1000000, 0); early(0 - 1); early(3);
+                 apply(fn (x) (if x < 0 then return 0 - x else x) + 100, 0 - 5);
+                 (for i = 0, i < 10 in if 2 < i then return i else 0) + 100;"),
+            vec![1.0, -1.0, 14.0, 1000000.0, 1.0, 6.0, 5.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn test_let() {
+        assert_eq!(
+            run("def f(x) (let x = x * 2 in let y = x + 1 in x * y) + x;
+                 def loop(n acc) if n < 1 then acc else let m = n - 1 in loop(m, acc + 1);
+                 f(3); loop(1000000, 0); let n: i64 = 3 in n * 2;
+                 let k = 10 in (fn (x) x + k)(5); let add = fn (x) x + 1 in add(2);"),
+            vec![45.0, 1000000.0, 6.0, 15.0, 3.0]
+        );
+        // Let variables get the type their uses call for.
+        assert_eq!(
+            run("def s(n: i64) -> i64 let m = 2 in n * m; s(5);"),
+            vec![10.0]
+        );
+    }
+
+    #[test]
+    fn test_types() {
+        assert_eq!(
+            run("def fact(n: i64) -> i64 if n < 2 then 1 else n * fact(n - 1);
+                 def odd(n: i64) -> bool if n < 1 then 0 < 0 else if odd(n - 1) then 1 < 0 else 0 < 1;
+                 fact(20); odd(7); odd(8) + 1;"),
+            vec![2432902008176640000.0, 1.0, 1.0]
+        );
+        // i64 arithmetic wraps instead of losing precision.
+        assert_eq!(
+            run("def fact(n: i64) -> i64 if n < 2 then 1 else n * fact(n - 1); fact(21);"),
+            vec![-4249290049419214848.0]
+        );
+        assert_eq!(
+            run("def id(n: i64) -> i64 n; def f(n: i64) for i: i64 = 0, i < n, 2 in id(i); f(5);"),
+            vec![0.0]
+        );
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut e = Engine::new();
+        e.enable_debug_info(Path::new("/tmp/test.ks"));
+        let src = "struct P { x, y };
+                   def norm2(p: P) p.x * p.x + p.y * p.y;
+                   def loop(n acc) if n < 1 then acc else loop(n - 1, acc + n);
+                   def adder(k) fn (x) x + k;
+                   def first(a) (for i = 0, i < 10 in if 2 < i then return i else 0) + a;
+                   def twice(f x) f(f(x));
+                   def sq(x) x * x;
+                   norm2(P { x: 3, y: 4 }); loop(100, 0); adder(1)(2); first(0); twice(adder(3), 1);
+                   twice(sq, 2);
+                   let v = 2 in (while v < 10 do break) + v;";
+        assert_eq!(
+            toplevel::run_source(&mut e, src).unwrap(),
+            vec![25.0, 5050.0, 3.0, 3.0, 7.0, 16.0, 2.0]
+        );
+        assert!(unsafe { !perf::__jit_debug_descriptor.first_entry.is_null() });
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut e = Engine::new();
+        toplevel::run_source(
+            &mut e,
+            "def spin(n acc) if n < 1 then acc else spin(n - 1, acc + n * n);
+             def busy(k) (for i = 0, i < k in spin(100000, 0)) + 1;",
+        )
+        .unwrap();
+        let f = match toplevel::parse_source("busy(1000);").unwrap().pop() {
+            Some(toplevel::Item::TopLevel(f)) => f,
+            _ => unreachable!(),
+        };
+        let (v, profile) = e.profile(f).unwrap();
+        assert_eq!(v, 1.0);
+        assert!(profile.samples > 0);
+        assert!(profile.cpu_time.as_millis() > 0);
+        let total: usize = profile.functions.iter().map(|(_, n)| n).sum();
+        assert_eq!(total, profile.samples);
+        assert_eq!(profile.functions[0].0, "spin");
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut e = Engine::new();
+        e.set_limits(Some(100_000), None);
+        let src = "def spin(x) spin(x);
+                   def count(n) (for i = 0, i < n in 0) + n;
+                   def apply(f x) f(x);";
+        toplevel::run_source(&mut e, src).unwrap();
+        let limited =
+            |e: &mut Engine, src: &str| toplevel::run_source(e, src).unwrap_err().to_string();
+        assert_eq!(
+            limited(&mut e, "spin(1);"),
+            "Limit exceeded: out of fuel after 100000 steps"
+        );
+        assert_eq!(
+            limited(&mut e, "while 1 do 0;"),
+            "Limit exceeded: out of fuel after 100000 steps"
+        );
+        assert_eq!(
+            limited(&mut e, "apply(fn (x) count(x), 200000);"),
+            "Limit exceeded: out of fuel after 100000 steps"
+        );
+        // Each evaluation gets the whole budget, and the functions stay usable.
+        assert_eq!(
+            toplevel::run_source(&mut e, "count(90000); count(90000);").unwrap(),
+            vec![90000.0, 90000.0]
+        );
+
+        e.set_limits(None, Some(Duration::from_millis(50)));
+        toplevel::run_source(&mut e, "def forever() while 1 do 0;").unwrap();
+        assert_eq!(
+            limited(&mut e, "forever();"),
+            "Limit exceeded: timed out after 50 ms"
+        );
+        assert_eq!(
+            toplevel::run_source(&mut e, "count(1000000);").unwrap(),
+            vec![1000000.0]
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut e = Engine::new();
+        let src = "def deep(n) 1 + deep(n + 1);
+                   def twice(x) x * 2;";
+        toplevel::run_source(&mut e, src).unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                toplevel::run_source(&mut e, "deep(0);")
+                    .unwrap_err()
+                    .to_string(),
+                "Runtime error: stack overflow in `deep`"
+            );
+        }
+        // Through a closure as well; the session carries on either way.
+        let src = "(fn (n) deep(n))(0);";
+        assert!(toplevel::run_source(&mut e, src).is_err());
+        assert_eq!(
+            toplevel::run_source(&mut e, "def more(x) twice(x) + 1; more(2);").unwrap(),
+            vec![5.0]
+        );
+    }
+
+    #[test]
+    fn test_perf_map() {
+        let mut e = Engine::new();
+        e.enable_perf_map().unwrap();
+        toplevel::run_source(&mut e, "def perfmapped(x) x + 1; perfmapped(1);").unwrap();
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        let map = std::fs::read_to_string(&path).unwrap();
+        let line = map
+            .lines()
+            .find(|l| l.ends_with(" perfmapped"))
+            .expect("perfmapped is not in the map");
+        let fields = line.split(' ').collect::<Vec<_>>();
+        let start = usize::from_str_radix(fields[0], 16).unwrap();
+        assert!(usize::from_str_radix(fields[1], 16).unwrap() > 0);
+        let addr = unsafe {
+            LLVMGetFunctionAddress(e.the_execution_engine, b"perfmapped\0".as_ptr() as *const _)
+        };
+        assert_eq!(start, addr as usize);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            run(r#"extern strlen(s); extern concat(a b); extern printstr(s);
+                   def twice(s: str) concat(s, s);
+                   def pick(x) if x < 0 then "negative" else "positive";
+                   strlen(twice("ab\n")); strlen(pick(0 - 1)) + strlen(""); printstr("");"#),
+            vec![6.0, 8.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_structs() {
+        assert_eq!(
+            run(
+                "struct Point { x, y }; struct Line { from: Point, to: Point };
+                 def norm2(p) p.x * p.x + p.y * p.y;
+                 def moved(p: Point) Point { x: p.x + 1, ..p };
+                 def length2(l) norm2(Point { x: l.to.x - l.from.x, y: l.to.y - l.from.y });
+                 norm2(moved(Point { y: 2, x: 1 }));
+                 length2(Line { from: Point { x: 1, y: 1 }, to: Point { x: 4, y: 5 } });"
+            ),
+            vec![8.0, 25.0]
+        );
+    }
+
+    #[test]
+    fn test_closures() {
+        assert_eq!(
+            run("extern array(n); extern len(a);
+                 def map(a f) (for i = 0, i < len(a) - 1 in a[i] = f(a[i])) + 0;
+                 def fold(a f acc) (for i = 0, i < len(a) - 1 in acc[0] = f(acc[0], a[i])) + acc[0];
+                 def twice(f x) f(f(x));
+                 def adder(n) fn (x) x + n;
+                 def scale(a k) (map(a, fn (x) x * k)) + a[0] + a[1];
+                 def compose(f g) fn (x) f(g(x));
+                 def double(x) x * 2;
+                 scale([1, 2], 10); twice(adder(3), 1); compose(double, adder(1))(4);
+                 (fn (x y) x < y)(1, 2); twice(double, 3);
+                 fold([1, 2, 3], fn (x y) x + y, [0]);"),
+            vec![30.0, 7.0, 10.0, 1.0, 12.0, 6.0]
+        );
+    }
+
+    #[test]
+    fn test_arrays() {
+        assert_eq!(
+            run("extern array(n); extern len(a);
+                 def fill(a) for i = 0, i < len(a) - 1 in a[i] = i * i;
+                 def sum(a s) (for i = 0, i < len(a) - 1 in s[0] = s[0] + a[i]) + s[0];
+                 def squares(n) let a = array(n) in fill(a) + sum(a, [0]);
+                 def total(n: i64) sum([0, 1, 2, 3], [0]);
+                 squares(4); total(0); len(array(7)); [1, 2, 3][2];"),
+            vec![14.0, 6.0, 7.0, 3.0]
+        );
+        // `len` needs no extern.
+        assert_eq!(run("len([1, 2, 3]);"), vec![3.0]);
+
+        // Out of bounds accesses fail the top-level expression, and the engine stays usable.
+        let mut e = Engine::new();
+        let src = "def get(a i) a[i]; get([1, 2], 2); get([1, 2], 0 - 1); get([1, 2], 1);
+                   get([1, 2], 0.5); get([1, 2], 1.7); get([1, 2], 1);";
+        let mut items = toplevel::parse_source(src).unwrap().into_iter();
+        let mut eval = |e: &mut Engine| match items.next().unwrap() {
+            toplevel::Item::Definition(f) => e.define(f).map(|_| 0.0),
+            toplevel::Item::TopLevel(f) => e.eval(f),
+            toplevel::Item::Extern(p) => e.declare(p).map(|_| 0.0),
+            toplevel::Item::Struct(s) => e.define_struct(s).map(|_| 0.0),
+            toplevel::Item::Import(_) => unreachable!(),
+        };
+        eval(&mut e).unwrap();
+        let err = eval(&mut e).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: index 2 out of bounds for length 2"
+        );
+        assert!(eval(&mut e).is_err());
+        assert_eq!(eval(&mut e).unwrap(), 2.0);
+
+        // Fractional indexes trap rather than truncate.
+        let err = eval(&mut e).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: index 0.5 is not a whole number"
+        );
+        assert!(eval(&mut e).is_err());
+        assert_eq!(eval(&mut e).unwrap(), 2.0);
+    }
+}
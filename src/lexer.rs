@@ -7,7 +7,7 @@ use combine::parser::repeat::{chainl1, take_until};
 use combine::parser::{EasyParser, Parser};
 use combine::stream::{Stream, StreamErrorFor};
 use combine::{
-    any, between, choice, eof, many, many1, none_of, not_followed_by, parser, satisfy_map,
+    any, attempt, between, choice, eof, many, many1, none_of, not_followed_by, parser, satisfy_map,
     skip_many, skip_many1, token,
 };
 
@@ -16,16 +16,53 @@ where
     Input: Stream<Token = char>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
 {
-    many1(choice((digit(), token('.'))))
-        .and_then(|ns: String| {
-            ns.parse::<f64>().map_err(|e| {
-                <Input::Error as combine::error::ParseError<char, Input::Range, Input::Position>>
+    // A leading `.` must be followed by a digit, which keeps the `.` of field accesses out of
+    // numbers.
+    or(
+        (digit(), many(choice((digit(), token('.'))))),
+        attempt((token('.'), many1(digit()))),
+    )
+    .map(|(d, ds): (char, String)| format!("{}{}", d, ds))
+    .and_then(|ns: String| {
+        ns.parse::<f64>().map_err(|e| {
+            <Input::Error as combine::error::ParseError<char, Input::Range, Input::Position>>
                                                          ::StreamError::other(e)
-            })
         })
-        .map(|n| Token::Number(n))
+    })
+    .map(|n| Token::Number(n))
 }
 
+/// A double-quoted string literal. It may not span lines; `\n`, `\t`, `\0`, `\\` and `\"` are the
+/// escapes.
+fn string<Input>() -> impl Parser<Input, Output = Token>
+where
+    Input: Stream<Token = char>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+{
+    let escape = token('\\').with(any()).and_then(|c| match c {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        '0' => Ok('\0'),
+        '\\' | '"' => Ok(c),
+        _ => Err(StreamErrorFor::<Input>::message_format(format_args!(
+            "unknown escape: \\{}",
+            c
+        ))),
+    });
+    between(
+        token('"'),
+        token('"'),
+        many(or(escape, none_of("\"\\\n".chars()))),
+    )
+    .map(Token::Str)
+}
+
+/// The words `ident` turns into keywords rather than identifiers.
+pub(crate) const KEYWORDS: &[&str] = &[
+    "def", "extern", "import", "struct", "fn", "if", "then", "else", "for", "in", "let", "while",
+    "do", "break", "continue", "return",
+];
+
 fn ident<Input>() -> impl Parser<Input, Output = Token>
 where
     Input: Stream<Token = char>,
@@ -34,11 +71,20 @@ where
     many1(alpha_num()).map(|s: String| match s.as_ref() {
         "def" => Token::Def,
         "extern" => Token::Extern,
+        "import" => Token::Import,
+        "struct" => Token::Struct,
+        "fn" => Token::Fn,
         "if" => Token::If,
         "then" => Token::Then,
         "else" => Token::Else,
         "for" => Token::For,
         "in" => Token::In,
+        "let" => Token::Let,
+        "while" => Token::While,
+        "do" => Token::Do,
+        "break" => Token::Break,
+        "continue" => Token::Continue,
+        "return" => Token::Return,
         id => Token::Ident(id.to_string()),
     })
 }
@@ -62,6 +108,7 @@ where
     skip_many(or(space(), newline())).with(choice((
         number().map(|x| Some(x)),
         ident().map(|x| Some(x)),
+        string().map(Some),
         comment().with(lex()),
         any().map(|c| Some(Token::Kwd(c))),
         eof().map(|_| None),
@@ -84,6 +131,8 @@ mod test {
     #[test]
     fn test_number() {
         assert_eq!(number().easy_parse("1.0").map(|x| x.0), Ok(Number(1.0)));
+        assert_eq!(number().easy_parse(".5").map(|x| x.0), Ok(Number(0.5)));
+        assert!(number().easy_parse(".x").is_err());
     }
 
     #[test]
@@ -101,6 +150,16 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_string() {
+        assert_eq!(
+            string().easy_parse(r#""a \"b\"\n""#).map(|x| x.0),
+            Ok(Str("a \"b\"\n".to_owned()))
+        );
+        assert!(string().easy_parse(r#""\q""#).is_err());
+        assert!(string().easy_parse("\"a\nb\"").is_err());
+    }
+
     #[test]
     fn test_comment() {
         assert_eq!(comment().easy_parse("#hoge").map(|x| x.0), Ok(()));
@@ -163,5 +222,15 @@ mod test {
                 Kwd(';')
             ]
         );
+        assert_eq!(
+            lex_tokens("p.x*.5"),
+            vec![
+                Ident("p".to_owned()),
+                Kwd('.'),
+                Ident("x".to_owned()),
+                Kwd('*'),
+                Number(0.5)
+            ]
+        );
     }
 }
@@ -1,63 +1,233 @@
 mod ast;
+mod bytecode;
+mod c;
 mod codegen;
+#[cfg(test)]
+mod difftest;
+mod editor;
 mod error;
+mod import;
+mod interp;
+mod jit;
 mod lexer;
 mod parser;
+mod perf;
+mod prelude;
+mod runtime;
 mod token;
 mod toplevel;
+mod typeck;
+mod vm;
+mod wasm;
+
+use error::{Error, ErrorKind};
+use import::Imports;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+use toplevel::Output;
+
+fn usage() -> ! {
+    eprintln!("usage: kaleidoscope [--backend=jit|interp|vm] [--no-prelude] [-g] [--perf-map]");
+    eprintln!("                    [--fuel=steps] [--timeout=ms] [-v | --json] [-I dir]...");
+    eprintln!("                    [file.ks | file.ksbc]");
+    eprintln!(
+        "       kaleidoscope --emit=bytecode|wat|wasm|c|h|rs [-g] [-I dir]... [-o out] file.ks"
+    );
+    exit(2);
+}
 
-use llvm_sys::core::*;
-use llvm_sys::execution_engine::*;
-use llvm_sys::target::*;
-use llvm_sys::transforms::{instcombine, scalar};
-use std::ffi::CString;
-use std::mem::{transmute, MaybeUninit};
-use std::ptr::null_mut;
-
-fn main() {
-    let mut the_execution_engine = MaybeUninit::<LLVMExecutionEngineRef>::uninit();
-
-    unsafe {
-        // robust code should check that these calls complete successfully
-        // each of these calls is necessary to setup an execution engine which compiles to native
-        // code
-        LLVMLinkInMCJIT();
-        LLVM_InitializeNativeTarget();
-        LLVM_InitializeNativeAsmPrinter();
-        LLVM_InitializeNativeAsmParser();
-
-        let mut c = codegen::Context::new();
-
-        LLVMCreateExecutionEngineForModule(
-            the_execution_engine.as_mut_ptr(),
-            c.the_module,
-            null_mut::<*mut ::libc::c_char>(),
-        );
-
-        let the_execution_engine = transmute::<_, LLVMExecutionEngineRef>(the_execution_engine);
-
-        // for debug
-        //            let target_machine =
-        //                execution_engine::LLVMGetExecutionEngineTargetMachine(the_execution_engine);
-        // let triplet = target_machine::LLVMGetTargetMachineTriple(target_machine);
-        // println!("triple: {}", CString::from_raw(triplet).to_str().unwrap());
-
-        let data_layout = LLVMGetExecutionEngineTargetData(the_execution_engine);
+fn io_error(path: &Path, e: std::io::Error) -> Error {
+    Error::from(ErrorKind::Io(format!("{}: {}", path.display(), e)))
+}
 
-        LLVMSetModuleDataLayout(c.the_module, data_layout);
+fn read_source(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|e| io_error(path, e))
+}
 
-        let the_fpm = LLVMCreateFunctionPassManagerForModule(c.the_module);
+/// The directory of `path`, which the files it imports are looked up in first.
+fn dir_of(path: &Path) -> &Path {
+    path.parent().unwrap_or_else(|| Path::new("."))
+}
 
-        instcombine::LLVMAddInstructionCombiningPass(the_fpm);
+fn emit_file(
+    kind: &str,
+    debug: bool,
+    imports: &mut Imports,
+    input: &Path,
+    output: &Path,
+) -> Result<(), Error> {
+    let items = toplevel::parse_source(&read_source(input)?)?;
+    let items = imports.expand(items, dir_of(input))?;
+    let mut w = BufWriter::new(File::create(output).map_err(|e| io_error(output, e))?);
+    match kind {
+        "bytecode" => bytecode::compile(&items)?.write(&mut w),
+        "wat" => w.write_all(wasm::compile(&items)?.to_wat().as_bytes()),
+        "wasm" => w.write_all(&wasm::compile(&items)?.to_wasm()),
+        "c" if debug => {
+            let file = input.canonicalize().map_err(|e| io_error(input, e))?;
+            w.write_all(c::compile_debug(&items, &file.to_string_lossy())?.as_bytes())
+        }
+        "c" => w.write_all(c::compile(&items)?.as_bytes()),
+        "h" => {
+            let name = output.file_stem().unwrap_or_default().to_string_lossy();
+            w.write_all(c::header(&items, &name)?.as_bytes())
+        }
+        "rs" => w.write_all(c::rust_externs(&items)?.as_bytes()),
+        _ => unreachable!(),
+    }
+    .map_err(|e| io_error(output, e))
+}
 
-        scalar::LLVMAddReassociatePass(the_fpm);
+fn run_bytecode(input: &Path, output: Output) -> Result<(), Error> {
+    let mut r = File::open(input).map_err(|e| io_error(input, e))?;
+    let vm = vm::Vm::from_module(bytecode::Module::read(&mut r)?)?;
+    for v in vm.run_entries()? {
+        toplevel::show_value(output, v);
+    }
+    Ok(())
+}
 
-        scalar::LLVMAddGVNPass(the_fpm);
+/// What `session` sets up besides the backend itself.
+struct SessionOptions<'a> {
+    load_prelude: bool,
+    // The file the JIT describes the code compiled after the prelude as coming from, with -g.
+    debug: Option<&'a Path>,
+    perf_map: bool,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    // Dumps the IR of everything compiled after the prelude.
+    dump_ir: bool,
+}
 
-        scalar::LLVMAddCFGSimplificationPass(the_fpm);
+/// The backend called `name`, with the prelude compiled into it unless told otherwise. Only the
+/// JIT takes the other options.
+fn session(name: &str, options: &SessionOptions) -> Result<Box<dyn toplevel::Backend>, Error> {
+    let mut backend: Box<dyn toplevel::Backend> = match name {
+        "jit" => {
+            let mut e = jit::Engine::new();
+            if options.fuel.is_some() || options.timeout.is_some() {
+                e.set_limits(options.fuel, options.timeout);
+            }
+            if options.load_prelude {
+                prelude::load(&mut e)?;
+            }
+            if let Some(file) = options.debug {
+                e.enable_debug_info(file);
+            }
+            if options.perf_map {
+                e.enable_perf_map()?;
+            }
+            e.dump_ir = options.dump_ir;
+            return Ok(Box::new(e));
+        }
+        _ if options.debug.is_some()
+            || options.perf_map
+            || options.fuel.is_some()
+            || options.timeout.is_some() =>
+        {
+            usage()
+        }
+        "interp" => Box::new(interp::Interpreter::new()),
+        "vm" => Box::new(vm::Vm::new()),
+        _ => usage(),
+    };
+    if options.load_prelude {
+        prelude::load(backend.as_mut())?;
+    }
+    Ok(backend)
+}
 
-        LLVMInitializeFunctionPassManager(the_fpm);
+fn main() {
+    let mut backend = "jit".to_owned();
+    let mut emit = None;
+    let mut output = None;
+    let mut input = None;
+    let mut search_path = Vec::new();
+    let mut load_prelude = true;
+    let mut debug = false;
+    let mut perf_map = false;
+    let mut fuel = None;
+    let mut timeout = None;
+    let mut mode = Output::Quiet;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(b) = arg.strip_prefix("--backend=") {
+            backend = b.to_owned();
+        } else if let Some(e) = arg.strip_prefix("--emit=") {
+            emit = Some(e.to_owned());
+        } else if arg == "--no-prelude" {
+            load_prelude = false;
+        } else if arg == "-g" {
+            debug = true;
+        } else if arg == "--perf-map" {
+            perf_map = true;
+        } else if let Some(n) = arg.strip_prefix("--fuel=") {
+            fuel = Some(n.parse().unwrap_or_else(|_| usage()));
+        } else if let Some(ms) = arg.strip_prefix("--timeout=") {
+            timeout = Some(Duration::from_millis(
+                ms.parse().unwrap_or_else(|_| usage()),
+            ));
+        } else if arg == "-v" && mode != Output::Json {
+            mode = Output::Verbose;
+        } else if arg == "--json" && mode != Output::Verbose {
+            mode = Output::Json;
+        } else if arg == "-I" {
+            search_path.push(PathBuf::from(args.next().unwrap_or_else(|| usage())));
+        } else if arg == "-o" {
+            output = Some(args.next().unwrap_or_else(|| usage()));
+        } else if !arg.starts_with('-') && input.is_none() {
+            input = Some(arg);
+        } else {
+            usage();
+        }
+    }
 
-        toplevel::main_loop(&mut c, the_fpm, the_execution_engine);
+    let mut imports = Imports::new(search_path);
+    let file = input.clone().unwrap_or_else(|| "<stdin>".to_owned());
+    let options = SessionOptions {
+        load_prelude,
+        debug: if debug { Some(Path::new(&file)) } else { None },
+        perf_map,
+        fuel,
+        timeout,
+        dump_ir: mode == Output::Verbose,
+    };
+    let ret = match (emit.as_deref(), input) {
+        (Some(kind @ "bytecode"), Some(input))
+        | (Some(kind @ "wat"), Some(input))
+        | (Some(kind @ "wasm"), Some(input))
+        | (Some(kind @ "c"), Some(input))
+        | (Some(kind @ "h"), Some(input))
+        | (Some(kind @ "rs"), Some(input)) => {
+            let input = Path::new(&input);
+            let ext = if kind == "bytecode" { "ksbc" } else { kind };
+            let output =
+                output.unwrap_or_else(|| input.with_extension(ext).to_string_lossy().into_owned());
+            emit_file(kind, debug, &mut imports, input, Path::new(&output))
+        }
+        (Some(_), _) => usage(),
+        (None, Some(input)) if input.ends_with(".ksbc") => {
+            if backend != "vm" {
+                usage();
+            }
+            run_bytecode(Path::new(&input), mode)
+        }
+        (None, Some(input)) => session(&backend, &options).and_then(|mut backend| {
+            let input = Path::new(&input);
+            read_source(input).map(|src| {
+                toplevel::handle_source(backend.as_mut(), &mut imports, mode, &src, dir_of(input))
+            })
+        }),
+        (None, None) => toplevel::main_loop(&|| session(&backend, &options), &mut imports, mode),
+    };
+
+    if let Err(e) = ret {
+        eprintln!("error: {}", e);
+        exit(1);
     }
 }
@@ -1,17 +1,45 @@
 use super::ast::Expr;
-use super::ast::{Function, Prototype};
+use super::ast::{intern, intern_fn, Function, Pos, Prototype, StructDef, Type};
 use super::token::Token;
-use combine::error::ParseError;
+use combine::error::{ParseError, StreamError};
 use combine::parser::choice::or;
 use combine::parser::repeat::chainl1;
 pub(crate) use combine::parser::Parser;
-use combine::stream::Stream;
-use combine::{any, attempt, between, choice, many, optional, parser, satisfy_map, sep_by, token};
+use combine::stream::{PointerOffset, Stream, StreamErrorFor};
+use combine::{
+    any, attempt, between, choice, many, optional, parser, position, satisfy_map, sep_by, token,
+};
+
+/// Source positions of the token stream, if it carries them. Plain token slices don't, and
+/// parse to an AST without `Expr::At` nodes.
+pub(crate) trait Locate {
+    fn locate(&self) -> Option<Pos>;
+}
+
+impl Locate for Pos {
+    fn locate(&self) -> Option<Pos> {
+        Some(*self)
+    }
+}
+
+impl Locate for PointerOffset<[Token]> {
+    fn locate(&self) -> Option<Pos> {
+        None
+    }
+}
+
+fn at<P: Locate>(pos: P, e: Expr) -> Expr {
+    match pos.locate() {
+        Some(pos) => Expr::At(pos, Box::new(e)),
+        None => e,
+    }
+}
 
 fn ident<Input>() -> impl Parser<Input, Output = String>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     satisfy_map(|t| match t {
         Token::Ident(id) => Some(id),
@@ -23,6 +51,7 @@ fn args<Input>() -> impl Parser<Input, Output = Vec<Expr>>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     sep_by(expr(), token(Token::Kwd(',')))
 }
@@ -31,68 +60,254 @@ fn call<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
+    // `geometry.area(r)` calls a function of an imported file. Whether `geometry` is a module or
+    // a local variable is up to the type checker.
+    let qualified = optional(attempt((token(Token::Kwd('.')), ident()).map(|(_, id)| id)));
     (
+        position(),
         ident(),
+        qualified,
         between(token(Token::Kwd('(')), token(Token::Kwd(')')), args()),
     )
-        .map(|(id, aa)| Expr::Call(id, aa))
+        .map(|(pos, id, field, aa)| {
+            let id = match field {
+                Some(field) => format!("{}.{}", id, field),
+                None => id,
+            };
+            at(pos, Expr::Call(id, aa))
+        })
 }
 
 fn primary_<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     use super::token::Token::*;
-    let number = satisfy_map(|c| match c {
-        Number(n) => Some(Expr::Number(n)),
-        _ => None,
-    });
+    let number = (
+        position(),
+        satisfy_map(|c| match c {
+            Number(n) => Some(Expr::Number(n)),
+            _ => None,
+        }),
+    )
+        .map(|(pos, e)| at(pos, e));
+
+    let string = (
+        position(),
+        satisfy_map(|c| match c {
+            Str(s) => Some(Expr::Str(s)),
+            _ => None,
+        }),
+    )
+        .map(|(pos, e)| at(pos, e));
 
     let paren = between(token(Kwd('(')), token(Kwd(')')), expr());
 
-    let variable = ident().map(|id| Expr::Variable(id));
+    let array = (
+        position(),
+        between(
+            token(Kwd('[')),
+            token(Kwd(']')),
+            sep_by(expr(), token(Kwd(','))),
+        ),
+    )
+        .map(|(pos, es)| at(pos, Expr::Array(es)));
+
+    // `Point { x: 1, ..p }`; the struct the other fields come from has to be last.
+    let init = or(
+        (ident(), token(Kwd(':')), expr()).map(|(f, _, e)| (Some(f), e)),
+        (token(Kwd('.')), token(Kwd('.')), expr()).map(|(_, _, e)| (None, e)),
+    );
+    let construct = (
+        position(),
+        ident(),
+        between(
+            token(Kwd('{')),
+            token(Kwd('}')),
+            sep_by::<Vec<_>, _, _, _>(init, token(Kwd(','))),
+        ),
+    )
+        .and_then(|(pos, name, inits)| {
+            let mut fields = Vec::new();
+            let mut base = None;
+            for (field, e) in inits {
+                match (field, &base) {
+                    (_, Some(_)) => {
+                        return Err(StreamErrorFor::<Input>::unexpected_static_message(","))
+                    }
+                    (Some(f), None) => fields.push((f, e)),
+                    (None, None) => base = Some(Box::new(e)),
+                }
+            }
+            Ok(at(pos, Expr::Struct(name, fields, base)))
+        });
+
+    let variable = (position(), ident()).map(|(pos, id)| at(pos, Expr::Variable(id)));
+
+    let lambda =
+        (position(), token(Fn), params(), expr()).map(|(pos, _, (names, types, ret), e)| {
+            at(
+                pos,
+                Expr::Lambda(
+                    Box::new(Prototype(String::new(), names, types, ret)),
+                    Box::new(e),
+                ),
+            )
+        });
+
+    let parse_while = (position(), token(While), expr(), token(Do), expr())
+        .map(|(pos, _, c, _, e)| at(pos, Expr::While(Box::new(c), Box::new(e))));
+    let jump = (
+        position(),
+        satisfy_map(|t| match t {
+            Break => Some(Expr::Break),
+            Continue => Some(Expr::Continue),
+            _ => None,
+        }),
+    )
+        .map(|(pos, e)| at(pos, e));
+
+    // `let x: i64 = ...` annotates the value, like a loop variable.
+    let parse_let = (
+        position(),
+        token(Let),
+        ident(),
+        optional((token(Kwd(':')), ty()).map(|(_, t)| t)),
+        token(Kwd('=')),
+        expr(),
+        token(In),
+        expr(),
+    )
+        .map(|(pos, _, id, t, _, value, _, body)| {
+            let value = match t {
+                Some(t) => Expr::Cast(t, Box::new(value)),
+                None => value,
+            };
+            at(pos, Expr::Let(id, Box::new(value), Box::new(body)))
+        });
+
+    let parse_return =
+        (position(), token(Return), expr()).map(|(pos, _, e)| at(pos, Expr::Return(Box::new(e))));
 
     choice((
         attempt(number),
+        attempt(string),
         attempt(paren),
         attempt(call()),
+        attempt(construct),
         attempt(variable),
         attempt(parse_if()),
         attempt(parse_for()),
+        attempt(parse_while),
+        attempt(parse_let),
+        attempt(jump),
+        attempt(parse_return),
+        attempt(array),
+        attempt(lambda),
     ))
 }
 
+enum Suffix {
+    Index(Expr),
+    Field(String),
+    Apply(Vec<Expr>),
+}
+
+fn postfix_<Input>() -> impl Parser<Input, Output = Expr>
+where
+    Input: Stream<Token = Token>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
+{
+    use super::token::Token::*;
+
+    let suffix = (
+        position(),
+        choice((
+            between(token(Kwd('[')), token(Kwd(']')), expr()).map(Suffix::Index),
+            (token(Kwd('.')), ident()).map(|(_, f)| Suffix::Field(f)),
+            between(token(Kwd('(')), token(Kwd(')')), args()).map(Suffix::Apply),
+        )),
+    );
+    // `a[i] = v` stores into an element; nothing else can be assigned to.
+    let assign = optional((position(), token(Kwd('=')), expr()));
+
+    (primary(), many::<Vec<_>, _, _>(suffix), assign).and_then(|(e, suffixes, assign)| {
+        let e = suffixes
+            .into_iter()
+            .fold(e, |e, (pos, suffix)| match suffix {
+                Suffix::Index(i) => at(pos, Expr::Index(Box::new(e), Box::new(i))),
+                Suffix::Field(f) => at(pos, Expr::Field(Box::new(e), f)),
+                Suffix::Apply(args) => at(pos, Expr::Apply(Box::new(e), args)),
+            });
+        let (pos, _, v) = match assign {
+            Some(assign) => assign,
+            None => return Ok(e),
+        };
+        let target = match e {
+            Expr::At(_, e) => *e,
+            e => e,
+        };
+        match target {
+            Expr::Index(a, i) => Ok(at(pos, Expr::SetIndex(a, i, Box::new(v)))),
+            _ => Err(StreamErrorFor::<Input>::unexpected_static_message("=")),
+        }
+    })
+}
+
 parser! {
     fn primary[Input]()(Input) -> Expr
-        where [Input: Stream<Token=Token>]
+        where [Input: Stream<Token=Token>, Input::Position: Locate]
     {
         primary_()
     }
 }
 
+parser! {
+    fn postfix[Input]()(Input) -> Expr
+        where [Input: Stream<Token=Token>, Input::Position: Locate]
+    {
+        postfix_()
+    }
+}
+
 fn parse_if<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     use super::token::Token::*;
 
-    (token(If), expr(), token(Then), expr(), token(Else), expr())
-        .map(|(_, c, _, t, _, e)| Expr::If(Box::new(c), Box::new(t), Box::new(e)))
+    (
+        position(),
+        token(If),
+        expr(),
+        token(Then),
+        expr(),
+        token(Else),
+        expr(),
+    )
+        .map(|(pos, _, c, _, t, _, e)| at(pos, Expr::If(Box::new(c), Box::new(t), Box::new(e))))
 }
 
 fn parse_for<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     use super::token::Token::*;
 
     (
+        position(),
         token(For),
         ident(),
+        optional((token(Kwd(':')), ty()).map(|(_, t)| t)),
         token(Kwd('=')),
         expr(),
         token(Kwd(',')),
@@ -101,13 +316,21 @@ where
         token(In),
         expr(),
     )
-        .map(|(_, id, _, start, _, end, step, _, body)| {
-            Expr::For(
-                id,
-                Box::new(start),
-                Box::new(end),
-                Box::new(step),
-                Box::new(body),
+        .map(|(pos, _, id, t, _, start, _, end, step, _, body)| {
+            // `for i: i64 = ...` annotates the start value.
+            let start = match t {
+                Some(t) => Expr::Cast(t, Box::new(start)),
+                None => start,
+            };
+            at(
+                pos,
+                Expr::For(
+                    id,
+                    Box::new(start),
+                    Box::new(end),
+                    Box::new(step),
+                    Box::new(body),
+                ),
             )
         })
 }
@@ -116,8 +339,10 @@ fn expr<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
-    let lt = token(Token::Kwd('<')).map(|_| |l, r| Expr::Binary('<', Box::new(l), Box::new(r)));
+    let lt = (position(), token(Token::Kwd('<')))
+        .map(|(pos, _)| move |l, r| at(pos, Expr::Binary('<', Box::new(l), Box::new(r))));
     or(chainl1(add(), lt), add())
 }
 
@@ -125,14 +350,19 @@ fn add<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     or(
         chainl1(
             mul(),
-            or(token(Token::Kwd('+')), token(Token::Kwd('-'))).map(|t| match t {
-                Token::Kwd(c) => move |l, r| Expr::Binary(c, Box::new(l), Box::new(r)),
-                _ => unreachable!(),
-            }),
+            (
+                position(),
+                or(token(Token::Kwd('+')), token(Token::Kwd('-'))),
+            )
+                .map(|(pos, t)| match t {
+                    Token::Kwd(c) => move |l, r| at(pos, Expr::Binary(c, Box::new(l), Box::new(r))),
+                    _ => unreachable!(),
+                }),
         ),
         mul(),
     )
@@ -142,32 +372,97 @@ fn mul<Input>() -> impl Parser<Input, Output = Expr>
 where
     Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     or(
         chainl1(
-            primary(),
-            token(Token::Kwd('*')).map(|_| |l, r| Expr::Binary('*', Box::new(l), Box::new(r))),
+            postfix(),
+            (position(), token(Token::Kwd('*')))
+                .map(|(pos, _)| move |l, r| at(pos, Expr::Binary('*', Box::new(l), Box::new(r)))),
         ),
-        primary(),
+        postfix(),
     )
 }
 
-fn prototype<Input>() -> impl Parser<Input, Output = Prototype>
+fn ty_<Input>() -> impl Parser<Input, Output = Type>
 where
-    Input: Stream<Token = Token> + Clone,
+    Input: Stream<Token = Token>,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     use super::token::Token::*;
 
-    let args = many(ident());
+    let array = (
+        token(Kwd('[')),
+        token(Ident("f64".to_owned())),
+        token(Kwd(']')),
+    )
+        .map(|_| Type::Array);
+    // `fn(f64, i64) -> bool`; like in a prototype, the return type defaults to f64.
+    let function = (
+        token(Fn),
+        between(
+            token(Kwd('(')),
+            token(Kwd(')')),
+            sep_by(ty(), token(Kwd(','))),
+        ),
+        optional((token(Kwd('-')), token(Kwd('>')), ty()).map(|(_, _, t)| t)),
+    )
+        .map(|(_, params, ret)| Type::Fn(intern_fn(params, ret.unwrap_or(Type::F64))));
+    let scalar = satisfy_map(|t| match t {
+        Token::Ident(ref id) if id == "f64" => Some(Type::F64),
+        Token::Ident(ref id) if id == "i64" => Some(Type::I64),
+        Token::Ident(ref id) if id == "bool" => Some(Type::Bool),
+        Token::Ident(ref id) if id == "str" => Some(Type::Str),
+        // Whether the struct exists is up to the type checker.
+        Token::Ident(ref id) => Some(Type::Struct(intern(id))),
+        _ => None,
+    });
+    choice((array, function, scalar))
+}
+
+parser! {
+    fn ty[Input]()(Input) -> Type
+        where [Input: Stream<Token=Token>, Input::Position: Locate]
+    {
+        ty_()
+    }
+}
+
+/// Parameters, which can be annotated, and an optional return type, as in
+/// `(n: i64 x) -> bool`.
+fn params<Input>() -> impl Parser<Input, Output = (Vec<String>, Vec<Option<Type>>, Option<Type>)>
+where
+    Input: Stream<Token = Token>,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
+{
+    use super::token::Token::*;
 
-    (ident(), between(token(Kwd('(')), token(Kwd(')')), args)).map(|(id, aa)| Prototype(id, aa))
+    let arg = (ident(), optional((token(Kwd(':')), ty()).map(|(_, t)| t)));
+    let args = many::<Vec<_>, _, _>(arg);
+    let ret = optional((token(Kwd('-')), token(Kwd('>')), ty()).map(|(_, _, t)| t));
+
+    (between(token(Kwd('(')), token(Kwd(')')), args), ret).map(|(aa, ret)| {
+        let (names, types) = aa.into_iter().unzip();
+        (names, types, ret)
+    })
+}
+
+fn prototype<Input>() -> impl Parser<Input, Output = Prototype>
+where
+    Input: Stream<Token = Token> + Clone,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
+{
+    (ident(), params()).map(|(id, (names, types, ret))| Prototype(id, names, types, ret))
 }
 
 pub(crate) fn definition<Input>() -> impl Parser<Input, Output = Function>
 where
     Input: Stream<Token = Token> + Clone,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     (token(Token::Def), prototype(), expr()).map(|(_, p, e)| Function(Box::new(p), Box::new(e)))
 }
@@ -176,18 +471,55 @@ pub(crate) fn toplevel<Input>() -> impl Parser<Input, Output = Function>
 where
     Input: Stream<Token = Token> + Clone,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
-    expr().map(|e| Function(Box::new(Prototype("".to_owned(), vec![])), Box::new(e)))
+    expr().map(|e| Function(Box::new(Prototype::new("".to_owned(), vec![])), Box::new(e)))
+}
+
+pub(crate) fn struct_parser<Input>() -> impl Parser<Input, Output = StructDef>
+where
+    Input: Stream<Token = Token> + Clone,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
+{
+    use super::token::Token::*;
+
+    let field = (ident(), optional((token(Kwd(':')), ty()).map(|(_, t)| t)))
+        .map(|(f, t)| (f, t.unwrap_or(Type::F64)));
+    (
+        token(Struct),
+        ident(),
+        between(
+            token(Kwd('{')),
+            token(Kwd('}')),
+            sep_by(field, token(Kwd(','))),
+        ),
+    )
+        .map(|(_, name, fields)| StructDef(name, fields))
 }
 
 pub(crate) fn extern_parser<Input>() -> impl Parser<Input, Output = Prototype>
 where
     Input: Stream<Token = Token> + Clone,
     Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
 {
     (token(Token::Extern), prototype()).map(|(_, p)| p)
 }
 
+pub(crate) fn import_parser<Input>() -> impl Parser<Input, Output = String>
+where
+    Input: Stream<Token = Token> + Clone,
+    Input::Error: ParseError<Input::Token, Input::Range, Input::Position>,
+    Input::Position: Locate,
+{
+    let path = satisfy_map(|t| match t {
+        Token::Str(s) => Some(s),
+        _ => None,
+    });
+    (token(Token::Import), path).map(|(_, p)| p)
+}
+
 #[cfg(test)]
 mod test {
 
@@ -294,7 +626,20 @@ mod test {
             let tokens = vec![Ident("f".to_owned()), Kwd('('), Kwd(')')];
             assert_eq!(
                 prototype().parse(tokens.as_slice()).map(|x| x.0),
-                Ok(Prototype("f".to_owned(), vec![]))
+                Ok(Prototype::new("f".to_owned(), vec![]))
+            );
+        }
+
+        {
+            let tokens = lex_tokens("f(n: i64 x) -> bool");
+            assert_eq!(
+                prototype().parse(tokens.as_slice()).map(|x| x.0),
+                Ok(Prototype(
+                    "f".to_owned(),
+                    vec!["n".to_owned(), "x".to_owned()],
+                    vec![Some(Type::I64), None],
+                    Some(Type::Bool)
+                ))
             );
         }
     }
@@ -329,6 +674,154 @@ mod test {
                 vec![Expr::Variable("y".to_owned()), Expr::Number(4.0)]
             ))
         );
+
+        let tokens = lex_tokens("geometry.area(r)");
+        assert_eq!(
+            call().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(Expr::Call(
+                "geometry.area".to_owned(),
+                vec![Expr::Variable("r".to_owned())]
+            ))
+        );
+
+        // A field that is not called is left to the postfix parser.
+        let tokens = lex_tokens("p.x");
+        assert!(call().parse(tokens.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_array() {
+        let var = |s: &str| Box::new(Expr::Variable(s.to_owned()));
+        let tokens = lex_tokens("a[i + 1] = [1, b[0][1]]");
+        assert_eq!(
+            expr().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(Expr::SetIndex(
+                var("a"),
+                Box::new(Expr::Binary('+', var("i"), Box::new(Expr::Number(1.0)))),
+                Box::new(Expr::Array(vec![
+                    Expr::Number(1.0),
+                    Expr::Index(
+                        Box::new(Expr::Index(var("b"), Box::new(Expr::Number(0.0)))),
+                        Box::new(Expr::Number(1.0))
+                    )
+                ]))
+            ))
+        );
+
+        let tokens = lex_tokens("x = 1");
+        assert!(expr().parse(tokens.as_slice()).is_err());
+
+        let tokens = lex_tokens("f(a: [f64])");
+        assert_eq!(
+            prototype().parse(tokens.as_slice()).map(|x| x.0 .2),
+            Ok(vec![Some(Type::Array)])
+        );
+    }
+
+    #[test]
+    fn test_struct() {
+        let var = |s: &str| Box::new(Expr::Variable(s.to_owned()));
+        let tokens = lex_tokens("Point { x: 1, ..p }.y");
+        assert_eq!(
+            expr().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(Expr::Field(
+                Box::new(Expr::Struct(
+                    "Point".to_owned(),
+                    vec![("x".to_owned(), Expr::Number(1.0))],
+                    Some(var("p"))
+                )),
+                "y".to_owned()
+            ))
+        );
+
+        // The base comes last.
+        let tokens = lex_tokens("Point { ..p, x: 1 }");
+        assert!(expr()
+            .skip(combine::eof())
+            .parse(tokens.as_slice())
+            .is_err());
+
+        let tokens = lex_tokens("struct Line { from: Point, to: Point, width }");
+        assert_eq!(
+            struct_parser().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(StructDef(
+                "Line".to_owned(),
+                vec![
+                    ("from".to_owned(), Type::Struct("Point")),
+                    ("to".to_owned(), Type::Struct("Point")),
+                    ("width".to_owned(), Type::F64),
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_lambda() {
+        let var = |s: &str| Box::new(Expr::Variable(s.to_owned()));
+        let tokens = lex_tokens("(fn (x n: i64) -> f64 x * n)(f(1)(2), 3)");
+        assert_eq!(
+            expr().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(Expr::Apply(
+                Box::new(Expr::Lambda(
+                    Box::new(Prototype(
+                        "".to_owned(),
+                        vec!["x".to_owned(), "n".to_owned()],
+                        vec![None, Some(Type::I64)],
+                        Some(Type::F64)
+                    )),
+                    Box::new(Expr::Binary('*', var("x"), var("n")))
+                )),
+                vec![
+                    Expr::Apply(
+                        Box::new(Expr::Call("f".to_owned(), vec![Expr::Number(1.0)])),
+                        vec![Expr::Number(2.0)]
+                    ),
+                    Expr::Number(3.0)
+                ]
+            ))
+        );
+
+        let tokens = lex_tokens("f(g: fn(f64, i64) -> bool h: fn())");
+        assert_eq!(
+            prototype().parse(tokens.as_slice()).map(|x| x.0 .2),
+            Ok(vec![
+                Some(Type::Fn(intern_fn(vec![Type::F64, Type::I64], Type::Bool))),
+                Some(Type::Fn(intern_fn(vec![], Type::F64)))
+            ])
+        );
+    }
+
+    #[test]
+    fn test_while() {
+        let tokens = lex_tokens("while x do if y then break else continue");
+        assert_eq!(
+            expr().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(Expr::While(
+                Box::new(Expr::Variable("x".to_owned())),
+                Box::new(Expr::If(
+                    Box::new(Expr::Variable("y".to_owned())),
+                    Box::new(Expr::Break),
+                    Box::new(Expr::Continue)
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_let() {
+        let tokens = lex_tokens("let x: i64 = 1 in let y = x in y");
+        assert_eq!(
+            expr().parse(tokens.as_slice()).map(|x| x.0),
+            Ok(Expr::Let(
+                "x".to_owned(),
+                Box::new(Expr::Cast(Type::I64, Box::new(Expr::Number(1.0)))),
+                Box::new(Expr::Let(
+                    "y".to_owned(),
+                    Box::new(Expr::Variable("x".to_owned())),
+                    Box::new(Expr::Variable("y".to_owned()))
+                ))
+            ))
+        );
     }
 
     #[test]
@@ -0,0 +1,276 @@
+use llvm_sys::core::LLVMCreateMemoryBufferWithMemoryRange;
+use llvm_sys::object::*;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::error::{Error, ErrorKind};
+use super::runtime;
+
+/// An entry of gdb's JIT interface, which MCJIT registers every object file it loads with,
+/// relocated to where its sections were loaded.
+#[repr(C)]
+pub(crate) struct JitCodeEntry {
+    next_entry: *const JitCodeEntry,
+    prev_entry: *const JitCodeEntry,
+    symfile_addr: *const libc::c_char,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub(crate) struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *const JitCodeEntry,
+    pub(crate) first_entry: *const JitCodeEntry,
+}
+
+extern "C" {
+    pub(crate) static __jit_debug_descriptor: JitDescriptor;
+}
+
+/// A function in compiled code.
+#[derive(Debug)]
+pub(crate) struct Symbol {
+    pub(crate) start: usize,
+    pub(crate) size: usize,
+    pub(crate) name: String,
+}
+
+/// Adds the functions of the object file `entry` to `symbols`.
+unsafe fn read_object(entry: &JitCodeEntry, symbols: &mut Vec<Symbol>) {
+    let buf = LLVMCreateMemoryBufferWithMemoryRange(
+        entry.symfile_addr,
+        entry.symfile_size as usize,
+        b"jit\0".as_ptr() as *const _,
+        0,
+    );
+    // Takes the buffer over.
+    let object = LLVMCreateObjectFile(buf);
+    if object.is_null() {
+        return;
+    }
+    let sections = LLVMGetSections(object);
+    let syms = LLVMGetSymbols(object);
+    while LLVMIsSymbolIteratorAtEnd(object, syms) == 0 {
+        LLVMMoveToContainingSection(sections, syms);
+        let in_text = LLVMIsSectionIteratorAtEnd(object, sections) == 0
+            && CStr::from_ptr(LLVMGetSectionName(sections))
+                .to_bytes()
+                .starts_with(b".text");
+        let size = LLVMGetSymbolSize(syms) as usize;
+        let name = CStr::from_ptr(LLVMGetSymbolName(syms)).to_string_lossy();
+        if in_text && size > 0 && !name.is_empty() {
+            symbols.push(Symbol {
+                start: LLVMGetSymbolAddress(syms) as usize,
+                size,
+                name: name.into_owned(),
+            });
+        }
+        LLVMMoveToNextSymbol(syms);
+    }
+    LLVMDisposeSymbolIterator(syms);
+    LLVMDisposeSectionIterator(sections);
+    LLVMDisposeObjectFile(object);
+}
+
+/// The functions the execution engine has loaded, optionally written to a perf map so that
+/// `perf report` can name JIT frames.
+pub(crate) struct Symbols {
+    // The entries of gdb's list read so far. New entries go at the front, so reading stops at
+    // the first one seen before.
+    seen: HashSet<usize>,
+    symbols: Vec<Symbol>,
+    map: Option<File>,
+}
+
+impl Symbols {
+    pub(crate) fn new() -> Self {
+        Symbols {
+            seen: HashSet::new(),
+            symbols: Vec::new(),
+            map: None,
+        }
+    }
+
+    /// Writes every function, those loaded so far included, to `/tmp/perf-<pid>.map`.
+    pub(crate) fn enable_map(&mut self) -> Result<(), Error> {
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::from(ErrorKind::Io(format!("{}: {}", path, e))))?;
+        self.map = Some(file);
+        self.write_map(0)
+    }
+
+    fn write_map(&mut self, from: usize) -> Result<(), Error> {
+        if let Some(map) = &mut self.map {
+            for s in &self.symbols[from..] {
+                writeln!(map, "{:x} {:x} {}", s.start, s.size, s.name)
+                    .map_err(|e| Error::from(ErrorKind::Io(format!("perf map: {}", e))))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks up the functions of the object files loaded since the last update.
+    pub(crate) fn update(&mut self) -> Result<(), Error> {
+        let from = self.symbols.len();
+        unsafe {
+            let mut entry = __jit_debug_descriptor.first_entry;
+            while !entry.is_null() && self.seen.insert(entry as usize) {
+                read_object(&*entry, &mut self.symbols);
+                entry = (*entry).next_entry;
+            }
+        }
+        self.write_map(from)
+    }
+
+    pub(crate) fn lookup(&self, pc: usize) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|s| s.start <= pc && pc < s.start + s.size)
+    }
+
+    /// Tallies `samples` by function, busiest first; time outside compiled code is put down
+    /// to the runtime.
+    pub(crate) fn profile(&self, samples: &[usize], cpu_time: Duration) -> Profile {
+        let mut functions: Vec<(String, usize)> = Vec::new();
+        for &pc in samples {
+            let name = self.lookup(pc).map_or("[runtime]", |s| &s.name);
+            match functions.iter_mut().find(|(n, _)| n == name) {
+                Some((_, count)) => *count += 1,
+                None => functions.push((name.to_owned(), 1)),
+            }
+        }
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Profile {
+            samples: samples.len(),
+            cpu_time,
+            functions,
+        }
+    }
+}
+
+/// Where the CPU time of an evaluation went. Each function is credited with the share of the
+/// time that its samples make up.
+#[derive(Debug)]
+pub(crate) struct Profile {
+    pub(crate) samples: usize,
+    pub(crate) cpu_time: Duration,
+    pub(crate) functions: Vec<(String, usize)>,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = self.cpu_time.as_secs_f64() * 1000.0;
+        writeln!(f, "{} samples over {:.1} ms of CPU time", self.samples, ms)?;
+        for (name, count) in &self.functions {
+            let share = *count as f64 / self.samples as f64;
+            writeln!(
+                f,
+                "{:>6.1}% {:>10.1} ms  {}",
+                100.0 * share,
+                share * ms,
+                name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn thread_cpu_time() -> Duration {
+    let mut t = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut t) };
+    Duration::new(t.tv_sec as u64, t.tv_nsec as u32)
+}
+
+// The timer ticks no faster than the kernel does, whatever it is asked for.
+const INTERVAL_US: i64 = 1000;
+const MAX_SAMPLES: usize = 1 << 16;
+
+// The program counters seen by the signal handler, which can neither lock nor allocate. Later
+// samples are dropped once the buffer is full.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SAMPLE: AtomicUsize = AtomicUsize::new(0);
+static SAMPLES: [AtomicUsize; MAX_SAMPLES] = [NO_SAMPLE; MAX_SAMPLES];
+static SAMPLED: AtomicUsize = AtomicUsize::new(0);
+// Only one thread samples at a time.
+static PROFILING: Mutex<()> = Mutex::new(());
+
+extern "C" fn record_sample(_: libc::c_int, _: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let i = SAMPLED.fetch_add(1, Ordering::Relaxed);
+    if i < MAX_SAMPLES {
+        SAMPLES[i].store(
+            unsafe { runtime::interrupted_pc(context) },
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Runs `run`, noting where the current thread is every `INTERVAL_US` of the CPU time it
+/// spends, and returns what `run` did along with the program counters seen and the CPU time
+/// taken.
+pub(crate) fn sample<T>(run: impl FnOnce() -> T) -> Result<(T, Vec<usize>, Duration), Error> {
+    let _guard = PROFILING.lock().unwrap_or_else(|e| e.into_inner());
+    let error = |what: &str| {
+        Error::from(ErrorKind::Runtime(format!(
+            "cannot profile: {}: {}",
+            what,
+            std::io::Error::last_os_error()
+        )))
+    };
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = record_sample as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut old_action: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGPROF, &action, &mut old_action) != 0 {
+            return Err(error("sigaction"));
+        }
+
+        let mut event: libc::sigevent = std::mem::zeroed();
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = libc::SIGPROF;
+        event.sigev_notify_thread_id = libc::syscall(libc::SYS_gettid) as libc::c_int;
+        let mut timer: libc::timer_t = std::mem::zeroed();
+        if libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut event, &mut timer) != 0 {
+            libc::sigaction(libc::SIGPROF, &old_action, std::ptr::null_mut());
+            return Err(error("timer_create"));
+        }
+        let interval = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: INTERVAL_US * 1000,
+        };
+        let spec = libc::itimerspec {
+            it_interval: interval,
+            it_value: interval,
+        };
+
+        SAMPLED.store(0, Ordering::Relaxed);
+        let start = thread_cpu_time();
+        libc::timer_settime(timer, 0, &spec, std::ptr::null_mut());
+        let ret = run();
+        libc::timer_delete(timer);
+        let cpu_time = thread_cpu_time() - start;
+        libc::sigaction(libc::SIGPROF, &old_action, std::ptr::null_mut());
+
+        let n = SAMPLED.load(Ordering::Relaxed).min(MAX_SAMPLES);
+        let samples = SAMPLES[..n]
+            .iter()
+            .map(|s| s.load(Ordering::Relaxed))
+            .collect();
+        Ok((ret, samples, cpu_time))
+    }
+}
@@ -0,0 +1,129 @@
+use super::error::Error;
+use super::toplevel::{self, Backend};
+
+/// The source of the prelude, which is bundled into the binary.
+pub(crate) const SOURCE: &str = include_str!("prelude.ks");
+
+/// Compiles the prelude into `backend`, quietly.
+pub(crate) fn load(backend: &mut dyn Backend) -> Result<(), Error> {
+    toplevel::run_source(backend, SOURCE).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::interp::Interpreter;
+    use super::super::jit::Engine;
+    use super::super::runtime;
+    use super::super::vm::Vm;
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    /// Collects what the printing builtins write.
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Evaluates `src` after the prelude with every backend that can load it, checking that
+    /// they agree on the results and on what gets printed.
+    fn run_printing(src: &str) -> (Vec<f64>, String) {
+        let mut backends: Vec<Box<dyn Backend>> = vec![
+            Box::new(Interpreter::new()),
+            Box::new(Vm::new()),
+            Box::new(Engine::new()),
+        ];
+        let mut results = backends.iter_mut().map(|b| {
+            let sink = Sink::default();
+            let stderr = runtime::set_output(Box::new(sink.clone()));
+            load(b.as_mut()).unwrap();
+            let results = toplevel::run_source(b.as_mut(), src).unwrap();
+            runtime::set_output(stderr);
+            let printed = String::from_utf8(sink.0.take()).unwrap();
+            (results, printed)
+        });
+        let first = results.next().unwrap();
+        for other in results {
+            assert_eq!(first, other);
+        }
+        first
+    }
+
+    fn run(src: &str) -> Vec<f64> {
+        run_printing(src).0
+    }
+
+    #[test]
+    fn test_arith() {
+        assert_eq!(run("abs(0 - 3); abs(2); abs(0);"), vec![3.0, 2.0, 0.0]);
+        assert_eq!(run("sign(0 - 3); sign(2); sign(0);"), vec![-1.0, 1.0, 0.0]);
+        assert_eq!(
+            run("min(1, 2); min(2, 1); max(1, 2); max(2, 1);"),
+            vec![1.0, 1.0, 2.0, 2.0]
+        );
+        assert_eq!(
+            run("clamp(0 - 5, 0, 10); clamp(5, 0, 10); clamp(15, 0, 10);"),
+            vec![0.0, 5.0, 10.0]
+        );
+        assert_eq!(run("square(0 - 3);"), vec![9.0]);
+        assert_eq!(run("div(7, 2); div(1, 4);"), vec![3.5, 0.25]);
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(
+            run("mod(7, 3); mod(0 - 7, 3); mod(49, 49); mod(2.5, 1);"),
+            vec![1.0, 2.0, 0.0, 0.5]
+        );
+        assert_eq!(
+            run("even(4); even(3); odd(4); odd(3);"),
+            vec![1.0, 0.0, 0.0, 1.0]
+        );
+        assert_eq!(
+            run("fact(0); fact(1); fact(10);"),
+            vec![1.0, 1.0, 3628800.0]
+        );
+        assert_eq!(
+            run("gcd(12, 18); gcd(0 - 12, 18); gcd(7, 0); gcd(0, 7); gcd(17, 5);"),
+            vec![6.0, 6.0, 7.0, 7.0, 1.0]
+        );
+        assert_eq!(
+            run("lcm(4, 6); lcm(0 - 4, 6); lcm(0, 6);"),
+            vec![12.0, 12.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_printing() {
+        // Each helper returns 0.
+        assert_eq!(
+            run_printing("newline(); space(); printint(1203); printint(0 - 45); printint(0);"),
+            (vec![0.0; 5], "\n 1203-450".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_redefinition() {
+        // Definitions of the same name replace the prelude's, for its own callers too.
+        assert_eq!(
+            run("def abs(x) 42; abs(0 - 3); def min(a b) 99; clamp(5, 0, 3);"),
+            vec![42.0, 99.0]
+        );
+    }
+
+    #[test]
+    fn test_redeclared_externs() {
+        // Programs written without the prelude still declare what they use.
+        assert_eq!(run("extern floor(x); floor(2.5);"), vec![2.0]);
+    }
+}
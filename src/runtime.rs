@@ -0,0 +1,426 @@
+use std::cell::{Cell, RefCell};
+use std::io::{stderr, Write};
+use std::mem::MaybeUninit;
+use std::ptr::null_mut;
+use std::slice;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use super::ast::Type;
+use super::error::{Error, ErrorKind};
+
+thread_local! {
+    // Where the printing builtins write on this thread.
+    static OUTPUT: RefCell<Box<dyn Write>> = RefCell::new(Box::new(stderr()));
+}
+
+/// Sends what the printing builtins write on this thread to `sink`, which is stderr to start
+/// with. Returns the previous sink.
+#[cfg(test)]
+pub(crate) fn set_output(sink: Box<dyn Write>) -> Box<dyn Write> {
+    OUTPUT.with(|out| out.replace(sink))
+}
+
+fn output(bytes: &[u8]) {
+    OUTPUT.with(|out| {
+        let _ = out.borrow_mut().write_all(bytes);
+    });
+}
+
+extern "C" fn putchard(x: f64) -> f64 {
+    output(&[x as u8]);
+    0.0
+}
+
+extern "C" fn printd(x: f64) -> f64 {
+    output(format!("{}\n", x).as_bytes());
+    0.0
+}
+
+extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+extern "C" fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+extern "C" fn log(x: f64) -> f64 {
+    x.ln()
+}
+
+extern "C" fn floor(x: f64) -> f64 {
+    x.floor()
+}
+
+extern "C" fn pow(x: f64, y: f64) -> f64 {
+    x.powf(y)
+}
+
+/// A string as compiled code passes it around: a pointer to UTF-8 bytes and their length.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Str {
+    ptr: *const u8,
+    len: i64,
+}
+
+impl Str {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len as usize) }
+    }
+}
+
+extern "C" fn printstr(s: Str) -> f64 {
+    output(s.as_bytes());
+    0.0
+}
+
+extern "C" fn strlen(s: Str) -> i64 {
+    s.len
+}
+
+// Strings are never freed: literals live as long as their module, and so do the results here.
+extern "C" fn concat(a: Str, b: Str) -> Str {
+    let bytes = [a.as_bytes(), b.as_bytes()].concat().into_boxed_slice();
+    let len = bytes.len() as i64;
+    Str {
+        ptr: Box::leak(bytes).as_ptr(),
+        len,
+    }
+}
+
+/// An array as compiled code passes it around: a pointer to its elements and their count.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Array {
+    ptr: *mut f64,
+    len: i64,
+}
+
+/// Allocates `n` zeroes. Like strings, arrays are never freed.
+pub(crate) extern "C" fn array(n: f64) -> Array {
+    let mut elements = Vec::new();
+    if !(n >= 0.0 && n.fract() == 0.0) || elements.try_reserve_exact(n as usize).is_err() {
+        trap(format!("invalid array length: {}", n));
+    }
+    elements.resize(n as usize, 0.0);
+    Array {
+        ptr: Box::leak(elements.into_boxed_slice()).as_mut_ptr(),
+        len: n as i64,
+    }
+}
+
+extern "C" fn len(a: Array) -> f64 {
+    a.len as f64
+}
+
+pub(crate) extern "C" fn index_error(index: f64, len: i64) -> ! {
+    if (0.0..len as f64).contains(&index) {
+        trap(format!("index {} is not a whole number", index))
+    }
+    trap(format!("index {} out of bounds for length {}", index, len))
+}
+
+/// Room for a `sigjmp_buf`, which is 200 bytes with glibc on x86-64.
+#[repr(C, align(16))]
+pub(crate) struct JmpBuf([u8; 256]);
+
+/// Why compiled code was abandoned.
+pub(crate) enum Trap {
+    Error(String),
+    LimitExceeded(String),
+    // At the given program counter.
+    StackOverflow(usize),
+}
+
+impl From<Trap> for Error {
+    fn from(t: Trap) -> Error {
+        Error::from(match t {
+            Trap::Error(msg) => ErrorKind::Runtime(msg),
+            Trap::LimitExceeded(msg) => ErrorKind::LimitExceeded(msg),
+            Trap::StackOverflow(_) => ErrorKind::Runtime("stack overflow".to_owned()),
+        })
+    }
+}
+
+thread_local! {
+    // The buffer of the innermost `catch`, if any.
+    static CATCH: Cell<*mut JmpBuf> = const { Cell::new(null_mut()) };
+    static TRAPPED: RefCell<Option<Trap>> = const { RefCell::new(None) };
+}
+
+extern "C" {
+    fn siglongjmp(env: *mut JmpBuf, val: libc::c_int) -> !;
+}
+
+/// Gives `run` a buffer to `sigsetjmp` on before it calls into compiled code, so that `trap`
+/// can abandon that code. `run` returns what `sigsetjmp` last returned; if that is because of a
+/// trap, the trap is the error.
+pub(crate) fn catch(run: impl FnOnce(*mut JmpBuf) -> libc::c_int) -> Result<(), Trap> {
+    guard_stack();
+    let mut buf = JmpBuf([0; 256]);
+    let outer = CATCH.with(|c| c.replace(&mut buf));
+    let jumped = run(&mut buf);
+    CATCH.with(|c| c.set(outer));
+    match jumped {
+        0 => Ok(()),
+        _ => Err(TRAPPED
+            .with(|t| t.borrow_mut().take())
+            .unwrap_or_else(|| Trap::Error(String::new()))),
+    }
+}
+
+/// Abandons the compiled code that is running, making the enclosing `catch` fail with `msg`.
+pub(crate) fn trap(msg: String) -> ! {
+    abandon(Trap::Error(msg))
+}
+
+fn abandon(t: Trap) -> ! {
+    let env = CATCH.with(|c| c.get());
+    if env.is_null() {
+        eprintln!("fatal runtime error: {}", Error::from(t));
+        std::process::abort();
+    }
+    TRAPPED.with(|tr| *tr.borrow_mut() = Some(t));
+    // Nothing between here and the `sigsetjmp` has anything left to drop: these frames have
+    // given away their strings, and compiled code owns nothing.
+    unsafe { siglongjmp(env, 1) }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn interrupted_registers(context: *mut libc::c_void) -> (usize, usize) {
+    let gregs = &(*(context as *const libc::ucontext_t)).uc_mcontext.gregs;
+    (
+        gregs[libc::REG_RIP as usize] as usize,
+        gregs[libc::REG_RSP as usize] as usize,
+    )
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+unsafe fn interrupted_registers(context: *mut libc::c_void) -> (usize, usize) {
+    let mcontext = &(*(context as *const libc::ucontext_t)).uc_mcontext;
+    (mcontext.pc as usize, mcontext.sp as usize)
+}
+
+/// The program counter of the code a signal interrupted, given the context its handler got.
+pub(crate) unsafe fn interrupted_pc(context: *mut libc::c_void) -> usize {
+    interrupted_registers(context).0
+}
+
+// Accesses this close to the stack pointer are taken to be to the stack.
+const STACK_SLACK: usize = 64 * 1024;
+const SIGNAL_STACK_SIZE: usize = 64 * 1024;
+
+static SEGV_HANDLER: Once = Once::new();
+// What handled SIGSEGV before us, which gets every fault that is not a stack overflow in
+// compiled code.
+static mut PREVIOUS_SEGV: MaybeUninit<libc::sigaction> = MaybeUninit::uninit();
+
+thread_local! {
+    static SIGNAL_STACK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes running out of stack under `catch` a trap rather than a crash. That takes a handler
+/// for SIGSEGV and, since the stack it would run on is gone, a stack of its own in each thread.
+fn guard_stack() {
+    SEGV_HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_segv as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(
+            libc::SIGSEGV,
+            &action,
+            (*std::ptr::addr_of_mut!(PREVIOUS_SEGV)).as_mut_ptr(),
+        );
+    });
+    if SIGNAL_STACK.with(|s| s.replace(true)) {
+        return;
+    }
+    unsafe {
+        // Threads spawned by std have one already.
+        let mut current: libc::stack_t = std::mem::zeroed();
+        libc::sigaltstack(null_mut(), &mut current);
+        if current.ss_flags & libc::SS_DISABLE != 0 {
+            // Leaked, as the thread may take signals until it ends.
+            let stack = Box::leak(vec![0u8; SIGNAL_STACK_SIZE].into_boxed_slice());
+            let stack = libc::stack_t {
+                ss_sp: stack.as_mut_ptr() as *mut libc::c_void,
+                ss_flags: 0,
+                ss_size: SIGNAL_STACK_SIZE,
+            };
+            libc::sigaltstack(&stack, null_mut());
+        }
+    }
+}
+
+extern "C" fn on_segv(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    unsafe {
+        let env = CATCH.with(|c| c.get());
+        let (pc, sp) = interrupted_registers(context);
+        let addr = (*info).si_addr() as usize;
+        if !env.is_null() && addr.wrapping_sub(sp.wrapping_sub(STACK_SLACK)) < 2 * STACK_SLACK {
+            // Only a handful of frames of `catch` run before the jump, and no allocation.
+            TRAPPED.with(|t| *t.borrow_mut() = Some(Trap::StackOverflow(pc)));
+            siglongjmp(env, 1);
+        }
+
+        let previous = &*(*std::ptr::addr_of!(PREVIOUS_SEGV)).as_ptr();
+        if previous.sa_flags & libc::SA_SIGINFO != 0
+            && previous.sa_sigaction != libc::SIG_DFL
+            && previous.sa_sigaction != libc::SIG_IGN
+        {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(previous.sa_sigaction);
+            handler(signal, info, context);
+        } else {
+            // Returning retries the access, which now gets the previous treatment.
+            libc::sigaction(libc::SIGSEGV, previous, null_mut());
+        }
+    }
+}
+
+// How many steps compiled code takes between looks at the clock.
+const FUEL_CHUNK: u64 = 10_000;
+
+/// How long each evaluation may run for, in steps and in wall-clock time. Code compiled with
+/// limits counts `tank` down at every function entry and loop iteration, and calls `refuel`
+/// when it goes negative.
+#[repr(C)]
+pub(crate) struct Limits {
+    // First, so that compiled code can reach it through a pointer to the whole.
+    tank: i64,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    // The fuel of the current evaluation that is not in the tank, and when it must end.
+    remaining: u64,
+    deadline: Option<Instant>,
+}
+
+impl Limits {
+    pub(crate) fn new(fuel: Option<u64>, timeout: Option<Duration>) -> Self {
+        let mut limits = Limits {
+            tank: 0,
+            fuel,
+            timeout,
+            remaining: 0,
+            deadline: None,
+        };
+        limits.start();
+        limits
+    }
+
+    /// Fills up for a new evaluation.
+    pub(crate) fn start(&mut self) {
+        self.tank = 0;
+        self.remaining = self.fuel.unwrap_or(u64::MAX);
+        self.deadline = self.timeout.map(|t| Instant::now() + t);
+    }
+}
+
+/// Called by compiled code when its tank is empty, for the step it is about to take.
+pub(crate) extern "C" fn refuel(limits: *mut Limits) {
+    let limits = unsafe { &mut *limits };
+    if limits.remaining == 0 {
+        abandon(Trap::LimitExceeded(format!(
+            "out of fuel after {} steps",
+            limits.fuel.unwrap_or_default()
+        )));
+    }
+    if let (Some(deadline), Some(timeout)) = (limits.deadline, limits.timeout) {
+        if Instant::now() >= deadline {
+            abandon(Trap::LimitExceeded(format!(
+                "timed out after {} ms",
+                timeout.as_millis()
+            )));
+        }
+    }
+    let refill = limits.remaining.min(FUEL_CHUNK);
+    limits.remaining -= refill;
+    limits.tank = refill as i64 - 1;
+}
+
+/// A native function that `extern` declarations are bound to, shared by every backend.
+#[derive(Clone, Copy)]
+pub(crate) enum Builtin {
+    Unary(extern "C" fn(f64) -> f64),
+    Binary(extern "C" fn(f64, f64) -> f64),
+    // Only the JIT has strings and arrays, so these are never `call`ed.
+    Print(extern "C" fn(Str) -> f64),
+    Length(extern "C" fn(Str) -> i64),
+    Concat(extern "C" fn(Str, Str) -> Str),
+    Alloc(extern "C" fn(f64) -> Array),
+    Len(extern "C" fn(Array) -> f64),
+}
+
+impl Builtin {
+    pub(crate) fn arity(&self) -> usize {
+        self.signature().0.len()
+    }
+
+    /// Parameter and return types.
+    pub(crate) fn signature(&self) -> (Vec<Type>, Type) {
+        use Type::*;
+
+        match self {
+            Builtin::Unary(_) => (vec![F64], F64),
+            Builtin::Binary(_) => (vec![F64, F64], F64),
+            Builtin::Print(_) => (vec![Str], F64),
+            Builtin::Length(_) => (vec![Str], I64),
+            Builtin::Concat(_) => (vec![Str, Str], Str),
+            Builtin::Alloc(_) => (vec![F64], Array),
+            Builtin::Len(_) => (vec![Array], F64),
+        }
+    }
+
+    pub(crate) fn call(&self, args: &[f64]) -> f64 {
+        match self {
+            Builtin::Unary(f) => f(args[0]),
+            Builtin::Binary(f) => f(args[0], args[1]),
+            _ => unreachable!("string and array builtins only run in the JIT"),
+        }
+    }
+
+    pub(crate) fn addr(&self) -> *mut libc::c_void {
+        match self {
+            Builtin::Unary(f) => *f as *mut _,
+            Builtin::Binary(f) => *f as *mut _,
+            Builtin::Print(f) => *f as *mut _,
+            Builtin::Length(f) => *f as *mut _,
+            Builtin::Concat(f) => *f as *mut _,
+            Builtin::Alloc(f) => *f as *mut _,
+            Builtin::Len(f) => *f as *mut _,
+        }
+    }
+}
+
+pub(crate) fn lookup(name: &str) -> Option<Builtin> {
+    use Builtin::*;
+
+    match name {
+        "putchard" => Some(Unary(putchard)),
+        "printd" => Some(Unary(printd)),
+        "sin" => Some(Unary(sin)),
+        "cos" => Some(Unary(cos)),
+        "sqrt" => Some(Unary(sqrt)),
+        "exp" => Some(Unary(exp)),
+        "log" => Some(Unary(log)),
+        "floor" => Some(Unary(floor)),
+        "pow" => Some(Binary(pow)),
+        "printstr" => Some(Print(printstr)),
+        "strlen" => Some(Length(strlen)),
+        "concat" => Some(Concat(concat)),
+        "array" => Some(Alloc(array)),
+        "len" => Some(Len(len)),
+        _ => None,
+    }
+}
@@ -2,13 +2,23 @@
 pub(crate) enum Token {
     Def,
     Extern,
+    Import,
+    Struct,
+    Fn,
     If,
     Then,
     Else,
     For,
     In,
+    Let,
+    While,
+    Do,
+    Break,
+    Continue,
+    Return,
     Ident(String),
     Number(f64),
+    Str(String),
     Kwd(char),
     Eof,
 }
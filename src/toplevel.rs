@@ -1,111 +1,653 @@
-use super::codegen;
+use super::ast::{Function, Pos, Prototype, StructDef};
+use super::editor::Editor;
+use super::error::{Error, ErrorKind};
+use super::import::Imports;
 use super::lexer;
 use super::parser;
+use super::perf::Profile;
 use super::token::Token;
-use combine::Parser;
-use std::io::{stdin, stdout, Write};
-use std::ptr::null_mut;
-
-use llvm_sys::core::*;
-use llvm_sys::execution_engine::*;
-use llvm_sys::prelude::*;
-
-pub(crate) unsafe fn main_loop(
-    c: &mut codegen::Context,
-    the_fpm: LLVMPassManagerRef,
-    the_execution_engine: LLVMExecutionEngineRef,
+use combine::error::UnexpectedParse;
+use combine::stream::position::{self, Positioner};
+use combine::{Parser, StreamOnce};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// An execution strategy for the items typed into the REPL.
+pub(crate) trait Backend {
+    /// Compiles (or records) a `def`.
+    fn define(&mut self, f: Function) -> Result<(), Error>;
+
+    /// Binds an `extern` declaration.
+    fn declare(&mut self, p: Prototype) -> Result<(), Error>;
+
+    /// Evaluates a top-level expression, wrapped in an anonymous function.
+    fn eval(&mut self, f: Function) -> Result<f64, Error>;
+
+    /// Declares a struct type. Only the JIT has structs.
+    fn define_struct(&mut self, _: StructDef) -> Result<(), Error> {
+        Err(Error::from(ErrorKind::Type(
+            "structs are only supported by the JIT backend".to_owned(),
+        )))
+    }
+
+    /// Evaluates a top-level expression like `eval`, sampling where its time goes. Only the JIT
+    /// can.
+    fn profile(&mut self, _: Function) -> Result<(f64, Profile), Error> {
+        Err(Error::from(ErrorKind::Runtime(
+            "profiling is only supported by the JIT backend".to_owned(),
+        )))
+    }
+
+    /// The signatures of the functions defined and declared so far.
+    fn list(&self) -> Vec<String>;
+
+    /// The LLVM IR of the function `name`. Only the JIT has any.
+    fn ir(&self, _: &str) -> Result<String, Error> {
+        Err(Error::from(ErrorKind::Codegen(
+            "IR is only available from the JIT backend".to_owned(),
+        )))
+    }
+
+    /// The machine code of the function `name`, disassembled. Only the JIT has any.
+    fn asm(&mut self, _: &str) -> Result<String, Error> {
+        Err(Error::from(ErrorKind::Codegen(
+            "machine code is only available from the JIT backend".to_owned(),
+        )))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Item {
+    Definition(Function),
+    Extern(Prototype),
+    TopLevel(Function),
+    Struct(StructDef),
+    // The path of another source file, resolved by `Imports`.
+    Import(String),
+}
+
+/// Skips what the lexer skips before a token, so that we know where the token starts.
+fn skip_blanks(mut s: &str) -> &str {
+    s = s.trim_start();
+    while s.starts_with('#') {
+        s = s.find('\n').map_or("", |i| &s[i..]).trim_start();
+    }
+    s
+}
+
+/// Splits `src` into tokens, along with the position of each.
+pub(crate) fn tokenize(src: &str) -> Result<(Vec<Token>, Vec<Pos>), Error> {
+    let mut buf = src;
+    let mut tokens = Vec::new();
+    let mut positions = Vec::new();
+    let mut pos = Pos { line: 1, col: 1 };
+    loop {
+        let start = skip_blanks(buf);
+        for c in buf[..buf.len() - start.len()].chars() {
+            if c == '\n' {
+                pos.line += 1;
+                pos.col = 1;
+            } else {
+                pos.col += 1;
+            }
+        }
+        buf = start;
+
+        match lexer::lex().parse(buf) {
+            Ok((Some(token), rest)) => {
+                positions.push(pos);
+                // Tokens never span lines.
+                pos.col += buf[..buf.len() - rest.len()].chars().count() as u32;
+                buf = rest;
+                tokens.push(token);
+            }
+            Ok(_) => break,
+            Err(e) => return Err(Error::from(ErrorKind::Parse(e.to_string()))),
+        }
+    }
+
+    Ok((tokens, positions))
+}
+
+/// Tracks the index of the next token to report its position from the table built by
+/// `tokenize`.
+#[derive(Clone)]
+pub(crate) struct TokenPositioner<'a> {
+    positions: &'a [Pos],
+    index: usize,
+}
+
+impl<'a> Positioner<Token> for TokenPositioner<'a> {
+    type Position = Pos;
+    type Checkpoint = usize;
+
+    fn position(&self) -> Pos {
+        self.positions.get(self.index).cloned().unwrap_or_default()
+    }
+
+    fn update(&mut self, _: &Token) {
+        self.index += 1;
+    }
+
+    fn checkpoint(&self) -> usize {
+        self.index
+    }
+
+    fn reset(&mut self, checkpoint: usize) {
+        self.index = checkpoint;
+    }
+}
+
+pub(crate) type TokenStream<'a> = position::Stream<&'a [Token], TokenPositioner<'a>>;
+
+pub(crate) fn token_stream<'a>(tokens: &'a [Token], positions: &'a [Pos]) -> TokenStream<'a> {
+    position::Stream::with_positioner(
+        tokens,
+        TokenPositioner {
+            positions,
+            index: 0,
+        },
+    )
+}
+
+/// Parses the next item, skipping any leading `;`. Returns `None` at the end of input.
+pub(crate) fn parse_item(
+    mut ts: TokenStream,
+) -> Result<Option<(Item, TokenStream)>, UnexpectedParse> {
+    while let Some(Token::Kwd(';')) = ts.input.first() {
+        let _ = ts.uncons();
+    }
+
+    match ts.input.first() {
+        None => Ok(None),
+        Some(Token::Def) => parser::definition()
+            .parse(ts)
+            .map(|(f, rest)| Some((Item::Definition(f), rest))),
+        Some(Token::Extern) => parser::extern_parser()
+            .parse(ts)
+            .map(|(p, rest)| Some((Item::Extern(p), rest))),
+        Some(Token::Import) => parser::import_parser()
+            .parse(ts)
+            .map(|(path, rest)| Some((Item::Import(path), rest))),
+        Some(Token::Struct) => parser::struct_parser()
+            .parse(ts)
+            .map(|(s, rest)| Some((Item::Struct(s), rest))),
+        Some(_) => parser::toplevel()
+            .parse(ts)
+            .map(|(f, rest)| Some((Item::TopLevel(f), rest))),
+    }
+}
+
+fn parse_error(e: UnexpectedParse) -> Error {
+    Error::from(ErrorKind::Parse(format!("{:?}", e)))
+}
+
+/// Parses all of `src` up front, for consumers that compile a whole file at once.
+pub(crate) fn parse_source(src: &str) -> Result<Vec<Item>, Error> {
+    let (tokens, positions) = tokenize(src)?;
+    let mut ts = token_stream(&tokens, &positions);
+    let mut items = Vec::new();
+
+    while let Some((item, rest)) = parse_item(ts).map_err(parse_error)? {
+        items.push(item);
+        ts = rest;
+    }
+
+    Ok(items)
+}
+
+/// Runs every item in `src`, stopping at the first error, and returns the values of the
+/// top-level expressions.
+pub(crate) fn run_source<B: Backend + ?Sized>(
+    backend: &mut B,
+    src: &str,
+) -> Result<Vec<f64>, Error> {
+    let mut results = Vec::new();
+
+    for item in parse_source(src)? {
+        match item {
+            Item::Definition(f) => backend.define(f)?,
+            Item::Extern(p) => backend.declare(p)?,
+            Item::TopLevel(f) => results.push(backend.eval(f)?),
+            Item::Struct(s) => backend.define_struct(s)?,
+            Item::Import(path) => {
+                return Err(Error::from(ErrorKind::Import(format!(
+                    "cannot import {} here",
+                    path
+                ))))
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// How the REPL and `handle_source` report on the items they run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Output {
+    /// Only the values of top-level expressions, and errors.
+    Quiet,
+    /// Also each item as it is parsed.
+    Verbose,
+    /// A JSON object per item run.
+    Json,
+}
+
+/// What running an item came to.
+enum Outcome {
+    Defined(&'static str, String),
+    Evaluated(f64),
+    Failed(Error),
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The line `output` shows for `outcome`, if any. JSON has no NaN or infinities, so those
+/// values are given as strings.
+fn report(output: Output, outcome: &Outcome) -> Option<String> {
+    match (output, outcome) {
+        (Output::Json, Outcome::Defined(kind, name)) => Some(format!(
+            "{{\"kind\":\"{}\",\"name\":{}}}",
+            kind,
+            json_string(name)
+        )),
+        (Output::Json, Outcome::Evaluated(v)) if v.is_finite() => {
+            Some(format!("{{\"kind\":\"value\",\"value\":{}}}", v))
+        }
+        (Output::Json, Outcome::Evaluated(v)) => Some(format!(
+            "{{\"kind\":\"value\",\"value\":{}}}",
+            json_string(&v.to_string())
+        )),
+        (Output::Json, Outcome::Failed(e)) => Some(format!(
+            "{{\"kind\":\"error\",\"message\":{}}}",
+            json_string(&e.to_string())
+        )),
+        (_, Outcome::Defined(..)) => None,
+        (_, Outcome::Evaluated(v)) => Some(format!("Evaluated to {}", v)),
+        (_, Outcome::Failed(e)) => Some(format!("error: {}", e)),
+    }
+}
+
+fn show(output: Output, outcome: Outcome) {
+    if let Some(line) = report(output, &outcome) {
+        println!("{}", line);
+    }
+}
+
+/// Shows the value of a top-level expression run some other way.
+pub(crate) fn show_value(output: Output, v: f64) {
+    show(output, Outcome::Evaluated(v));
+}
+
+fn trace(output: Output, msg: &str) {
+    if output == Output::Verbose {
+        println!("{}", msg);
+    }
+}
+
+/// Runs one item as the REPL does, reporting on stdout as `output` says. The items an import
+/// brings in run in turn; `dir` is where the file being run lives.
+fn handle_item(
+    backend: &mut dyn Backend,
+    imports: &mut Imports,
+    output: Output,
+    item: Item,
+    dir: &Path,
 ) {
-    'outer: loop {
-        print!("Ready> ");
-        stdout().flush().unwrap();
-        let mut line = String::new();
-        stdin().read_line(&mut line).unwrap();
-        let mut buf = line.as_str();
-        let mut tokens = Vec::new();
-        loop {
-            match lexer::lex().parse(buf) {
-                Ok((Some(token), rest)) => {
-                    buf = rest;
-                    tokens.push(token);
-                }
-                Ok(_) => break,
-                Err(e) => {
-                    println!("error: {}", e);
-                    continue 'outer;
+    let outcome = match item {
+        Item::Definition(f) => {
+            trace(output, "parsed a function definition.");
+            let name = f.0 .0.clone();
+            backend.define(f).map(|()| Outcome::Defined("def", name))
+        }
+        Item::Extern(p) => {
+            trace(output, "parsed an extern.");
+            let name = p.0.clone();
+            backend
+                .declare(p)
+                .map(|()| Outcome::Defined("extern", name))
+        }
+        Item::TopLevel(f) => {
+            trace(output, "parsed a top-level expr.");
+            backend.eval(f).map(Outcome::Evaluated)
+        }
+        Item::Struct(s) => {
+            trace(output, "parsed a struct.");
+            let name = s.0.clone();
+            backend
+                .define_struct(s)
+                .map(|()| Outcome::Defined("struct", name))
+        }
+        Item::Import(path) => {
+            trace(output, "parsed an import.");
+            match imports.import(&path, dir) {
+                Ok(items) => {
+                    for item in items {
+                        handle_item(backend, imports, output, item, dir);
+                    }
+                    return;
                 }
+                Err(e) => Err(e),
             }
         }
+    };
+    show(output, outcome.unwrap_or_else(Outcome::Failed));
+}
 
-        let mut ts = tokens.as_slice();
-
-        while ts.len() > 0 {
-            match ts[0] {
-                Token::Kwd(';') => ts = &ts[1..],
-                Token::Def => match parser::definition().parse(ts) {
-                    Ok((e, rest)) => {
-                        println!("parse a function definition.");
-                        unsafe {
-                            match codegen::codegen_func(c, the_fpm, &e) {
-                                Ok(v) => LLVMDumpValue(v),
-                                Err(e) => println!("error: {}", e),
-                            }
-                        };
-                        ts = rest;
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                        break;
-                    }
-                },
-                Token::Extern => match parser::extern_parser().parse(ts) {
-                    Ok((p, rest)) => {
-                        println!("parsed an extern.");
-                        unsafe {
-                            match codegen::codegen_proto(c, &p) {
-                                Ok(v) => LLVMDumpValue(v),
-                                Err(e) => println!("error: {}", e),
-                            }
-                        };
-                        ts = rest;
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                        break;
-                    }
-                },
-                _ => match parser::toplevel().parse(ts) {
-                    Ok((e, rest)) => {
-                        println!("parse a top-level expr");
-                        unsafe {
-                            match codegen::codegen_func(c, the_fpm, &e) {
-                                Ok(the_function) => {
-                                    LLVMDumpValue(the_function);
-
-                                    let result = LLVMRunFunction(
-                                        the_execution_engine,
-                                        the_function as *mut _,
-                                        0,
-                                        null_mut(),
-                                    );
-                                    println!("the_function: {:?}", the_function);
-                                    println!("result: {:?}", result);
-
-                                    println!(
-                                        "Evaluated to {}",
-                                        LLVMGenericValueToFloat(c.double_type, result) as f64
-                                    );
-                                }
-                                Err(e) => println!("error: {}", e),
-                            }
-                        };
-                        ts = rest;
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                        break;
-                    }
-                },
+/// Runs the items in `src` one by one, reporting on stdout as `output` says. Items after a
+/// parse error are skipped.
+pub(crate) fn handle_source(
+    backend: &mut dyn Backend,
+    imports: &mut Imports,
+    output: Output,
+    src: &str,
+    dir: &Path,
+) {
+    let (tokens, positions) = match tokenize(src) {
+        Ok(tokenized) => tokenized,
+        Err(e) => {
+            show(output, Outcome::Failed(e));
+            return;
+        }
+    };
+
+    let mut ts = token_stream(&tokens, &positions);
+
+    loop {
+        match parse_item(ts) {
+            Ok(Some((item, rest))) => {
+                handle_item(backend, imports, output, item, dir);
+                ts = rest;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                show(output, Outcome::Failed(parse_error(e)));
+                break;
+            }
+        }
+    }
+}
+
+/// Evaluates the top-level expression in `src` under the sampling profiler and reports where
+/// the time went.
+fn profile_source(backend: &mut dyn Backend, src: &str) -> Result<String, Error> {
+    let mut items = parse_source(src)?;
+    match (items.pop(), items.is_empty()) {
+        (Some(Item::TopLevel(f)), true) => backend
+            .profile(f)
+            .map(|(v, profile)| format!("Evaluated to {}\n{}", v, profile)),
+        _ => Err(command_error(":profile takes one expression")),
+    }
+}
+
+/// The AST of the expression or definition in `src`, without positions.
+fn parse_ast(src: &str) -> Result<String, Error> {
+    let (tokens, _) = tokenize(src)?;
+    let tokens = tokens.as_slice();
+    let parsed = match tokens.first() {
+        Some(Token::Def) => parser::definition()
+            .parse(tokens)
+            .map(|(f, rest)| (format!("{:#?}", f), rest)),
+        _ => parser::toplevel()
+            .parse(tokens)
+            .map(|(Function(_, body), rest)| (format!("{:#?}", body), rest)),
+    };
+    match parsed.map_err(parse_error)? {
+        (ast, rest) if rest.iter().all(|t| *t == Token::Kwd(';')) => Ok(ast),
+        _ => Err(command_error(":ast takes one expression or definition")),
+    }
+}
+
+fn command_error(msg: &str) -> Error {
+    Error::from(ErrorKind::Parse(msg.to_owned()))
+}
+
+const HELP: &str = "\
+:tokens src    the tokens of src
+:ast expr      the syntax tree of an expression or definition
+:ir name       the LLVM IR of a function
+:asm name      the machine code of a function
+:list          the functions defined and declared so far
+:profile expr  evaluate expr, reporting the time spent in each function
+:load file     run the items in a file
+:reset         start over with a new session
+:quit          leave";
+
+/// Runs the REPL command `command` with the rest of the line, `arg`, and returns what to show.
+/// `:reset` and `:quit` are up to the caller.
+fn run_command(
+    backend: &mut dyn Backend,
+    imports: &mut Imports,
+    output: Output,
+    command: &str,
+    arg: &str,
+) -> Result<String, Error> {
+    match command {
+        "tokens" => tokenize(arg).map(|(tokens, _)| {
+            let tokens = tokens
+                .iter()
+                .map(|t| format!("{:?}", t))
+                .collect::<Vec<_>>();
+            tokens.join(" ")
+        }),
+        "ast" => parse_ast(arg),
+        "ir" => backend.ir(arg),
+        "asm" => backend.asm(arg),
+        "list" => Ok(backend.list().join("\n")),
+        "profile" => profile_source(backend, arg),
+        "load" => {
+            let path = Path::new(arg);
+            let src = std::fs::read_to_string(path)
+                .map_err(|e| Error::from(ErrorKind::Io(format!("{}: {}", arg, e))))?;
+            let dir = path.parent().unwrap_or_else(|| Path::new("."));
+            handle_source(backend, imports, output, &src, dir);
+            Ok(String::new())
+        }
+        "help" => Ok(HELP.to_owned()),
+        _ => Err(command_error(&format!(
+            "unknown command :{}; :help lists them",
+            command
+        ))),
+    }
+}
+
+const COMMANDS: &[&str] = &[
+    ":tokens", ":ast", ":ir", ":asm", ":list", ":profile", ":load", ":reset", ":quit", ":help",
+];
+
+/// What tab offers: keywords, commands and the functions `backend` knows of.
+fn completions(backend: &dyn Backend) -> Vec<String> {
+    let names = backend.list().into_iter().filter_map(|entry| {
+        let (_, rest) = entry.split_once(' ')?;
+        rest.split('(').next().map(str::to_owned)
+    });
+    lexer::KEYWORDS
+        .iter()
+        .chain(COMMANDS)
+        .map(|&s| s.to_owned())
+        .chain(names)
+        .collect()
+}
+
+fn history_file() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".kaleidoscope_history"))
+}
+
+/// Reads items from stdin, with line editing and history kept in `~/.kaleidoscope_history`
+/// when it is a terminal; imports are relative to the current directory. Lines starting with
+/// `:` are commands, which `:help` lists. `new_backend` starts the session, and starts it over
+/// on `:reset`. Ends at the end of input or on `:quit`. There is no prompt in JSON mode, and
+/// what commands print comes as a JSON object too.
+pub(crate) fn main_loop(
+    new_backend: &dyn Fn() -> Result<Box<dyn Backend>, Error>,
+    imports: &mut Imports,
+    output: Output,
+) -> Result<(), Error> {
+    let mut backend = new_backend()?;
+    let mut editor = Editor::new(history_file());
+    let prompt = if output == Output::Json {
+        ""
+    } else {
+        "Ready> "
+    };
+    loop {
+        let line = {
+            let backend = backend.as_ref();
+            editor.read_line(prompt, &|| completions(backend))
+        };
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(Error::from(ErrorKind::Io(format!("stdin: {}", e)))),
+        };
+        editor.add_history(&line);
+        let command = match line.trim().strip_prefix(':') {
+            Some(command) => command,
+            None => {
+                handle_source(backend.as_mut(), imports, output, &line, Path::new("."));
+                continue;
             }
+        };
+        let (command, arg) = match command.find(char::is_whitespace) {
+            Some(i) => (&command[..i], command[i..].trim_start()),
+            None => (command, ""),
+        };
+        match command {
+            "quit" => return Ok(()),
+            "reset" => match new_backend() {
+                Ok(b) => {
+                    backend = b;
+                    imports.reset();
+                }
+                Err(e) => show(output, Outcome::Failed(e)),
+            },
+            _ => match run_command(backend.as_mut(), imports, output, command, arg) {
+                Ok(out) if out.is_empty() => (),
+                Ok(out) if output == Output::Json => println!(
+                    "{{\"kind\":\"command\",\"output\":{}}}",
+                    json_string(out.trim_end())
+                ),
+                Ok(out) => println!("{}", out.trim_end()),
+                Err(e) => show(output, Outcome::Failed(e)),
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::ast::Expr;
+    use super::super::interp::Interpreter;
+    use super::super::jit::Engine;
+    use super::*;
+
+    fn command(backend: &mut dyn Backend, line: &str) -> Result<String, Error> {
+        let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+        run_command(
+            backend,
+            &mut Imports::new(Vec::new()),
+            Output::Quiet,
+            command,
+            arg,
+        )
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut interp = Interpreter::new();
+        assert_eq!(
+            command(&mut interp, "tokens f(1);").unwrap(),
+            "Ident(\"f\") Kwd('(') Number(1.0) Kwd(')') Kwd(';')"
+        );
+        assert_eq!(
+            command(&mut interp, "ast x + 1;").unwrap(),
+            format!(
+                "{:#?}",
+                Expr::Binary(
+                    '+',
+                    Box::new(Expr::Variable("x".to_owned())),
+                    Box::new(Expr::Number(1.0))
+                )
+            )
+        );
+        assert!(command(&mut interp, "ast def f(x) x")
+            .unwrap()
+            .starts_with("Function("));
+        assert!(command(&mut interp, "ast 1 2").is_err());
+
+        run_source(
+            &mut interp,
+            "extern sin(x); def sq(x) x * x; def below(x y) x < y;",
+        )
+        .unwrap();
+        assert_eq!(
+            command(&mut interp, "list").unwrap(),
+            "def below(x: f64 y: f64) -> bool\nextern sin(x: f64) -> f64\ndef sq(x: f64) -> f64"
+        );
+        let names = completions(&interp);
+        for name in &["sq", "sin", "below", ":list", "while"] {
+            assert!(names.iter().any(|n| n == name), "{}", name);
+        }
+        assert!(command(&mut interp, "ir sq").is_err());
+        assert_eq!(
+            command(&mut interp, "frobnicate").unwrap_err().to_string(),
+            "Parse error: unknown command :frobnicate; :help lists them"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let def = Outcome::Defined("def", "sq".to_owned());
+        let value = Outcome::Evaluated(9.0);
+        let error = Outcome::Failed(Error::from(ErrorKind::Runtime("say \"hi\"\n".to_owned())));
+        assert_eq!(report(Output::Quiet, &def), None);
+        assert_eq!(report(Output::Verbose, &value).unwrap(), "Evaluated to 9");
+        assert_eq!(
+            report(Output::Json, &def).unwrap(),
+            r#"{"kind":"def","name":"sq"}"#
+        );
+        assert_eq!(
+            report(Output::Json, &value).unwrap(),
+            r#"{"kind":"value","value":9}"#
+        );
+        assert_eq!(
+            report(Output::Json, &Outcome::Evaluated(f64::NAN)).unwrap(),
+            r#"{"kind":"value","value":"NaN"}"#
+        );
+        assert_eq!(
+            report(Output::Json, &error).unwrap(),
+            r#"{"kind":"error","message":"Runtime error: say \"hi\"\n"}"#
+        );
+    }
+
+    #[test]
+    fn test_jit_commands() {
+        let mut e = Engine::new();
+        run_source(&mut e, "def sq(x) x * x;").unwrap();
+        assert!(command(&mut e, "ir sq")
+            .unwrap()
+            .starts_with("define double @sq(double %x)"));
+        let asm = command(&mut e, "asm sq").unwrap();
+        assert!(asm.lines().count() >= 2, "{}", asm);
+        assert!(command(&mut e, "asm cube").is_err());
+        assert!(command(&mut e, "profile sq(2)")
+            .unwrap()
+            .starts_with("Evaluated to 4\n"));
+        assert!(command(&mut e, "profile def f(x) x").is_err());
+    }
+}
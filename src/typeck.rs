@@ -0,0 +1,1617 @@
+use std::collections::{HashMap, HashSet};
+
+use super::ast::{intern, intern_fn, Expr, FnType, Function, Pos, Prototype, StructDef, Type};
+use super::error::{Error, ErrorKind};
+use super::runtime;
+use super::toplevel::Item;
+
+fn type_error(pos: Option<Pos>, msg: String) -> Error {
+    Error::from(ErrorKind::Type(match pos {
+        Some(pos) => format!("{}: {}", pos, msg),
+        None => msg,
+    }))
+}
+
+fn pos_of(e: &Expr) -> Option<Pos> {
+    match e {
+        Expr::At(pos, _) => Some(*pos),
+        _ => None,
+    }
+}
+
+/// The value of `e` if it is a literal that can be used as an i64.
+fn integral(e: &Expr) -> Option<i64> {
+    match e {
+        // Anything below 2^63 in magnitude converts exactly.
+        Expr::Number(n) if n.fract() == 0.0 && n.abs() < 9_223_372_036_854_775_808.0 => {
+            Some(*n as i64)
+        }
+        Expr::At(_, e) => integral(e),
+        _ => None,
+    }
+}
+
+/// Converts `e` from `from` to `to` where that is implicit: integral literals can be used as
+/// i64, and bools as either number type.
+fn coerce(e: Expr, from: Type, to: Type) -> Result<Expr, String> {
+    match (integral(&e), from) {
+        _ if from == to => Ok(e),
+        (Some(n), _) if to == Type::I64 => Ok(Expr::Int(n)),
+        (_, Type::Bool) if to.is_number() => Ok(Expr::Cast(to, Box::new(e))),
+        _ => Err(format!("expected {}, found {}", to, from)),
+    }
+}
+
+/// Brings both operands to a common type.
+fn unify((l, tl): (Expr, Type), (r, tr): (Expr, Type)) -> Result<(Expr, Expr, Type), String> {
+    if tl == tr {
+        Ok((l, r, tl))
+    } else if tr == Type::Bool || (tl == Type::I64 && integral(&r).is_some()) {
+        Ok((l, coerce(r, tr, tl)?, tl))
+    } else if tl == Type::Bool || (tr == Type::I64 && integral(&l).is_some()) {
+        Ok((coerce(l, tl, tr)?, r, tr))
+    } else {
+        Err(format!("mismatched types {} and {}", tl, tr))
+    }
+}
+
+fn cast_bool((e, t): (Expr, Type)) -> (Expr, Type) {
+    if t == Type::Bool {
+        (Expr::Cast(Type::F64, Box::new(e)), Type::F64)
+    } else {
+        (e, t)
+    }
+}
+
+/// `p.f(x)` parses as a call of `p.f`, which names a function of an imported file unless `p` is
+/// a local variable. Then it calls the function in field `f` of `p`.
+fn field_call<T>(callee: &str, args: &[Expr], scope: &HashMap<String, T>) -> Option<Expr> {
+    let (var, field) = callee.split_once('.')?;
+    if !scope.contains_key(var) {
+        return None;
+    }
+    let f = Expr::Field(Box::new(Expr::Variable(var.to_owned())), field.to_owned());
+    Some(Expr::Apply(Box::new(f), args.to_vec()))
+}
+
+/// A type during inference. Function types whose parts are still being inferred are kept in a
+/// table, so that this stays `Copy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Known(Type),
+    Var(usize),
+    Fn(usize),
+}
+
+const BOOL: Ty = Ty::Known(Type::Bool);
+const F64: Ty = Ty::Known(Type::F64);
+
+fn scalar(t: Ty) -> bool {
+    match t {
+        Ty::Known(t) => t.is_scalar(),
+        Ty::Var(_) => true,
+        Ty::Fn(_) => false,
+    }
+}
+
+fn known(t: Option<Type>) -> Ty {
+    Ty::Known(t.unwrap_or(Type::F64))
+}
+
+/// Infers the types a function's prototype leaves out, and those of its loop variables, by
+/// unification. Integer literals get variables of their own that can only be solved to a number;
+/// whatever is still unsolved at the end is f64. Bools convert to numbers implicitly, so they
+/// never force a number to be a bool.
+struct Inference<'a> {
+    functions: &'a HashMap<String, Prototype>,
+    structs: &'a HashMap<&'static str, Vec<(String, Type)>>,
+    this: &'a str,
+    params: Vec<Ty>,
+    ret: Ty,
+    // What `return` returns from: the function or the innermost lambda.
+    returns: Ty,
+    scope: HashMap<String, Ty>,
+    // What each variable is solved to, if anything, and whether it has to be a number.
+    vars: Vec<(Option<Ty>, bool)>,
+    // Loop variables, in the order the `for`s are visited.
+    loops: Vec<Ty>,
+    // Likewise for `let` variables.
+    lets: Vec<Ty>,
+    // Parameter types and return type of each function type in `Ty::Fn`.
+    fns: Vec<(Vec<Ty>, Ty)>,
+    // The `Ty::Fn` of each lambda, in the order they are visited.
+    lambdas: Vec<usize>,
+    pos: Option<Pos>,
+}
+
+impl<'a> Inference<'a> {
+    fn fresh(&mut self, numeric: bool) -> Ty {
+        self.vars.push((None, numeric));
+        Ty::Var(self.vars.len() - 1)
+    }
+
+    fn resolve(&self, mut t: Ty) -> Ty {
+        while let Ty::Var(v) = t {
+            match self.vars[v].0 {
+                Some(u) => t = u,
+                None => break,
+            }
+        }
+        t
+    }
+
+    fn solution(&self, t: Ty) -> Type {
+        match self.resolve(t) {
+            Ty::Known(t) => t,
+            Ty::Var(_) => Type::F64,
+            Ty::Fn(f) => {
+                let (params, ret) = &self.fns[f];
+                let params = params.iter().map(|t| self.solution(*t)).collect();
+                Type::Fn(intern_fn(params, self.solution(*ret)))
+            }
+        }
+    }
+
+    /// Describes `t` for an error message, as it would be if inference ended now.
+    fn show(&self, t: Ty) -> String {
+        self.solution(t).to_string()
+    }
+
+    fn function_type(&mut self, params: Vec<Ty>, ret: Ty) -> Ty {
+        self.fns.push((params, ret));
+        Ty::Fn(self.fns.len() - 1)
+    }
+
+    /// Whether solving `v` to `t` would make an infinite type.
+    fn occurs(&self, v: usize, t: Ty) -> bool {
+        match self.resolve(t) {
+            Ty::Var(w) => v == w,
+            Ty::Fn(f) => {
+                let (params, ret) = &self.fns[f];
+                params.iter().chain(Some(ret)).any(|t| self.occurs(v, *t))
+            }
+            Ty::Known(_) => false,
+        }
+    }
+
+    /// The parameter types and return type of what `t` is, which has to be a function.
+    fn signature(&self, t: Ty) -> Option<(Vec<Ty>, Ty)> {
+        match self.resolve(t) {
+            Ty::Fn(f) => Some(self.fns[f].clone()),
+            Ty::Known(Type::Fn(FnType(params, ret))) => Some((
+                params.iter().map(|t| Ty::Known(*t)).collect(),
+                Ty::Known(*ret),
+            )),
+            _ => None,
+        }
+    }
+
+    fn error(&self, msg: String) -> Error {
+        type_error(self.pos, msg)
+    }
+
+    fn unify(&mut self, a: Ty, b: Ty) -> Result<(), Error> {
+        match (self.resolve(a), self.resolve(b)) {
+            (a, b) if a == b => Ok(()),
+            (Ty::Var(v), Ty::Var(w)) => {
+                self.vars[w].1 |= self.vars[v].1;
+                self.vars[v].0 = Some(Ty::Var(w));
+                Ok(())
+            }
+            (Ty::Var(v), t) | (t, Ty::Var(v)) => {
+                if self.vars[v].1 && !matches!(t, Ty::Known(t) if t.is_number()) {
+                    return Err(self.error(format!("expected a number, found {}", self.show(t))));
+                }
+                if self.occurs(v, t) {
+                    return Err(self.error("a function can not take or return itself".to_owned()));
+                }
+                self.vars[v].0 = Some(t);
+                Ok(())
+            }
+            (a, b) => match (self.signature(a), self.signature(b)) {
+                (Some((pa, ra)), Some((pb, rb))) if pa.len() == pb.len() => {
+                    for (a, b) in pa.into_iter().zip(pb) {
+                        self.unify(a, b)?;
+                    }
+                    self.unify(ra, rb)
+                }
+                _ => Err(self.error(format!(
+                    "mismatched types {} and {}",
+                    self.show(a),
+                    self.show(b)
+                ))),
+            },
+        }
+    }
+
+    /// Like `unify`, but a bool may be passed where a number is expected.
+    fn flow(&mut self, from: Ty, to: Ty) -> Result<(), Error> {
+        let number = match self.resolve(to) {
+            Ty::Known(t) => t.is_number(),
+            Ty::Var(v) => self.vars[v].1,
+            Ty::Fn(_) => false,
+        };
+        if self.resolve(from) == BOOL && number {
+            Ok(())
+        } else {
+            self.unify(from, to)
+        }
+    }
+
+    /// The type where two operands or branches meet; a bool gives way to a number on the other
+    /// side.
+    fn join(&mut self, a: Ty, b: Ty) -> Result<Ty, Error> {
+        match (self.resolve(a), self.resolve(b)) {
+            (a, b) if a == BOOL && scalar(b) => Ok(b),
+            (a, b) if b == BOOL && scalar(a) => Ok(a),
+            (a, b) => self.unify(a, b).map(|_| a),
+        }
+    }
+
+    /// Indexes can be either kind of number.
+    fn index(&mut self, array: &Expr, index: &Expr) -> Result<(), Error> {
+        let a = self.expr(array)?;
+        self.unify(a, Ty::Known(Type::Array))?;
+        match self.expr(index)? {
+            Ty::Var(v) => self.vars[v].1 = true,
+            t if !scalar(t) => {
+                return Err(self.error(format!("expected a number, found {}", self.show(t))))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, params: Vec<Ty>, ret: Ty, args: &[Expr]) -> Result<Ty, Error> {
+        if params.len() != args.len() {
+            return Err(self.error("incorrect # arguments passed".to_owned()));
+        }
+        for (arg, param) in args.iter().zip(params) {
+            let t = self.expr(arg)?;
+            self.flow(t, param)?;
+        }
+        Ok(ret)
+    }
+
+    /// Calls a function value of type `t`. One that is not known yet takes its shape from the
+    /// call.
+    fn apply(&mut self, t: Ty, args: &[Expr]) -> Result<Ty, Error> {
+        if let Ty::Var(_) = self.resolve(t) {
+            let params = args.iter().map(|_| self.fresh(false)).collect::<Vec<_>>();
+            let ret = self.fresh(false);
+            let f = self.function_type(params, ret);
+            self.unify(t, f)?;
+        }
+        match self.signature(t) {
+            Some((params, ret)) => self.call(params, ret, args),
+            None => Err(self.error(format!("expected a function, found {}", self.show(t)))),
+        }
+    }
+
+    fn fields(&self, name: &str) -> Result<&'a [(String, Type)], Error> {
+        let structs = self.structs;
+        match structs.get(name) {
+            Some(fields) => Ok(fields),
+            None => Err(self.error(format!("unknown struct: {}", name))),
+        }
+    }
+
+    /// The type of `field` in a value of type `t`. A value whose type is still unknown is taken
+    /// to be the only struct with such a field.
+    fn field(&mut self, t: Ty, field: &str) -> Result<Ty, Error> {
+        let name = match self.resolve(t) {
+            Ty::Known(Type::Struct(name)) => name,
+            Ty::Var(_) => {
+                let mut candidates = self
+                    .structs
+                    .iter()
+                    .filter(|(_, fields)| fields.iter().any(|(f, _)| f == field));
+                match (candidates.next(), candidates.next()) {
+                    (Some((name, _)), None) => {
+                        let name = *name;
+                        self.unify(t, Ty::Known(Type::Struct(name)))?;
+                        name
+                    }
+                    (None, _) => return Err(self.error(format!("no struct has field {}", field))),
+                    _ => {
+                        return Err(self.error(format!(
+                            "cannot infer which struct has field {}; annotate its type",
+                            field
+                        )))
+                    }
+                }
+            }
+            t => return Err(self.error(format!("{} has no field {}", self.show(t), field))),
+        };
+        match self.fields(name)?.iter().find(|(f, _)| f == field) {
+            Some((_, t)) => Ok(Ty::Known(*t)),
+            None => Err(self.error(format!("{} has no field {}", name, field))),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<Ty, Error> {
+        match e {
+            Expr::Number(_) if integral(e).is_some() => Ok(self.fresh(true)),
+            Expr::Number(_) => Ok(Ty::Known(Type::F64)),
+            Expr::Str(_) => Ok(Ty::Known(Type::Str)),
+            Expr::Int(_) => Ok(Ty::Known(Type::I64)),
+            Expr::Variable(name) => match self.scope.get(name) {
+                Some(t) => Ok(*t),
+                // Functions are values too.
+                None if name == self.this => Ok(self.function_type(self.params.clone(), self.ret)),
+                None => match self.functions.get(name) {
+                    Some(Prototype(_, _, types, ret)) => {
+                        let params = types.iter().map(|t| t.unwrap_or(Type::F64)).collect();
+                        Ok(Ty::Known(Type::Fn(intern_fn(
+                            params,
+                            ret.unwrap_or(Type::F64),
+                        ))))
+                    }
+                    None => Err(self.error(format!("unknown variable name: {}", name))),
+                },
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let l = self.expr(lhs)?;
+                let r = self.expr(rhs)?;
+                let t = match self.join(l, r)? {
+                    BOOL => F64,
+                    Ty::Var(v) => {
+                        self.vars[v].1 = true;
+                        Ty::Var(v)
+                    }
+                    t if !scalar(t) => {
+                        return Err(self.error(format!("expected a number, found {}", self.show(t))))
+                    }
+                    t => t,
+                };
+                Ok(if *op == '<' { BOOL } else { t })
+            }
+            Expr::Call(callee, args) if field_call(callee, args, &self.scope).is_some() => {
+                self.expr(&field_call(callee, args, &self.scope).unwrap())
+            }
+            Expr::Call(callee, args) => match self.scope.get(callee) {
+                Some(t) => self.apply(*t, args),
+                None if callee == self.this => self.call(self.params.clone(), self.ret, args),
+                None => match self.functions.get(callee) {
+                    Some(Prototype(_, _, types, ret)) => {
+                        let params = types.iter().map(|t| known(*t)).collect();
+                        self.call(params, known(*ret), args)
+                    }
+                    None => Err(self.error(format!("unknown function: {}", callee))),
+                },
+            },
+            Expr::Apply(callee, args) => {
+                let t = self.expr(callee)?;
+                self.apply(t, args)
+            }
+            Expr::Lambda(proto, body) => {
+                let Prototype(_, args, types, ret) = proto.as_ref();
+                let params = types
+                    .iter()
+                    .map(|t| match t {
+                        Some(t) => Ty::Known(*t),
+                        None => self.fresh(false),
+                    })
+                    .collect::<Vec<_>>();
+                let ret = match ret {
+                    Some(t) => Ty::Known(*t),
+                    None => self.fresh(false),
+                };
+                let f = self.fns.len();
+                self.function_type(params.clone(), ret);
+                self.lambdas.push(f);
+
+                let olds = args
+                    .iter()
+                    .zip(params)
+                    .map(|(arg, t)| (arg, self.scope.insert(arg.clone(), t)))
+                    .collect::<Vec<_>>();
+                let returns = std::mem::replace(&mut self.returns, ret);
+                let ret = self.expr(body).and_then(|t| self.flow(t, ret));
+                self.returns = returns;
+                for (arg, old) in olds.into_iter().rev() {
+                    match old {
+                        Some(t) => self.scope.insert(arg.clone(), t),
+                        None => self.scope.remove(arg),
+                    };
+                }
+                ret.map(|_| Ty::Fn(f))
+            }
+            Expr::If(cond, then, els) => {
+                self.expr(cond)?;
+                let then = self.expr(then)?;
+                let els = self.expr(els)?;
+                self.join(then, els)
+            }
+            Expr::While(cond, body) => {
+                self.expr(cond)?;
+                self.expr(body)?;
+                Ok(F64)
+            }
+            // Jumps have no value, so they fit anywhere.
+            Expr::Break | Expr::Continue => Ok(self.fresh(false)),
+            Expr::Return(value) => {
+                let t = self.expr(value)?;
+                self.flow(t, self.returns)?;
+                Ok(self.fresh(false))
+            }
+            Expr::For(var_name, start, end, step, body) => {
+                let index = self.loops.len();
+                self.loops.push(Ty::Known(Type::F64));
+                let t = match self.expr(start)? {
+                    t if self.resolve(t) == BOOL => Ty::Known(Type::F64),
+                    t => t,
+                };
+                self.loops[index] = t;
+                let old = self.scope.insert(var_name.clone(), t);
+
+                let ret = (|| -> Result<(), Error> {
+                    self.expr(body)?;
+                    if let Some(step) = step.as_ref() {
+                        let s = self.expr(step)?;
+                        self.flow(s, t)?;
+                    }
+                    self.expr(end)?;
+                    Ok(())
+                })();
+
+                match old {
+                    Some(t) => self.scope.insert(var_name.clone(), t),
+                    None => self.scope.remove(var_name),
+                };
+
+                ret.map(|_| Ty::Known(Type::F64))
+            }
+            Expr::Let(name, value, body) => {
+                let index = self.lets.len();
+                self.lets.push(Ty::Known(Type::F64));
+                let t = self.expr(value)?;
+                self.lets[index] = t;
+                let old = self.scope.insert(name.clone(), t);
+                let ret = self.expr(body);
+                match old {
+                    Some(t) => self.scope.insert(name.clone(), t),
+                    None => self.scope.remove(name),
+                };
+                ret
+            }
+            Expr::Array(elements) => {
+                for e in elements {
+                    let t = self.expr(e)?;
+                    self.flow(t, F64)?;
+                }
+                Ok(Ty::Known(Type::Array))
+            }
+            Expr::Index(array, index) => {
+                self.index(array, index)?;
+                Ok(F64)
+            }
+            Expr::SetIndex(array, index, value) => {
+                self.index(array, index)?;
+                let t = self.expr(value)?;
+                self.flow(t, F64)?;
+                Ok(F64)
+            }
+            Expr::Struct(name, fields, base) => {
+                let t = Ty::Known(Type::Struct(intern(name)));
+                for (field, value) in fields {
+                    let want = self.field(t, field)?;
+                    let actual = self.expr(value)?;
+                    self.flow(actual, want)?;
+                }
+                if let Some(base) = base {
+                    let b = self.expr(base)?;
+                    self.unify(b, t)?;
+                }
+                Ok(t)
+            }
+            Expr::Field(value, field) => {
+                let t = self.expr(value)?;
+                self.field(t, field)
+            }
+            Expr::Cast(t, inner) => {
+                let actual = self.expr(inner)?;
+                self.flow(actual, Ty::Known(*t))?;
+                Ok(Ty::Known(*t))
+            }
+            Expr::At(pos, inner) => {
+                let outer = self.pos.replace(*pos);
+                let ret = self.expr(inner);
+                self.pos = outer;
+                ret
+            }
+        }
+    }
+}
+
+/// The types inference solves a function's loop variables, let variables and lambdas to, in
+/// the order the checker comes across them.
+struct Solved {
+    loops: Vec<Type>,
+    lets: Vec<Type>,
+    lambdas: Vec<&'static FnType>,
+}
+
+/// Infers the types a function's prototype leaves out and checks it against the rest, making
+/// the types explicit for codegen: literals used as integers become `Expr::Int` and implicit
+/// conversions become `Expr::Cast`. Comparisons yield `bool`, which converts to 0 or 1 where a
+/// number is expected, so unannotated programs mean what they always did.
+pub(crate) struct Checker {
+    functions: HashMap<String, Prototype>,
+    // Which of them are externs.
+    externs: HashSet<String>,
+    structs: HashMap<&'static str, Vec<(String, Type)>>,
+    scope: HashMap<String, Type>,
+    // The inferred types of the current function's loop variables, in order.
+    loops: std::vec::IntoIter<Type>,
+    // Likewise for its `let` variables.
+    lets: std::vec::IntoIter<Type>,
+    // Likewise for the types of its lambdas.
+    lambdas: std::vec::IntoIter<&'static FnType>,
+    // How many loops the current expression is in the body of.
+    loop_depth: usize,
+    // The type `return` converts to.
+    returns: Type,
+    pos: Option<Pos>,
+    // Only the JIT can represent strings, arrays, structs and closures, or jump out of loops.
+    jit: bool,
+    // Whether elaborated expressions keep their positions, for debug information.
+    pub(crate) positions: bool,
+}
+
+impl Checker {
+    pub(crate) fn new() -> Self {
+        Checker {
+            functions: HashMap::new(),
+            externs: HashSet::new(),
+            structs: HashMap::new(),
+            scope: HashMap::new(),
+            loops: Vec::new().into_iter(),
+            lets: Vec::new().into_iter(),
+            lambdas: Vec::new().into_iter(),
+            loop_depth: 0,
+            returns: Type::F64,
+            pos: None,
+            jit: false,
+            positions: false,
+        }
+    }
+
+    /// A checker for the JIT, which also accepts strings, arrays, structs and closures.
+    pub(crate) fn for_jit() -> Self {
+        Checker {
+            jit: true,
+            ..Checker::new()
+        }
+    }
+
+    fn located<T>(&self, r: Result<T, String>) -> Result<T, Error> {
+        r.map_err(|msg| type_error(self.pos, msg))
+    }
+
+    /// Errors if `p` redefines a function with a different signature, since the backends
+    /// compile callers against the signature they saw.
+    fn redefines(&self, p: &Prototype) -> Result<(), Error> {
+        let signature = |Prototype(_, _, types, ret): &Prototype| {
+            let params = types.iter().map(|t| t.unwrap_or(Type::F64)).collect();
+            Type::Fn(intern_fn(params, ret.unwrap_or(Type::F64)))
+        };
+        match self.functions.get(&p.0) {
+            Some(old) if signature(old) != signature(p) => Err(type_error(
+                None,
+                format!("{} is already defined as {}", p.0, signature(old)),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn supported(&self, t: Type) -> Result<(), String> {
+        match t {
+            // The other backends compute with doubles, which do not overflow the way i64 does.
+            Type::I64 if !self.jit => Err("i64 is only supported by the JIT backend".to_owned()),
+            Type::Str if !self.jit => {
+                Err("strings are only supported by the JIT backend".to_owned())
+            }
+            Type::Array if !self.jit => {
+                Err("arrays are only supported by the JIT backend".to_owned())
+            }
+            Type::Struct(_) if !self.jit => {
+                Err("structs are only supported by the JIT backend".to_owned())
+            }
+            Type::Struct(name) if !self.structs.contains_key(name) => {
+                Err(format!("unknown type: {}", name))
+            }
+            Type::Fn(_) if !self.jit => {
+                Err("closures are only supported by the JIT backend".to_owned())
+            }
+            Type::Fn(FnType(params, ret)) => params
+                .iter()
+                .chain(Some(ret))
+                .try_for_each(|t| self.supported(*t)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like `supported`, for types written out in the source. Elsewhere comparisons are still
+    /// bools, which are 0 or 1 wherever they turn into numbers, but a `bool` annotation would
+    /// be lost on the doubles those backends pass around.
+    fn annotation(&self, t: Type) -> Result<(), String> {
+        match t {
+            Type::Bool if !self.jit => Err("bool is only supported by the JIT backend".to_owned()),
+            t => self.supported(t),
+        }
+    }
+
+    /// Records a struct's fields. Redefining a struct is only allowed if nothing changes, since
+    /// code compiled against the old layout would otherwise break.
+    pub(crate) fn define_struct(
+        &mut self,
+        StructDef(name, fields): &StructDef,
+    ) -> Result<(), Error> {
+        let name = intern(name);
+        if !self.jit {
+            return Err(type_error(
+                None,
+                "structs are only supported by the JIT backend".to_owned(),
+            ));
+        }
+        for (i, (field, t)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(f, _)| f == field) {
+                return Err(type_error(
+                    None,
+                    format!("duplicate field {} in {}", field, name),
+                ));
+            }
+            self.supported(*t).map_err(|msg| type_error(None, msg))?;
+        }
+        match self.structs.get(name) {
+            Some(old) if old != fields => Err(type_error(
+                None,
+                format!("struct {} is already defined", name),
+            )),
+            _ => {
+                self.structs.insert(name, fields.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns `p` with every type filled in from the runtime builtin of the same name; anything
+    /// else takes and returns f64. Annotations have to agree.
+    pub(crate) fn declare(&mut self, p: &Prototype) -> Result<Prototype, Error> {
+        let Prototype(name, args, types, ret) = p;
+        let (params, result) = match runtime::lookup(name) {
+            Some(builtin) if builtin.arity() == args.len() => builtin.signature(),
+            _ => (vec![Type::F64; args.len()], Type::F64),
+        };
+        let agrees = |t: &Option<Type>, want: Type| t.is_none() || *t == Some(want);
+        if !types.iter().zip(&params).all(|(t, p)| agrees(t, *p)) || !agrees(ret, result) {
+            let params = params.iter().map(Type::to_string).collect::<Vec<_>>();
+            return Err(type_error(
+                None,
+                format!(
+                    "extern {} must take ({}) and return {}",
+                    name,
+                    params.join(", "),
+                    result
+                ),
+            ));
+        }
+        for t in params.iter().chain(Some(&result)) {
+            self.supported(*t).map_err(|msg| type_error(None, msg))?;
+        }
+        let p = Prototype(
+            name.clone(),
+            args.clone(),
+            params.into_iter().map(Some).collect(),
+            Some(result),
+        );
+        self.redefines(&p)?;
+        self.functions.insert(name.clone(), p.clone());
+        self.externs.insert(name.clone());
+        Ok(p)
+    }
+
+    /// The signatures of the `def`s and `extern`s seen so far, by name.
+    pub(crate) fn listing(&self) -> Vec<String> {
+        let mut names = self.functions.keys().collect::<Vec<_>>();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let Prototype(_, args, types, ret) = &self.functions[name];
+                let params = args
+                    .iter()
+                    .zip(types)
+                    .map(|(arg, t)| format!("{}: {}", arg, t.unwrap_or(Type::F64)))
+                    .collect::<Vec<_>>();
+                format!(
+                    "{} {}({}) -> {}",
+                    if self.externs.contains(name) {
+                        "extern"
+                    } else {
+                        "def"
+                    },
+                    name,
+                    params.join(" "),
+                    ret.unwrap_or(Type::F64)
+                )
+            })
+            .collect()
+    }
+
+    fn infer(&self, proto: &Prototype, body: &Expr) -> Result<(Prototype, Solved), Error> {
+        let Prototype(name, args, types, ret) = proto;
+        for t in types.iter().chain(Some(ret)).flatten() {
+            self.annotation(*t).map_err(|msg| type_error(None, msg))?;
+        }
+        let mut inf = Inference {
+            functions: &self.functions,
+            structs: &self.structs,
+            this: name,
+            params: Vec::new(),
+            ret: Ty::Known(Type::F64),
+            returns: Ty::Known(Type::F64),
+            scope: HashMap::new(),
+            vars: Vec::new(),
+            loops: Vec::new(),
+            lets: Vec::new(),
+            fns: Vec::new(),
+            lambdas: Vec::new(),
+            pos: None,
+        };
+        for t in types {
+            let t = match t {
+                Some(t) => Ty::Known(*t),
+                None => inf.fresh(false),
+            };
+            inf.params.push(t);
+        }
+        inf.ret = match ret {
+            Some(t) => Ty::Known(*t),
+            None => inf.fresh(false),
+        };
+        // The REPL prints top-level expressions as f64.
+        if !name.is_empty() {
+            inf.returns = inf.ret;
+        }
+        inf.scope = args.iter().cloned().zip(inf.params.clone()).collect();
+
+        let t = inf.expr(body)?;
+        inf.pos = pos_of(body);
+        inf.flow(t, inf.ret)?;
+
+        let types = inf
+            .params
+            .iter()
+            .map(|t| Some(inf.solution(*t)))
+            .collect::<Vec<_>>();
+        let ret = Some(inf.solution(inf.ret));
+        // Inferred types have to be supported as much as annotated ones.
+        for t in types.iter().chain(Some(&ret)).flatten() {
+            self.supported(*t)
+                .map_err(|msg| type_error(pos_of(body), msg))?;
+        }
+        let loops = inf.loops.iter().map(|t| inf.solution(*t)).collect();
+        let lets = inf.lets.iter().map(|t| inf.solution(*t)).collect();
+        let lambdas = inf
+            .lambdas
+            .iter()
+            .map(|f| match inf.solution(Ty::Fn(*f)) {
+                Type::Fn(sig) => sig,
+                _ => unreachable!(),
+            })
+            .collect();
+        Ok((
+            Prototype(name.clone(), args.clone(), types, ret),
+            Solved {
+                loops,
+                lets,
+                lambdas,
+            },
+        ))
+    }
+
+    /// Returns `f` with its types inferred and its body elaborated. The bodies of top-level
+    /// expressions are converted to f64, the type the REPL prints.
+    pub(crate) fn function(&mut self, Function(proto, body): &Function) -> Result<Function, Error> {
+        let (proto, solved) = self.infer(proto, body)?;
+        let Prototype(name, args, types, ret) = &proto;
+        let ret = ret.unwrap_or(Type::F64);
+        let anonymous = name.is_empty();
+        if !anonymous {
+            self.redefines(&proto)?;
+        }
+
+        // Visible to its own body, for recursion.
+        let previous = if anonymous {
+            None
+        } else {
+            self.functions.insert(name.clone(), proto.clone())
+        };
+        self.scope = args
+            .iter()
+            .cloned()
+            .zip(types.iter().map(|t| t.unwrap_or(Type::F64)))
+            .collect();
+        self.loops = solved.loops.into_iter();
+        self.lets = solved.lets.into_iter();
+        self.lambdas = solved.lambdas.into_iter();
+        self.loop_depth = 0;
+        self.returns = if anonymous { Type::F64 } else { ret };
+        self.pos = pos_of(body);
+
+        let body = if anonymous {
+            self.expr(body, None).and_then(|(e, t)| match t {
+                Type::F64 => Ok(e),
+                t if !t.is_scalar() => self.located(Err(format!("expected a number, found {}", t))),
+                _ => Ok(Expr::Cast(Type::F64, Box::new(e))),
+            })
+        } else {
+            self.expr(body, Some(ret))
+                .and_then(|(e, t)| self.located(coerce(e, t, ret)))
+        };
+
+        match body {
+            Ok(body) => {
+                self.externs.remove(name);
+                Ok(Function(Box::new(proto), Box::new(body)))
+            }
+            Err(e) => {
+                match previous {
+                    Some(p) => self.functions.insert(name.clone(), p),
+                    None => self.functions.remove(name),
+                };
+                Err(e)
+            }
+        }
+    }
+
+    fn cond(&mut self, e: &Expr) -> Result<Expr, Error> {
+        match self.expr(e, None)? {
+            (_, t) if !t.is_scalar() => {
+                self.located(Err(format!("expected a condition, found {}", t)))
+            }
+            (e, _) => Ok(e),
+        }
+    }
+
+    /// Returns the elaborated array and index; bool indexes become f64.
+    fn index(&mut self, array: &Expr, index: &Expr) -> Result<(Box<Expr>, Box<Expr>), Error> {
+        let (array, t) = self.expr(array, None)?;
+        let array = self.located(coerce(array, t, Type::Array))?;
+        let (index, t) = cast_bool(self.expr(index, None)?);
+        if !t.is_number() {
+            return self.located(Err(format!("expected a number, found {}", t)));
+        }
+        Ok((Box::new(array), Box::new(index)))
+    }
+
+    /// Elaborates arguments, converting them to the parameter types.
+    fn args(&mut self, args: &[Expr], types: &[Type]) -> Result<Vec<Expr>, Error> {
+        if types.len() != args.len() {
+            return self.located(Err("incorrect # arguments passed".to_owned()));
+        }
+        args.iter()
+            .zip(types)
+            .map(|(arg, t)| {
+                self.expr(arg, Some(*t))
+                    .and_then(|(e, actual)| self.located(coerce(e, actual, *t)))
+            })
+            .collect()
+    }
+
+    fn apply(&mut self, callee: Expr, t: Type, args: &[Expr]) -> Result<(Expr, Type), Error> {
+        match t {
+            Type::Fn(FnType(params, ret)) => {
+                let args = self.args(args, params)?;
+                Ok((Expr::Apply(Box::new(callee), args), *ret))
+            }
+            t => self.located(Err(format!("expected a function, found {}", t))),
+        }
+    }
+
+    /// `want` is the type the context expects, used only to pick the type of literals.
+    fn expr(&mut self, e: &Expr, want: Option<Type>) -> Result<(Expr, Type), Error> {
+        match e {
+            Expr::Number(n) => match integral(e) {
+                Some(i) if want == Some(Type::I64) => Ok((Expr::Int(i), Type::I64)),
+                _ => Ok((Expr::Number(*n), Type::F64)),
+            },
+            Expr::Str(s) => {
+                self.located(self.supported(Type::Str))?;
+                Ok((Expr::Str(s.clone()), Type::Str))
+            }
+            Expr::Int(n) => Ok((Expr::Int(*n), Type::I64)),
+            Expr::Variable(name) => match self.scope.get(name) {
+                Some(t) => Ok((e.clone(), *t)),
+                None => match self.functions.get(name) {
+                    Some(Prototype(_, _, types, ret)) => {
+                        let params = types.iter().map(|t| t.unwrap_or(Type::F64)).collect();
+                        let t = Type::Fn(intern_fn(params, ret.unwrap_or(Type::F64)));
+                        self.located(self.supported(t))?;
+                        Ok((e.clone(), t))
+                    }
+                    None => self.located(Err(format!("unknown variable name: {}", name))),
+                },
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let want = match op {
+                    '<' => None,
+                    _ => want.filter(|t| *t != Type::Bool),
+                };
+                let l = self.expr(lhs, want)?;
+                let r = self.expr(rhs, Some(l.1))?;
+                let (l, r, t) = self.located(unify(l, r))?;
+                if !t.is_scalar() {
+                    return self.located(Err(format!("expected a number, found {}", t)));
+                }
+                // Bools are compared and added as numbers.
+                let (r, _) = cast_bool((r, t));
+                let (l, t) = cast_bool((l, t));
+                let ret = match op {
+                    '+' | '-' | '*' => t,
+                    '<' => Type::Bool,
+                    _ => return self.located(Err(format!("invalid binary operator: {}", op))),
+                };
+                Ok((Expr::Binary(*op, Box::new(l), Box::new(r)), ret))
+            }
+            Expr::Call(callee, args) if field_call(callee, args, &self.scope).is_some() => {
+                self.expr(&field_call(callee, args, &self.scope).unwrap(), want)
+            }
+            // Local variables shadow functions.
+            Expr::Call(callee, args) if self.scope.contains_key(callee) => {
+                let t = self.scope[callee];
+                self.apply(Expr::Variable(callee.clone()), t, args)
+            }
+            Expr::Call(callee, args) => {
+                let Prototype(_, _, types, ret) = match self.functions.get(callee) {
+                    Some(proto) => proto.clone(),
+                    None => return self.located(Err(format!("unknown function: {}", callee))),
+                };
+                let types = types
+                    .iter()
+                    .map(|t| t.unwrap_or(Type::F64))
+                    .collect::<Vec<_>>();
+                let args = self.args(args, &types)?;
+                Ok((Expr::Call(callee.clone(), args), ret.unwrap_or(Type::F64)))
+            }
+            Expr::Apply(callee, args) => {
+                let (callee, t) = self.expr(callee, None)?;
+                self.apply(callee, t, args)
+            }
+            Expr::Lambda(proto, body) => {
+                let sig = self
+                    .lambdas
+                    .next()
+                    .expect("lambdas are inferred before they are elaborated");
+                let t = Type::Fn(sig);
+                self.located(self.supported(t))?;
+                let FnType(params, ret) = sig;
+                let args = &proto.1;
+                // Loops outside the lambda can't be jumped out of from inside.
+                let loop_depth = std::mem::take(&mut self.loop_depth);
+                let returns = std::mem::replace(&mut self.returns, *ret);
+
+                let olds = args
+                    .iter()
+                    .zip(params)
+                    .map(|(arg, t)| (arg, self.scope.insert(arg.clone(), *t)))
+                    .collect::<Vec<_>>();
+                let body = self
+                    .expr(body, Some(*ret))
+                    .and_then(|(e, actual)| self.located(coerce(e, actual, *ret)));
+                for (arg, old) in olds.into_iter().rev() {
+                    match old {
+                        Some(t) => self.scope.insert(arg.clone(), t),
+                        None => self.scope.remove(arg),
+                    };
+                }
+                self.loop_depth = loop_depth;
+                self.returns = returns;
+
+                let types = params.iter().map(|t| Some(*t)).collect();
+                let proto = Prototype(String::new(), args.clone(), types, Some(*ret));
+                Ok((Expr::Lambda(Box::new(proto), Box::new(body?)), t))
+            }
+            Expr::If(cond, then, els) => {
+                // Numbers are true when nonzero.
+                let cond = self.cond(cond)?;
+                let then = self.expr(then, want)?;
+                let els = self.expr(els, want.or(Some(then.1)))?;
+                // A branch that jumps takes the type of the other.
+                let (then, els, t) = if then.0.jumps() {
+                    (then.0, els.0, els.1)
+                } else if els.0.jumps() {
+                    (then.0, els.0, then.1)
+                } else {
+                    self.located(unify(then, els))?
+                };
+                Ok((Expr::If(Box::new(cond), Box::new(then), Box::new(els)), t))
+            }
+            Expr::While(cond, body) => {
+                if !self.jit {
+                    return self.located(Err(
+                        "while loops are only supported by the JIT backend".to_owned()
+                    ));
+                }
+                let cond = self.cond(cond)?;
+                self.loop_depth += 1;
+                let body = self.expr(body, None);
+                self.loop_depth -= 1;
+                Ok((Expr::While(Box::new(cond), Box::new(body?.0)), Type::F64))
+            }
+            Expr::Break | Expr::Continue => {
+                let name = if *e == Expr::Break {
+                    "break"
+                } else {
+                    "continue"
+                };
+                if !self.jit {
+                    return self.located(Err(format!(
+                        "{} is only supported by the JIT backend",
+                        name
+                    )));
+                }
+                if self.loop_depth == 0 {
+                    return self.located(Err(format!("{} outside of a loop", name)));
+                }
+                Ok((e.clone(), want.unwrap_or(Type::F64)))
+            }
+            Expr::Return(value) => {
+                if !self.jit {
+                    return self
+                        .located(Err("return is only supported by the JIT backend".to_owned()));
+                }
+                let ret = self.returns;
+                let (value, t) = self.expr(value, Some(ret))?;
+                let value = self.located(coerce(value, t, ret))?;
+                Ok((Expr::Return(Box::new(value)), want.unwrap_or(Type::F64)))
+            }
+            Expr::For(var_name, start, end, step, body) => {
+                let t = self.loops.next().unwrap_or(Type::F64);
+                let (start, actual) = self.expr(start, Some(t))?;
+                let (start, actual) = cast_bool((start, actual));
+                let start = self.located(coerce(start, actual, t))?;
+                if !t.is_number() {
+                    return self.located(Err(format!("expected a number, found {}", t)));
+                }
+                let old = self.scope.insert(var_name.clone(), t);
+
+                let ret = (|| -> Result<Expr, Error> {
+                    self.loop_depth += 1;
+                    let body = self.expr(body, None);
+                    self.loop_depth -= 1;
+                    let (body, _) = body?;
+                    let step = match step.as_ref() {
+                        Some(step) => {
+                            let (e, actual) = self.expr(step, Some(t))?;
+                            Some(self.located(coerce(e, actual, t))?)
+                        }
+                        None => None,
+                    };
+                    let end = self.cond(end)?;
+                    Ok(Expr::For(
+                        var_name.clone(),
+                        Box::new(start),
+                        Box::new(end),
+                        Box::new(step),
+                        Box::new(body),
+                    ))
+                })();
+
+                match old {
+                    Some(t) => self.scope.insert(var_name.clone(), t),
+                    None => self.scope.remove(var_name),
+                };
+
+                // for expr always returns 0.0.
+                ret.map(|e| (e, Type::F64))
+            }
+            Expr::Let(name, value, body) => {
+                let t = self.lets.next().unwrap_or(Type::F64);
+                let (value, actual) = self.expr(value, Some(t))?;
+                let value = self.located(coerce(value, actual, t))?;
+                let old = self.scope.insert(name.clone(), t);
+                let ret = self.expr(body, want);
+                match old {
+                    Some(t) => self.scope.insert(name.clone(), t),
+                    None => self.scope.remove(name),
+                };
+                let (body, t) = ret?;
+                Ok((Expr::Let(name.clone(), Box::new(value), Box::new(body)), t))
+            }
+            Expr::Array(elements) => {
+                self.located(self.supported(Type::Array))?;
+                let elements = elements
+                    .iter()
+                    .map(|e| {
+                        self.expr(e, Some(Type::F64))
+                            .and_then(|(e, t)| self.located(coerce(e, t, Type::F64)))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok((Expr::Array(elements), Type::Array))
+            }
+            Expr::Index(array, index) => {
+                let (array, index) = self.index(array, index)?;
+                Ok((Expr::Index(array, index), Type::F64))
+            }
+            Expr::SetIndex(array, index, value) => {
+                let (array, index) = self.index(array, index)?;
+                let (value, t) = self.expr(value, Some(Type::F64))?;
+                let value = self.located(coerce(value, t, Type::F64))?;
+                Ok((Expr::SetIndex(array, index, Box::new(value)), Type::F64))
+            }
+            Expr::Struct(name, fields, base) => {
+                let t = Type::Struct(intern(name));
+                self.located(self.supported(t))?;
+                let defined = self.structs[name.as_str()].clone();
+                let mut values = Vec::new();
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if fields[..i].iter().any(|(f, _)| f == field) {
+                        return self.located(Err(format!("field {} is given twice", field)));
+                    }
+                    let want = match defined.iter().find(|(f, _)| f == field) {
+                        Some((_, t)) => *t,
+                        None => {
+                            return self.located(Err(format!("{} has no field {}", name, field)))
+                        }
+                    };
+                    let (value, actual) = self.expr(value, Some(want))?;
+                    values.push((field.clone(), self.located(coerce(value, actual, want))?));
+                }
+                let base = match base {
+                    Some(base) => {
+                        let (base, actual) = self.expr(base, Some(t))?;
+                        Some(Box::new(self.located(coerce(base, actual, t))?))
+                    }
+                    None => {
+                        if let Some((f, _)) = defined
+                            .iter()
+                            .find(|(f, _)| !fields.iter().any(|(g, _)| g == f))
+                        {
+                            return self.located(Err(format!("missing field {} in {}", f, name)));
+                        }
+                        None
+                    }
+                };
+                Ok((Expr::Struct(name.clone(), values, base), t))
+            }
+            Expr::Field(value, field) => {
+                let (value, t) = self.expr(value, None)?;
+                let found = match t {
+                    Type::Struct(name) => self.structs[name].iter().find(|(f, _)| f == field),
+                    _ => None,
+                };
+                match found {
+                    Some((_, ft)) => Ok((Expr::Field(Box::new(value), field.clone()), *ft)),
+                    None => self.located(Err(format!("{} has no field {}", t, field))),
+                }
+            }
+            Expr::Cast(t, inner) => {
+                self.located(self.annotation(*t))?;
+                let (inner, actual) = self.expr(inner, Some(*t))?;
+                Ok((self.located(coerce(inner, actual, *t))?, *t))
+            }
+            // Positions go no further unless there is debug information to make.
+            Expr::At(pos, inner) => {
+                let outer = self.pos.replace(*pos);
+                let ret = self.expr(inner, want);
+                self.pos = outer;
+                match ret {
+                    Ok((e, t)) if self.positions => Ok((Expr::At(*pos, Box::new(e)), t)),
+                    ret => ret,
+                }
+            }
+        }
+    }
+}
+
+/// Checks and elaborates a whole program, for the backends that compile one in one go.
+pub(crate) fn check_items(items: &[Item]) -> Result<Vec<Item>, Error> {
+    check_items_with(Checker::new(), items)
+}
+
+pub(crate) fn check_items_with(mut checker: Checker, items: &[Item]) -> Result<Vec<Item>, Error> {
+    items
+        .iter()
+        .map(|item| match item {
+            Item::Definition(f) => checker.function(f).map(Item::Definition),
+            Item::Extern(p) => checker.declare(p).map(Item::Extern),
+            Item::TopLevel(f) => checker.function(f).map(Item::TopLevel),
+            Item::Struct(s) => checker.define_struct(s).map(|_| Item::Struct(s.clone())),
+            Item::Import(path) => Err(type_error(None, format!("unresolved import: {}", path))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::toplevel;
+    use super::*;
+
+    fn check(src: &str) -> Result<Vec<Item>, Error> {
+        check_items(&toplevel::parse_source(src).unwrap())
+    }
+
+    fn check_jit(src: &str) -> Result<Vec<Item>, Error> {
+        check_items_with(Checker::for_jit(), &toplevel::parse_source(src).unwrap())
+    }
+
+    fn function(item: &Item) -> &Function {
+        match item {
+            Item::Definition(f) | Item::TopLevel(f) => f,
+            Item::Extern(_) | Item::Struct(_) | Item::Import(_) => panic!("not a function"),
+        }
+    }
+
+    fn body(item: &Item) -> &Expr {
+        &function(item).1
+    }
+
+    fn signature(item: &Item) -> (Vec<Type>, Type) {
+        let Prototype(_, _, types, ret) = function(item).0.as_ref();
+        (types.iter().map(|t| t.unwrap()).collect(), ret.unwrap())
+    }
+
+    #[test]
+    fn test_elaborate() {
+        let items = check_jit("def f(n: i64) -> i64 n * 2; f(3);").unwrap();
+        assert_eq!(
+            body(&items[0]),
+            &Expr::Binary(
+                '*',
+                Box::new(Expr::Variable("n".to_owned())),
+                Box::new(Expr::Int(2))
+            )
+        );
+        // Top-level results are printed as doubles.
+        assert_eq!(
+            body(&items[1]),
+            &Expr::Cast(
+                Type::F64,
+                Box::new(Expr::Call("f".to_owned(), vec![Expr::Int(3)]))
+            )
+        );
+
+        // Comparisons are bools, which still work as numbers.
+        let items = check("def f(x) (x < 1) + 1;").unwrap();
+        assert_eq!(
+            body(&items[0]),
+            &Expr::Binary(
+                '+',
+                Box::new(Expr::Cast(
+                    Type::F64,
+                    Box::new(Expr::Binary(
+                        '<',
+                        Box::new(Expr::Variable("x".to_owned())),
+                        Box::new(Expr::Number(1.0))
+                    ))
+                )),
+                Box::new(Expr::Number(1.0))
+            )
+        );
+    }
+
+    #[test]
+    fn test_infer() {
+        let items = check_jit(
+            "def fact(n: i64) -> i64 if n < 2 then 1 else n * fact(n - 1);
+             def g(n) fact(n) + 1;
+             def h(x) x < 1;
+             def k(x y) if h(y) then x * 2 else y;
+             def count(n: i64) for i = 0, i < n in 0;",
+        )
+        .unwrap();
+        assert_eq!(signature(&items[1]), (vec![Type::I64], Type::I64));
+        assert_eq!(signature(&items[2]), (vec![Type::F64], Type::Bool));
+        assert_eq!(
+            signature(&items[3]),
+            (vec![Type::F64, Type::F64], Type::F64)
+        );
+
+        // The loop variable is an i64 because it is compared with one.
+        match body(&items[4]) {
+            Expr::For(_, start, ..) => assert_eq!(start.as_ref(), &Expr::Int(0)),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        // So is a let variable multiplied by one.
+        let items = check_jit("def s(n: i64) -> i64 let m = 2 in n * m;").unwrap();
+        match body(&items[0]) {
+            Expr::Let(_, value, _) => assert_eq!(value.as_ref(), &Expr::Int(2)),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(check_jit("def f(n: i64) -> i64 n; f(0.5);").is_err());
+        assert!(check_jit("extern sqrt(x: i64);").is_err());
+        assert!(check("def f(x) g(x);").is_err());
+        assert!(check_jit("def f(b: bool) -> bool b; f(1 < 2); f(1) < 2;").is_err());
+        assert!(check_jit("def f(n: i64 x: f64) n + x;").is_err());
+
+        let e = check_jit("def f(n: i64) -> i64\n  n + 1.5;").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 2:5: mismatched types i64 and f64"
+        );
+        let e = check_jit("def f(n: i64) n;\nf(2.5);").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 2:1: mismatched types f64 and i64"
+        );
+
+        // Redefinitions keep the signature, inferred or not.
+        assert!(check("def f(x) x * 2; def f(y) y * 3; f(5);").is_ok());
+        let e = check_jit("def f(x) x * 2; def f(x: i64) -> i64 x * 3; f(5);").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: f is already defined as fn(f64) -> f64"
+        );
+        assert!(check("def f(n) n < 1; def f(n) n;").is_err());
+        assert!(check("def sin(x) x; extern sin(x);").is_ok());
+        assert!(check("def f(x y) x; extern f(x);").is_err());
+    }
+
+    #[test]
+    fn test_jit_only_scalars() {
+        // Other backends compute in doubles, so they take neither i64 nor bool annotations.
+        for (src, msg) in [
+            (
+                "def f(n: i64) n;",
+                "i64 is only supported by the JIT backend",
+            ),
+            (
+                "def f(x) -> bool x < 1;",
+                "bool is only supported by the JIT backend",
+            ),
+            (
+                "def f(x) let n: i64 = 1 in n;",
+                "1:10: i64 is only supported by the JIT backend",
+            ),
+        ] {
+            assert_eq!(
+                check(src).unwrap_err().to_string(),
+                format!("Type error: {}", msg)
+            );
+            assert!(check_jit(src).is_ok());
+        }
+        // Comparisons are still bools, which are 0 or 1 as numbers on every backend.
+        let items = check("def f(x) x < 1; f(0) + 1;").unwrap();
+        assert_eq!(signature(&items[0]), (vec![Type::F64], Type::Bool));
+    }
+
+    #[test]
+    fn test_strings() {
+        let jit = |src: &str| {
+            let mut checker = Checker::for_jit();
+            toplevel::parse_source(src)
+                .unwrap()
+                .iter()
+                .try_for_each(|item| match item {
+                    Item::Definition(f) | Item::TopLevel(f) => checker.function(f).map(|_| ()),
+                    Item::Extern(p) => checker.declare(p).map(|_| ()),
+                    Item::Struct(s) => checker.define_struct(s),
+                    Item::Import(_) => unreachable!(),
+                })
+        };
+        assert!(jit(r#"extern strlen(s); def f(s) strlen(s) + 1; f("a");"#).is_ok());
+        assert!(jit(r#"extern strlen(s: f64);"#).is_err());
+        assert!(jit(r#"def f(s: str) s + 1;"#).is_err());
+        assert!(jit(r#"def f(s: str) if s then 1 else 2;"#).is_err());
+        assert!(jit(r#"def f(x) if x then "a" else x < 1;"#).is_err());
+        assert!(jit(r#""a";"#).is_err());
+
+        let e = check(r#"def f() "a";"#).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 1:9: strings are only supported by the JIT backend"
+        );
+        assert!(check("extern printstr(s);").is_err());
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut checker = Checker::for_jit();
+        let items = toplevel::parse_source(
+            "extern len(a); def get(a i) a[i]; def last(a) get(a, len(a) - 1); def fill(a) a[0] = 1 < 2;",
+        )
+        .unwrap();
+        checker
+            .declare(match &items[0] {
+                Item::Extern(p) => p,
+                _ => unreachable!(),
+            })
+            .unwrap();
+        let get = checker.function(function(&items[1])).unwrap();
+        let Prototype(_, _, types, ret) = get.0.as_ref();
+        assert_eq!(types, &vec![Some(Type::Array), Some(Type::F64)]);
+        assert_eq!(ret, &Some(Type::F64));
+        assert!(checker.function(function(&items[2])).is_ok());
+        assert!(checker.function(function(&items[3])).is_ok());
+
+        for src in [
+            "def f(a: [f64]) a + 1;",
+            "def f(a: [f64]) a[a];",
+            "def f(x) x[0] + x;",
+        ] {
+            let items = toplevel::parse_source(src).unwrap();
+            assert!(Checker::for_jit().function(function(&items[0])).is_err());
+        }
+
+        let e = check("def f() [1, 2];").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 1:9: arrays are only supported by the JIT backend"
+        );
+    }
+
+    #[test]
+    fn test_structs() {
+        let mut checker = Checker::for_jit();
+        let items = toplevel::parse_source(
+            "struct Point { x, y: i64 }; struct Circle { centre: Point, r }; struct Size { x, y }; \
+             def area(c) c.r * c.r * 3; def up(p: Point) Point { y: p.y + 1, ..p };",
+        )
+        .unwrap();
+        for item in &items[..3] {
+            match item {
+                Item::Struct(s) => checker.define_struct(s).unwrap(),
+                _ => unreachable!(),
+            }
+        }
+        let area = checker.function(function(&items[3])).unwrap();
+        assert_eq!(area.0 .2, vec![Some(Type::Struct("Circle"))]);
+        let up = checker.function(function(&items[4])).unwrap();
+        assert_eq!(up.0 .2, vec![Some(Type::Struct("Point"))]);
+        assert_eq!(up.0 .3, Some(Type::Struct("Point")));
+
+        for (src, msg) in [
+            (
+                "def f(p) p.x;",
+                "1:11: cannot infer which struct has field x; annotate its type",
+            ),
+            ("def f(p: Point) p.z;", "1:18: Point has no field z"),
+            ("def f() Point { x: 1 };", "1:9: missing field y in Point"),
+            (
+                "def f() Point { x: 1, y: 2, x: 3 };",
+                "1:9: field x is given twice",
+            ),
+            (
+                "def f() Point { x: 1, y: 0.5 };",
+                "1:9: mismatched types f64 and i64",
+            ),
+            ("def f(s: Square) 0;", "unknown type: Square"),
+        ] {
+            let items = toplevel::parse_source(src).unwrap();
+            let e = checker.function(function(&items[0])).unwrap_err();
+            assert_eq!(e.to_string(), format!("Type error: {}", msg));
+        }
+
+        let redefined = toplevel::parse_source("struct Point { x, y };").unwrap();
+        match &redefined[0] {
+            Item::Struct(s) => assert!(checker.define_struct(s).is_err()),
+            _ => unreachable!(),
+        }
+
+        let e = check("struct Point { x };").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: structs are only supported by the JIT backend"
+        );
+    }
+
+    #[test]
+    fn test_closures() {
+        let mut checker = Checker::for_jit();
+        let items = toplevel::parse_source(
+            "def twice(f x) f(f(x)); def adder(n: i64) fn (x) x + n; \
+             def apply(f: fn(i64) -> i64) f(2); def inc(x) x + 1; twice(inc, 1);",
+        )
+        .unwrap();
+        let checked = items
+            .iter()
+            .map(|item| checker.function(function(item)).unwrap())
+            .collect::<Vec<_>>();
+        let f64_to_f64 = Type::Fn(intern_fn(vec![Type::F64], Type::F64));
+        assert_eq!(checked[0].0 .2, vec![Some(f64_to_f64), Some(Type::F64)]);
+        assert_eq!(
+            checked[1].0 .3,
+            Some(Type::Fn(intern_fn(vec![Type::I64], Type::I64)))
+        );
+        assert!(checker.function(function(&items[2])).is_ok());
+
+        for (src, msg) in [
+            (
+                "def f(g: fn(f64)) g + 1;",
+                "1:21: expected a number, found fn(f64) -> f64",
+            ),
+            (
+                "def f(g) g(g);",
+                "1:10: a function can not take or return itself",
+            ),
+            (
+                "def g() twice(inc, 1, 2);",
+                "1:9: incorrect # arguments passed",
+            ),
+            ("def f() apply(inc);", "1:9: mismatched types f64 and i64"),
+        ] {
+            let items = toplevel::parse_source(src).unwrap();
+            let e = items
+                .iter()
+                .try_for_each(|item| checker.function(function(item)).map(|_| ()))
+                .unwrap_err();
+            assert_eq!(e.to_string(), format!("Type error: {}", msg));
+        }
+
+        let e = check("def f() 1; def g(h) h(); g(f);").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 1:21: closures are only supported by the JIT backend"
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut checker = Checker::for_jit();
+        let items = toplevel::parse_source(
+            "def f(x: i64) while x < 10 do (if x < 5 then continue else x) + x;",
+        )
+        .unwrap();
+        // The `if` is an i64, since one branch jumps.
+        assert!(checker.function(function(&items[0])).is_ok());
+        for (src, msg) in [
+            (
+                "def g(x: i64) -> i64 if x < 0 then break else x;",
+                "1:36: break outside of a loop",
+            ),
+            (
+                "def h(x) while x do fn () continue;",
+                "1:27: continue outside of a loop",
+            ),
+        ] {
+            let items = toplevel::parse_source(src).unwrap();
+            let e = checker.function(function(&items[0])).unwrap_err();
+            assert_eq!(e.to_string(), format!("Type error: {}", msg));
+        }
+
+        let e = check("def f(x) while x do 1;").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 1:10: while loops are only supported by the JIT backend"
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        let mut checker = Checker::for_jit();
+        let items = toplevel::parse_source(
+            "def f(x) if x < 0 then return 0 < 1 else x < 2; \
+             def g(x: i64) fn (y) if y < x then return y else x;",
+        )
+        .unwrap();
+        let f = checker.function(function(&items[0])).unwrap();
+        assert_eq!(f.0 .3, Some(Type::Bool));
+        // Returns from a lambda return from the lambda.
+        let g = checker.function(function(&items[1])).unwrap();
+        assert_eq!(
+            g.0 .3,
+            Some(Type::Fn(intern_fn(vec![Type::I64], Type::I64)))
+        );
+
+        let items = toplevel::parse_source("def h(x: i64) -> i64 (return \"a\") + x;").unwrap();
+        let e = checker.function(function(&items[0])).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 1:23: mismatched types str and i64"
+        );
+
+        let e = check("def f(x) return x;").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Type error: 1:10: return is only supported by the JIT backend"
+        );
+    }
+}
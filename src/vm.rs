@@ -0,0 +1,264 @@
+use super::ast::{Function, Prototype};
+use super::bytecode::{Module, Op};
+use super::error::{Error, ErrorKind};
+use super::runtime::{self, Builtin};
+use super::toplevel::Backend;
+use super::typeck;
+
+/// Calls nest on an explicit frame stack, so runaway recursion is reported rather than
+/// overflowing the native stack.
+const MAX_FRAMES: usize = 1 << 16;
+
+fn runtime_error(msg: String) -> Error {
+    Error::from(ErrorKind::Runtime(msg))
+}
+
+struct Frame {
+    chunk: usize,
+    pc: usize,
+    base: usize,
+}
+
+/// Executes bytecode compiled from the AST, the `--backend=vm` alternative to the JIT.
+pub(crate) struct Vm {
+    module: Module,
+    // Parallel to `module.externs`.
+    builtins: Vec<Builtin>,
+    types: typeck::Checker,
+}
+
+/// Only the f64 builtins can be called from bytecode; the rest take strings or arrays.
+fn bind(name: &str, arity: usize) -> Result<Builtin, Error> {
+    match runtime::lookup(name) {
+        Some(builtin @ (Builtin::Unary(_) | Builtin::Binary(_))) if builtin.arity() == arity => {
+            Ok(builtin)
+        }
+        Some(Builtin::Unary(_) | Builtin::Binary(_)) => Err(runtime_error(format!(
+            "extern {} declared with the wrong # arguments",
+            name
+        ))),
+        Some(_) => Err(runtime_error(format!(
+            "extern {} is only supported by the JIT backend",
+            name
+        ))),
+        None => Err(runtime_error(format!("unknown extern function: {}", name))),
+    }
+}
+
+impl Vm {
+    pub(crate) fn new() -> Self {
+        Vm {
+            module: Module::default(),
+            builtins: Vec::new(),
+            types: typeck::Checker::new(),
+        }
+    }
+
+    /// Prepares a previously compiled module, binding its externs to the runtime.
+    pub(crate) fn from_module(module: Module) -> Result<Self, Error> {
+        let builtins = module
+            .externs
+            .iter()
+            .map(|e| bind(&e.name, e.arity as usize))
+            .collect::<Result<_, _>>()?;
+        Ok(Vm {
+            module,
+            builtins,
+            types: typeck::Checker::new(),
+        })
+    }
+
+    /// Runs the module's top-level expressions in order.
+    pub(crate) fn run_entries(&self) -> Result<Vec<f64>, Error> {
+        self.module
+            .entries
+            .iter()
+            .map(|&e| self.run(e as usize))
+            .collect()
+    }
+
+    fn run(&self, chunk: usize) -> Result<f64, Error> {
+        let functions = &self.module.functions;
+        let mut stack: Vec<f64> = Vec::new();
+        let mut locals = vec![0.0; functions[chunk].locals as usize];
+        let mut frames = vec![Frame {
+            chunk,
+            pc: 0,
+            base: 0,
+        }];
+
+        fn pop(stack: &mut Vec<f64>) -> Result<f64, Error> {
+            stack
+                .pop()
+                .ok_or_else(|| runtime_error("bytecode stack underflow".to_owned()))
+        }
+
+        loop {
+            let frame = frames.last_mut().unwrap();
+            let op = functions[frame.chunk].code[frame.pc];
+            frame.pc += 1;
+
+            match op {
+                Op::Const(n) => stack.push(n),
+                Op::Load(slot) => stack.push(locals[frame.base + slot as usize]),
+                Op::Store(slot) => locals[frame.base + slot as usize] = pop(&mut stack)?,
+                Op::Pop => {
+                    pop(&mut stack)?;
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Lt => {
+                    let r = pop(&mut stack)?;
+                    let l = pop(&mut stack)?;
+                    stack.push(match op {
+                        Op::Add => l + r,
+                        Op::Sub => l - r,
+                        Op::Mul => l * r,
+                        // Unordered comparison, as in codegen.
+                        _ => {
+                            if l < r || l.is_nan() || r.is_nan() {
+                                1.0
+                            } else {
+                                0.0
+                            }
+                        }
+                    });
+                }
+                Op::Jump(target) => frame.pc = target as usize,
+                Op::JumpIfFalse(target) => {
+                    let c = pop(&mut stack)?;
+                    if c == 0.0 || c.is_nan() {
+                        frame.pc = target as usize;
+                    }
+                }
+                Op::Call(callee) => {
+                    if frames.len() >= MAX_FRAMES {
+                        return Err(runtime_error("call stack overflow".to_owned()));
+                    }
+                    let callee = callee as usize;
+                    let chunk = &functions[callee];
+                    let arity = chunk.arity as usize;
+                    if stack.len() < arity {
+                        return Err(runtime_error("bytecode stack underflow".to_owned()));
+                    }
+                    let base = locals.len();
+                    locals.extend(stack.drain(stack.len() - arity..));
+                    locals.resize(base + chunk.locals as usize, 0.0);
+                    frames.push(Frame {
+                        chunk: callee,
+                        pc: 0,
+                        base,
+                    });
+                }
+                Op::CallExtern(callee) => {
+                    let builtin = &self.builtins[callee as usize];
+                    let arity = builtin.arity();
+                    if stack.len() < arity {
+                        return Err(runtime_error("bytecode stack underflow".to_owned()));
+                    }
+                    let args = stack.split_off(stack.len() - arity);
+                    stack.push(builtin.call(&args));
+                }
+                Op::Ret => {
+                    let v = pop(&mut stack)?;
+                    let frame = frames.pop().unwrap();
+                    if frames.is_empty() {
+                        return Ok(v);
+                    }
+                    locals.truncate(frame.base);
+                    stack.push(v);
+                }
+            }
+        }
+    }
+}
+
+impl Backend for Vm {
+    fn define(&mut self, f: Function) -> Result<(), Error> {
+        let f = self.types.function(&f)?;
+        self.module.compile_function(&f).map(|_| ())
+    }
+
+    fn declare(&mut self, p: Prototype) -> Result<(), Error> {
+        self.types.declare(&p)?;
+        let builtin = bind(&p.0, p.1.len())?;
+        if self.module.find_extern(&p.0).is_none() {
+            self.module.declare_extern(&p);
+            self.builtins.push(builtin);
+        }
+        Ok(())
+    }
+
+    fn eval(&mut self, f: Function) -> Result<f64, Error> {
+        let f = self.types.function(&f)?;
+        let index = self.module.compile_function(&f)? as usize;
+        let ret = self.run(index);
+        // Top-level expressions are never called again; don't let them pile up.
+        self.module.functions.truncate(index);
+        ret
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.types.listing()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::bytecode;
+    use super::super::toplevel;
+    use super::*;
+
+    fn run(src: &str) -> Vec<f64> {
+        toplevel::run_source(&mut Vm::new(), src).unwrap()
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(
+            run("def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2); fib(10); extern sqrt(x); sqrt(16);"),
+            vec![55.0, 4.0]
+        );
+        assert_eq!(
+            run("def f(i) (for i = 0, i < 3 in i) + i; f(7);"),
+            vec![7.0]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut vm = Vm::new();
+        assert!(toplevel::run_source(&mut vm, "extern nosuch(x);").is_err());
+        assert!(toplevel::run_source(&mut vm, "def f(x) f(x); f(1);").is_err());
+        // The VM is still usable after a runtime error.
+        assert_eq!(toplevel::run_source(&mut vm, "1+1;").unwrap(), vec![2.0]);
+    }
+
+    #[test]
+    fn test_run_loaded_module() {
+        let items =
+            toplevel::parse_source("extern sqrt(x); def f(x) sqrt(x)*2; f(9); f(16);").unwrap();
+        let mut buf = Vec::new();
+        bytecode::compile(&items).unwrap().write(&mut buf).unwrap();
+
+        let module = Module::read(&mut buf.as_slice()).unwrap();
+        let vm = Vm::from_module(module).unwrap();
+        assert_eq!(vm.run_entries().unwrap(), vec![6.0, 8.0]);
+    }
+
+    #[test]
+    fn test_load_unsupported_extern() {
+        for (name, arity) in [("strlen", 1), ("printstr", 1), ("sqrt", 2), ("nosuch", 1)] {
+            let module = Module {
+                externs: vec![bytecode::Extern {
+                    name: name.to_owned(),
+                    arity,
+                }],
+                functions: Vec::new(),
+                entries: Vec::new(),
+            };
+            let mut buf = Vec::new();
+            module.write(&mut buf).unwrap();
+            let module = Module::read(&mut buf.as_slice()).unwrap();
+            assert!(Vm::from_module(module).is_err(), "{}", name);
+        }
+    }
+}
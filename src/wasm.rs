@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::ast::{Expr, Function, Prototype};
+use super::error::{Error, ErrorKind};
+use super::toplevel::Item;
+use super::typeck;
+
+/// The subset of WebAssembly instructions that Kaleidoscope lowers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Instr {
+    F64Const(f64),
+    LocalGet(u32),
+    LocalSet(u32),
+    F64Add,
+    F64Sub,
+    F64Mul,
+    F64Abs,
+    F64Gt,
+    F64Ge,
+    I32Eqz,
+    F64ConvertI32U,
+    Call(u32),
+    /// `if` with an f64 result.
+    If,
+    Else,
+    /// `loop` with no result.
+    Loop,
+    End,
+    BrIf(u32),
+    Drop,
+}
+
+struct Import {
+    name: String,
+    arity: usize,
+}
+
+struct Func {
+    name: String,
+    params: usize,
+    // Names of all locals, parameters first; unique within the function.
+    locals: Vec<String>,
+    body: Vec<Instr>,
+}
+
+/// A WebAssembly module where every value is an f64. Externs become imports from `env`,
+/// `def`s are exported under their own names and top-level expressions as `__anon_exprN`.
+pub(crate) struct Module {
+    imports: Vec<Import>,
+    funcs: Vec<Func>,
+}
+
+fn codegen_error(msg: String) -> Error {
+    Error::from(ErrorKind::Codegen(msg))
+}
+
+struct FuncCompiler<'a> {
+    imports: &'a [Import],
+    funcs: &'a [Func],
+    // The function being compiled, callable before it is finished.
+    this: (&'a str, usize),
+    scope: HashMap<String, u32>,
+    locals: Vec<String>,
+    body: Vec<Instr>,
+}
+
+impl<'a> FuncCompiler<'a> {
+    fn new_local(&mut self, name: &str) -> u32 {
+        let mut unique = name.to_owned();
+        let mut n = 0;
+        while self.locals.contains(&unique) {
+            n += 1;
+            unique = format!("{}.{}", name, n);
+        }
+        self.locals.push(unique);
+        self.locals.len() as u32 - 1
+    }
+
+    /// Leaves an i32 on the stack that is 1 iff the f64 on top is neither 0.0 nor NaN, which
+    /// is what `fcmp one` against 0.0 computes in codegen.
+    fn truthy(&mut self) {
+        self.body
+            .extend(&[Instr::F64Abs, Instr::F64Const(0.0), Instr::F64Gt]);
+    }
+
+    fn resolve(&self, callee: &str) -> Option<(u32, usize)> {
+        let base = self.imports.len();
+        if callee == self.this.0 {
+            return Some(((base + self.funcs.len()) as u32, self.this.1));
+        }
+        if let Some(i) = self.funcs.iter().rposition(|f| f.name == callee) {
+            return Some(((base + i) as u32, self.funcs[i].params));
+        }
+        self.imports
+            .iter()
+            .position(|i| i.name == callee)
+            .map(|i| (i as u32, self.imports[i].arity))
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<(), Error> {
+        match e {
+            Expr::Number(n) => self.body.push(Instr::F64Const(*n)),
+            // Only f64 is used for now: i64 is left to the JIT, and bools are 0 or 1.
+            Expr::Int(n) => self.body.push(Instr::F64Const(*n as f64)),
+            Expr::Cast(_, e) | Expr::At(_, e) => self.expr(e)?,
+            Expr::Str(_)
+            | Expr::Array(_)
+            | Expr::Index(..)
+            | Expr::SetIndex(..)
+            | Expr::Struct(..)
+            | Expr::Field(..)
+            | Expr::Lambda(..)
+            | Expr::Apply(..)
+            | Expr::While(..)
+            | Expr::Break
+            | Expr::Continue
+            | Expr::Return(_) => {
+                return Err(codegen_error(
+                    "this expression is only supported by the JIT backend".to_owned(),
+                ))
+            }
+            Expr::Variable(name) => match self.scope.get(name) {
+                Some(idx) => self.body.push(Instr::LocalGet(*idx)),
+                None => return Err(codegen_error(format!("unknown variable name: {}", name))),
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                match op {
+                    '+' => self.body.push(Instr::F64Add),
+                    '-' => self.body.push(Instr::F64Sub),
+                    '*' => self.body.push(Instr::F64Mul),
+                    // `fcmp ult` is true when unordered; wasm's f64.lt is ordered, so use !(l >= r).
+                    '<' => self
+                        .body
+                        .extend(&[Instr::F64Ge, Instr::I32Eqz, Instr::F64ConvertI32U]),
+                    _ => return Err(codegen_error(format!("invalid binary operator: {}", op))),
+                }
+            }
+            Expr::Call(callee, args) => {
+                let (idx, arity) = self
+                    .resolve(callee)
+                    .ok_or_else(|| codegen_error(format!("unknown function: {}", callee)))?;
+                if arity != args.len() {
+                    return Err(codegen_error("incorrect # arguments passed".to_owned()));
+                }
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                self.body.push(Instr::Call(idx));
+            }
+            Expr::If(cond, then, els) => {
+                self.expr(cond)?;
+                self.truthy();
+                self.body.push(Instr::If);
+                self.expr(then)?;
+                self.body.push(Instr::Else);
+                self.expr(els)?;
+                self.body.push(Instr::End);
+            }
+            Expr::Let(name, value, body) => {
+                self.expr(value)?;
+                let var = self.new_local(name);
+                self.body.push(Instr::LocalSet(var));
+                let old = self.scope.insert(name.clone(), var);
+                let ret = self.expr(body);
+                match old {
+                    Some(idx) => self.scope.insert(name.clone(), idx),
+                    None => self.scope.remove(name),
+                };
+                ret?;
+            }
+            Expr::For(var_name, start, end, step, body) => {
+                self.expr(start)?;
+                let var = self.new_local(var_name);
+                let step_local = self.new_local(&format!("{}.step", var_name));
+                self.body.push(Instr::LocalSet(var));
+
+                let old = self.scope.insert(var_name.clone(), var);
+
+                let ret = (|| -> Result<(), Error> {
+                    self.body.push(Instr::Loop);
+                    self.expr(body)?;
+                    self.body.push(Instr::Drop);
+                    match step.as_ref() {
+                        Some(step) => self.expr(step)?,
+                        None => self.body.push(Instr::F64Const(1.0)),
+                    }
+                    self.body.push(Instr::LocalSet(step_local));
+                    self.expr(end)?;
+                    self.truthy();
+                    self.body.extend(&[
+                        Instr::LocalGet(var),
+                        Instr::LocalGet(step_local),
+                        Instr::F64Add,
+                        Instr::LocalSet(var),
+                        Instr::BrIf(0),
+                        Instr::End,
+                        Instr::F64Const(0.0),
+                    ]);
+                    Ok(())
+                })();
+
+                match old {
+                    Some(idx) => self.scope.insert(var_name.clone(), idx),
+                    None => self.scope.remove(var_name),
+                };
+
+                ret?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn compile(items: &[Item]) -> Result<Module, Error> {
+    let items = typeck::check_items(items)?;
+
+    // Imports come first in the function index space, so collect them up front.
+    let mut imports: Vec<Import> = Vec::new();
+    for item in &items {
+        if let Item::Extern(Prototype(name, args, ..)) = item {
+            if !imports.iter().any(|i| &i.name == name) {
+                imports.push(Import {
+                    name: name.clone(),
+                    arity: args.len(),
+                });
+            }
+        }
+    }
+
+    let mut funcs: Vec<Func> = Vec::new();
+    let mut anon_count = 0;
+
+    for item in &items {
+        let Function(proto, body) = match item {
+            Item::Definition(f) | Item::TopLevel(f) => f,
+            Item::Extern(_) | Item::Struct(_) | Item::Import(_) => continue,
+        };
+
+        let Prototype(name, args, ..) = proto.as_ref();
+        let name = if name.is_empty() {
+            anon_count += 1;
+            format!("__anon_expr{}", anon_count - 1)
+        } else {
+            name.clone()
+        };
+        if funcs.iter().any(|f| f.name == name) || imports.iter().any(|i| i.name == name) {
+            return Err(codegen_error(format!("redefinition of function: {}", name)));
+        }
+
+        let mut fc = FuncCompiler {
+            imports: &imports,
+            funcs: &funcs,
+            this: (&name, args.len()),
+            scope: HashMap::new(),
+            locals: Vec::new(),
+            body: Vec::new(),
+        };
+        for arg in args {
+            let idx = fc.new_local(arg);
+            fc.scope.insert(arg.clone(), idx);
+        }
+        fc.expr(body)?;
+
+        let (locals, body) = (fc.locals, fc.body);
+        funcs.push(Func {
+            name,
+            params: args.len(),
+            locals,
+            body,
+        });
+    }
+
+    Ok(Module { imports, funcs })
+}
+
+fn leb128(out: &mut Vec<u8>, mut v: u32) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    leb128(out, s.len() as u32);
+    out.extend(s.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, count: usize, contents: Vec<u8>) {
+    let mut body = Vec::new();
+    leb128(&mut body, count as u32);
+    body.extend(contents);
+    out.push(id);
+    leb128(out, body.len() as u32);
+    out.extend(body);
+}
+
+const F64: u8 = 0x7c;
+
+impl Module {
+    /// Function types are shared between all functions of the same arity.
+    fn types(&self) -> Vec<usize> {
+        let mut types = Vec::new();
+        let arities = self
+            .imports
+            .iter()
+            .map(|i| i.arity)
+            .chain(self.funcs.iter().map(|f| f.params));
+        for arity in arities {
+            if !types.contains(&arity) {
+                types.push(arity);
+            }
+        }
+        types
+    }
+
+    fn type_index(types: &[usize], arity: usize) -> u32 {
+        types.iter().position(|&a| a == arity).unwrap() as u32
+    }
+
+    fn func_name(&self, idx: u32) -> &str {
+        let idx = idx as usize;
+        if idx < self.imports.len() {
+            &self.imports[idx].name
+        } else {
+            &self.funcs[idx - self.imports.len()].name
+        }
+    }
+
+    pub(crate) fn to_wasm(&self) -> Vec<u8> {
+        let types = self.types();
+        let mut out = b"\0asm".to_vec();
+        out.extend(&1u32.to_le_bytes());
+
+        let mut buf = Vec::new();
+        for &arity in &types {
+            buf.push(0x60);
+            leb128(&mut buf, arity as u32);
+            buf.extend(std::iter::repeat_n(F64, arity));
+            buf.extend(&[1, F64]);
+        }
+        section(&mut out, 1, types.len(), buf);
+
+        let mut buf = Vec::new();
+        for import in &self.imports {
+            name(&mut buf, "env");
+            name(&mut buf, &import.name);
+            buf.push(0x00);
+            leb128(&mut buf, Self::type_index(&types, import.arity));
+        }
+        section(&mut out, 2, self.imports.len(), buf);
+
+        let mut buf = Vec::new();
+        for func in &self.funcs {
+            leb128(&mut buf, Self::type_index(&types, func.params));
+        }
+        section(&mut out, 3, self.funcs.len(), buf);
+
+        let mut buf = Vec::new();
+        for (i, func) in self.funcs.iter().enumerate() {
+            name(&mut buf, &func.name);
+            buf.push(0x00);
+            leb128(&mut buf, (self.imports.len() + i) as u32);
+        }
+        section(&mut out, 7, self.funcs.len(), buf);
+
+        let mut buf = Vec::new();
+        for func in &self.funcs {
+            let mut code = Vec::new();
+            let extra = func.locals.len() - func.params;
+            if extra > 0 {
+                code.push(1);
+                leb128(&mut code, extra as u32);
+                code.push(F64);
+            } else {
+                code.push(0);
+            }
+            for instr in &func.body {
+                match *instr {
+                    Instr::F64Const(n) => {
+                        code.push(0x44);
+                        code.extend(&n.to_le_bytes());
+                    }
+                    Instr::LocalGet(i) => {
+                        code.push(0x20);
+                        leb128(&mut code, i);
+                    }
+                    Instr::LocalSet(i) => {
+                        code.push(0x21);
+                        leb128(&mut code, i);
+                    }
+                    Instr::F64Add => code.push(0xa0),
+                    Instr::F64Sub => code.push(0xa1),
+                    Instr::F64Mul => code.push(0xa2),
+                    Instr::F64Abs => code.push(0x99),
+                    Instr::F64Gt => code.push(0x64),
+                    Instr::F64Ge => code.push(0x66),
+                    Instr::I32Eqz => code.push(0x45),
+                    Instr::F64ConvertI32U => code.push(0xb8),
+                    Instr::Call(f) => {
+                        code.push(0x10);
+                        leb128(&mut code, f);
+                    }
+                    Instr::If => code.extend(&[0x04, F64]),
+                    Instr::Else => code.push(0x05),
+                    Instr::Loop => code.extend(&[0x03, 0x40]),
+                    Instr::End => code.push(0x0b),
+                    Instr::BrIf(depth) => {
+                        code.push(0x0d);
+                        leb128(&mut code, depth);
+                    }
+                    Instr::Drop => code.push(0x1a),
+                }
+            }
+            code.push(0x0b);
+
+            leb128(&mut buf, code.len() as u32);
+            buf.extend(code);
+        }
+        section(&mut out, 10, self.funcs.len(), buf);
+
+        out
+    }
+
+    pub(crate) fn to_wat(&self) -> String {
+        let types = self.types();
+        let mut out = String::from("(module\n");
+
+        for (i, arity) in types.iter().enumerate() {
+            let _ = write!(out, "  (type (;{};) (func", i);
+            if *arity > 0 {
+                out.push_str(" (param");
+                for _ in 0..*arity {
+                    out.push_str(" f64");
+                }
+                out.push(')');
+            }
+            out.push_str(" (result f64)))\n");
+        }
+
+        for import in &self.imports {
+            let _ = writeln!(
+                out,
+                "  (import \"env\" \"{}\" (func ${} (type {})))",
+                import.name,
+                import.name,
+                Self::type_index(&types, import.arity)
+            );
+        }
+
+        for func in &self.funcs {
+            let _ = write!(out, "  (func ${} (export \"{}\")", func.name, func.name);
+            let _ = write!(out, " (type {})", Self::type_index(&types, func.params));
+            for param in &func.locals[..func.params] {
+                let _ = write!(out, " (param ${} f64)", param);
+            }
+            out.push_str(" (result f64)\n");
+            for local in &func.locals[func.params..] {
+                let _ = writeln!(out, "    (local ${} f64)", local);
+            }
+
+            let mut indent = 2;
+            for instr in &func.body {
+                if let Instr::Else | Instr::End = instr {
+                    indent -= 1;
+                }
+                for _ in 0..indent {
+                    out.push_str("  ");
+                }
+                match *instr {
+                    Instr::F64Const(n) if n.is_nan() => out.push_str("f64.const nan"),
+                    Instr::F64Const(n) if n.is_infinite() => out.push_str(if n > 0.0 {
+                        "f64.const inf"
+                    } else {
+                        "f64.const -inf"
+                    }),
+                    Instr::F64Const(n) => {
+                        let _ = write!(out, "f64.const {:?}", n);
+                    }
+                    Instr::LocalGet(i) => {
+                        let _ = write!(out, "local.get ${}", func.locals[i as usize]);
+                    }
+                    Instr::LocalSet(i) => {
+                        let _ = write!(out, "local.set ${}", func.locals[i as usize]);
+                    }
+                    Instr::F64Add => out.push_str("f64.add"),
+                    Instr::F64Sub => out.push_str("f64.sub"),
+                    Instr::F64Mul => out.push_str("f64.mul"),
+                    Instr::F64Abs => out.push_str("f64.abs"),
+                    Instr::F64Gt => out.push_str("f64.gt"),
+                    Instr::F64Ge => out.push_str("f64.ge"),
+                    Instr::I32Eqz => out.push_str("i32.eqz"),
+                    Instr::F64ConvertI32U => out.push_str("f64.convert_i32_u"),
+                    Instr::Call(f) => {
+                        let _ = write!(out, "call ${}", self.func_name(f));
+                    }
+                    Instr::If => out.push_str("if (result f64)"),
+                    Instr::Else => out.push_str("else"),
+                    Instr::Loop => out.push_str("loop"),
+                    Instr::End => out.push_str("end"),
+                    Instr::BrIf(depth) => {
+                        let _ = write!(out, "br_if {}", depth);
+                    }
+                    Instr::Drop => out.push_str("drop"),
+                }
+                out.push('\n');
+                if let Instr::If | Instr::Else | Instr::Loop = instr {
+                    indent += 1;
+                }
+            }
+            out.push_str("  )\n");
+        }
+
+        out.push_str(")\n");
+        out
+    }
+}
+
+/// Instantiates `wasm` in wasmi with the runtime builtins as its imports and calls each of
+/// `exports` in turn.
+#[cfg(test)]
+pub(crate) fn run_exports(wasm: &[u8], exports: &[&str]) -> Vec<f64> {
+    use super::runtime::{self, Builtin};
+    use wasmi::core::F64;
+    use wasmi::{Engine, Linker, Store, Value};
+
+    wasmparser::validate(wasm).unwrap();
+
+    let engine = Engine::default();
+    let module = wasmi::Module::new(&engine, wasm).unwrap();
+    let mut store = Store::new(&engine, ());
+    let mut linker = <Linker<()>>::new(&engine);
+    for import in module.imports() {
+        match runtime::lookup(import.name()).unwrap() {
+            Builtin::Unary(f) => linker
+                .func_wrap("env", import.name(), move |x: F64| F64::from(f(x.into())))
+                .unwrap(),
+            Builtin::Binary(f) => linker
+                .func_wrap("env", import.name(), move |x: F64, y: F64| {
+                    F64::from(f(x.into(), y.into()))
+                })
+                .unwrap(),
+            _ => unreachable!("string builtins are rejected by the type checker"),
+        };
+    }
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+
+    exports
+        .iter()
+        .map(|name| {
+            let func = instance.get_func(&store, name).unwrap();
+            let mut result = [Value::F64(F64::from(0.0))];
+            func.call(&mut store, &[], &mut result).unwrap();
+            match result[0] {
+                Value::F64(v) => v.into(),
+                _ => unreachable!(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::toplevel;
+    use super::*;
+
+    fn compile_source(src: &str) -> Module {
+        compile(&toplevel::parse_source(src).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_run() {
+        let module = compile_source(
+            "extern sqrt(x);
+             def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2);
+             def f(i) (for i = 0, i < 3 in i) + i;
+             fib(10); sqrt(16); f(7);",
+        );
+        let exports = ["__anon_expr0", "__anon_expr1", "__anon_expr2"];
+        let expected = vec![55.0, 4.0, 7.0];
+
+        assert_eq!(run_exports(&module.to_wasm(), &exports), expected);
+
+        // The text format must describe the same module.
+        let wasm = wat::parse_str(module.to_wat()).unwrap();
+        assert_eq!(run_exports(&wasm, &exports), expected);
+    }
+
+    #[test]
+    fn test_wat() {
+        assert_eq!(
+            compile_source("def twice(x) x*2;").to_wat(),
+            "(module
+  (type (;0;) (func (param f64) (result f64)))
+  (func $twice (export \"twice\") (type 0) (param $x f64) (result f64)
+    local.get $x
+    f64.const 2.0
+    f64.mul
+  )
+)
+"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let items = toplevel::parse_source("def f(x) g(x);").unwrap();
+        assert!(compile(&items).is_err());
+        let items = toplevel::parse_source("def f(x) x; def f(y) y;").unwrap();
+        assert!(compile(&items).is_err());
+    }
+}